@@ -0,0 +1,156 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tokio::sync::watch;
+
+/// How many bytes of the replication stream are kept for replicas that
+/// reconnect and ask to continue where they left off.
+const BACKLOG_SIZE: usize = 1024 * 1024;
+
+/// The replication stream of this server.
+///
+/// Every write is appended as the command that replays it, in the order the
+/// writes happened. Offsets count bytes from the start of the stream, so a
+/// replica can resume from the offset it had reached as long as those bytes
+/// are still in the backlog.
+#[derive(Debug)]
+pub struct Backlog {
+    inner: Mutex<BacklogInner>,
+    /// Carries the end offset, waking replica streams on every write.
+    end_tx: watch::Sender<u64>,
+}
+
+#[derive(Debug)]
+struct BacklogInner {
+    replid: String,
+    /// Offset of the first byte in `buf`.
+    start: u64,
+    buf: VecDeque<u8>,
+}
+
+impl BacklogInner {
+    fn end(&self) -> u64 {
+        self.start + self.buf.len() as u64
+    }
+}
+
+impl Default for Backlog {
+    fn default() -> Backlog {
+        Backlog::new()
+    }
+}
+
+impl Backlog {
+    pub fn new() -> Backlog {
+        Backlog {
+            inner: Mutex::new(BacklogInner {
+                replid: new_replid(),
+                start: 0,
+                buf: VecDeque::new(),
+            }),
+            end_tx: watch::channel(0).0,
+        }
+    }
+
+    /// Append a write. Callers hold the locks of the shards it touched, so
+    /// the stream order matches the order the writes were applied in.
+    pub fn append(&self, args: Vec<Bytes>) {
+        let mut out = BytesMut::new();
+        encode_command(&args, &mut out);
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.buf.extend(&out[..]);
+        let excess = inner.buf.len().saturating_sub(BACKLOG_SIZE);
+        inner.buf.drain(..excess);
+        inner.start += excess as u64;
+        self.end_tx.send_replace(inner.end());
+    }
+
+    /// Follow the end offset of the stream, which changes on every write.
+    pub fn watch_end(&self) -> watch::Receiver<u64> {
+        self.end_tx.subscribe()
+    }
+
+    /// The stream id and the offset the next write will get.
+    pub fn position(&self) -> (String, u64) {
+        let inner = self.inner.lock().unwrap();
+        (inner.replid.clone(), inner.end())
+    }
+
+    /// Start a new stream at the current offset. Anything replicating from
+    /// this server has to do a full sync afterwards.
+    pub fn reset(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.start = inner.end();
+        inner.buf.clear();
+        inner.replid = new_replid();
+    }
+
+    /// Whether a replica that reached `offset` of stream `replid` can pick
+    /// up from the backlog instead of needing a full sync.
+    pub fn can_continue(&self, replid: &str, offset: u64) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.replid == replid && inner.start <= offset && offset <= inner.end()
+    }
+
+    /// The bytes from `offset` to the end of the stream, or `None` if they
+    /// have already been dropped from the backlog.
+    pub fn read_from(&self, offset: u64) -> Option<Vec<u8>> {
+        let inner = self.inner.lock().unwrap();
+        let skip = offset.checked_sub(inner.start)? as usize;
+        Some(inner.buf.range(skip..).copied().collect())
+    }
+}
+
+fn new_replid() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64);
+    (0..3)
+        .map(|_| {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u64(nanos);
+            format!("{:016x}", hasher.finish())
+        })
+        .collect::<String>()[..40]
+        .to_string()
+}
+
+/// Encode a command as a RESP array of bulk strings.
+pub fn encode_command(args: &[Bytes], out: &mut BytesMut) {
+    out.put_slice(format!("*{}\r\n", args.len()).as_bytes());
+    for arg in args {
+        out.put_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.put_slice(arg);
+        out.put_slice(b"\r\n");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backlog_trims_and_resumes() {
+        let backlog = Backlog::new();
+        let (replid, start) = backlog.position();
+        backlog.append(vec!["SET".into(), "k".into(), "v".into()]);
+        let (_, end) = backlog.position();
+
+        assert!(backlog.can_continue(&replid, start));
+        assert!(backlog.can_continue(&replid, end));
+        assert!(!backlog.can_continue("other", start));
+        assert_eq!(
+            backlog.read_from(start).unwrap(),
+            b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n"
+        );
+
+        let big = Bytes::from(vec![b'x'; BACKLOG_SIZE]);
+        backlog.append(vec!["SET".into(), "k".into(), big]);
+        assert!(!backlog.can_continue(&replid, start));
+        assert!(backlog.read_from(start).is_none());
+    }
+}
@@ -0,0 +1,251 @@
+use bytes::{Buf, Bytes, BytesMut};
+use clap::{Arg, ArgAction, Command};
+use mini_redis::frame::{self, Frame};
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_practice::backlog::encode_command;
+
+type AnyError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Keys are picked from this many, so GET finds what SET wrote.
+const KEYSPACE: usize = 10_000;
+
+const TESTS: [&str; 4] = ["set", "get", "lpush", "lpop"];
+
+/// A redis-benchmark style load generator for the tokio-practice server.
+///
+/// Each client sends `--pipeline` commands in one write, then waits for all
+/// of their replies before sending the next batch.
+#[tokio::main]
+async fn main() -> Result<(), AnyError> {
+    let matches = Command::new("bench")
+        .about("Benchmark the tokio-practice server with pipelined commands")
+        .arg(
+            Arg::new("host")
+                .short('h')
+                .long("host")
+                .default_value("127.0.0.1")
+                .help("Server hostname"),
+        )
+        .arg(
+            Arg::new("port")
+                .short('p')
+                .long("port")
+                .default_value("6379")
+                .value_parser(clap::value_parser!(u16))
+                .help("Server port"),
+        )
+        .arg(
+            Arg::new("clients")
+                .short('c')
+                .long("clients")
+                .default_value("50")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Number of parallel connections"),
+        )
+        .arg(
+            Arg::new("requests")
+                .short('n')
+                .long("requests")
+                .default_value("100000")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Total number of requests per test"),
+        )
+        .arg(
+            Arg::new("pipeline")
+                .short('P')
+                .long("pipeline")
+                .default_value("1")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Commands sent per write"),
+        )
+        .arg(
+            Arg::new("data-size")
+                .short('d')
+                .long("data-size")
+                .default_value("3")
+                .value_parser(clap::value_parser!(usize))
+                .help("Bytes in each SET and LPUSH value"),
+        )
+        .arg(
+            Arg::new("tests")
+                .short('t')
+                .long("tests")
+                .default_value("set,get")
+                .value_delimiter(',')
+                .value_parser(TESTS)
+                .help("Comma separated tests to run"),
+        )
+        .disable_help_flag(true)
+        .arg(
+            Arg::new("help")
+                .long("help")
+                .action(ArgAction::Help)
+                .help("Print help"),
+        )
+        .get_matches();
+
+    let host = matches.get_one::<String>("host").unwrap();
+    let port = *matches.get_one::<u16>("port").unwrap();
+    let config = Config {
+        addr: format!("{}:{}", host, port),
+        clients: *matches.get_one::<u64>("clients").unwrap() as usize,
+        requests: *matches.get_one::<u64>("requests").unwrap() as usize,
+        pipeline: *matches.get_one::<u64>("pipeline").unwrap() as usize,
+        value: Bytes::from(vec![b'x'; *matches.get_one::<usize>("data-size").unwrap()]),
+    };
+
+    for test in matches.get_many::<String>("tests").unwrap() {
+        let report = bench(&config, test).await?;
+        report.print(test, &config);
+    }
+    Ok(())
+}
+
+#[derive(Clone)]
+struct Config {
+    addr: String,
+    clients: usize,
+    requests: usize,
+    pipeline: usize,
+    value: Bytes,
+}
+
+struct Report {
+    elapsed: Duration,
+    /// How long each pipeline took, from its write to its last reply.
+    latencies: Vec<Duration>,
+    errors: usize,
+}
+
+impl Report {
+    fn print(&self, test: &str, config: &Config) {
+        let mut latencies = self.latencies.clone();
+        latencies.sort_unstable();
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        let percentile = |p: f64| {
+            let i = ((latencies.len() - 1) as f64 * p).round() as usize;
+            ms(latencies[i])
+        };
+        let avg = latencies.iter().sum::<Duration>() / latencies.len() as u32;
+
+        println!("====== {} ======", test.to_ascii_uppercase());
+        println!(
+            "  {} requests completed in {:.2} seconds",
+            config.requests,
+            self.elapsed.as_secs_f64()
+        );
+        println!("  {} parallel clients", config.clients);
+        println!("  pipeline depth {}", config.pipeline);
+        if self.errors > 0 {
+            println!("  {} error replies", self.errors);
+        }
+        println!(
+            "  latency per pipeline (ms): avg {:.3}, p50 {:.3}, p99 {:.3}, max {:.3}",
+            ms(avg),
+            percentile(0.5),
+            percentile(0.99),
+            ms(*latencies.last().unwrap()),
+        );
+        println!(
+            "  {:.2} requests per second\n",
+            config.requests as f64 / self.elapsed.as_secs_f64()
+        );
+    }
+}
+
+/// Run `requests` commands of one kind, spread over `clients` connections.
+async fn bench(config: &Config, test: &str) -> Result<Report, AnyError> {
+    let start = Instant::now();
+    let mut tasks = Vec::with_capacity(config.clients);
+    for client in 0..config.clients {
+        // The first clients take one extra request when they don't divide
+        // evenly.
+        let requests = config.requests / config.clients
+            + usize::from(client < config.requests % config.clients);
+        let config = config.clone();
+        let test = test.to_string();
+        tasks.push(tokio::spawn(async move {
+            run_client(&config, &test, client, requests).await
+        }));
+    }
+
+    let mut report = Report {
+        elapsed: Duration::ZERO,
+        latencies: Vec::new(),
+        errors: 0,
+    };
+    for task in tasks {
+        let (latencies, errors) = task.await??;
+        report.latencies.extend(latencies);
+        report.errors += errors;
+    }
+    report.elapsed = start.elapsed();
+    Ok(report)
+}
+
+async fn run_client(
+    config: &Config,
+    test: &str,
+    client: usize,
+    requests: usize,
+) -> Result<(Vec<Duration>, usize), AnyError> {
+    let mut socket = TcpStream::connect(&config.addr).await?;
+    let mut out = BytesMut::new();
+    let mut input = BytesMut::with_capacity(4 * 1024);
+    let mut latencies = Vec::new();
+    let mut errors = 0;
+    let mut sent = 0;
+
+    while sent < requests {
+        let batch = config.pipeline.min(requests - sent);
+        out.clear();
+        for i in sent..sent + batch {
+            let key = format!("key:{}", (client * requests + i) % KEYSPACE);
+            let key = Bytes::from(key);
+            let args: Vec<Bytes> = match test {
+                "set" => vec!["SET".into(), key, config.value.clone()],
+                "get" => vec!["GET".into(), key],
+                "lpush" => vec!["LPUSH".into(), "mylist".into(), config.value.clone()],
+                "lpop" => vec!["LPOP".into(), "mylist".into()],
+                _ => unreachable!("clap only accepts known tests"),
+            };
+            encode_command(&args, &mut out);
+        }
+
+        let started = Instant::now();
+        socket.write_all(&out).await?;
+        for _ in 0..batch {
+            if let Frame::Error(_) = read_reply(&mut socket, &mut input).await? {
+                errors += 1;
+            }
+        }
+        latencies.push(started.elapsed());
+        sent += batch;
+    }
+
+    Ok((latencies, errors))
+}
+
+async fn read_reply(socket: &mut TcpStream, input: &mut BytesMut) -> Result<Frame, AnyError> {
+    loop {
+        let mut buf = Cursor::new(&input[..]);
+        match Frame::check(&mut buf) {
+            Ok(()) => {
+                let len = buf.position() as usize;
+                buf.set_position(0);
+                let frame = Frame::parse(&mut buf)?;
+                input.advance(len);
+                return Ok(frame);
+            }
+            Err(frame::Error::Incomplete) => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        if socket.read_buf(input).await? == 0 {
+            return Err("server closed the connection".into());
+        }
+    }
+}
@@ -1,3 +1,320 @@
-fn main () {
-	
-}
\ No newline at end of file
+use bytes::Bytes;
+use clap::{Arg, ArgAction, Command};
+use mini_redis::{Connection, Frame};
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::io::{self, BufRead, IsTerminal};
+use tokio::net::TcpStream;
+
+type AnyError = Box<dyn std::error::Error + Send + Sync>;
+
+#[tokio::main]
+async fn main() -> Result<(), AnyError> {
+    let matches = Command::new("client")
+        .about("A redis-cli style client for the tokio-practice server")
+        .arg(
+            Arg::new("host")
+                .short('h')
+                .long("host")
+                .default_value("127.0.0.1")
+                .help("Server hostname"),
+        )
+        .arg(
+            Arg::new("port")
+                .short('p')
+                .long("port")
+                .default_value("6379")
+                .value_parser(clap::value_parser!(u16))
+                .help("Server port"),
+        )
+        .arg(
+            Arg::new("eval")
+                .short('e')
+                .long("eval")
+                .action(ArgAction::Append)
+                .help("Run a command line and exit, can be given more than once"),
+        )
+        .disable_help_flag(true)
+        .arg(
+            Arg::new("help")
+                .long("help")
+                .action(ArgAction::Help)
+                .help("Print help"),
+        )
+        .get_matches();
+
+    let host = matches.get_one::<String>("host").unwrap();
+    let port = *matches.get_one::<u16>("port").unwrap();
+    let addr = format!("{}:{}", host, port);
+
+    let socket = TcpStream::connect(&addr).await?;
+    let mut connection = Connection::new(socket);
+
+    // `--eval` lines run first and nothing else is read. Without them, a
+    // terminal gets the interactive prompt and anything else (a pipe, a file)
+    // is read line by line so the client can be scripted.
+    if let Some(lines) = matches.get_many::<String>("eval") {
+        for line in lines {
+            run_line(&mut connection, line).await?;
+        }
+        return Ok(());
+    }
+
+    if io::stdin().is_terminal() {
+        repl(&mut connection, &addr).await
+    } else {
+        for line in io::stdin().lock().lines() {
+            run_line(&mut connection, &line?).await?;
+        }
+        Ok(())
+    }
+}
+
+async fn repl(connection: &mut Connection, addr: &str) -> Result<(), AnyError> {
+    let mut rl = DefaultEditor::new()?;
+    let prompt = format!("{}> ", addr);
+
+    loop {
+        // `readline` blocks the thread, so let the runtime know rather than
+        // stalling a worker it might want to schedule something else on.
+        let line = tokio::task::block_in_place(|| rl.readline(&prompt));
+
+        match line {
+            Ok(line) => {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                rl.add_history_entry(line.as_str())?;
+
+                if matches!(line.trim().to_ascii_lowercase().as_str(), "quit" | "exit") {
+                    return Ok(());
+                }
+
+                run_line(connection, &line).await?;
+            }
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Send one command line to the server and print the reply.
+///
+/// Bad quoting is reported and skipped, a closed connection is an error.
+async fn run_line(connection: &mut Connection, line: &str) -> Result<(), AnyError> {
+    let args = match split_args(line) {
+        Ok(args) => args,
+        Err(e) => {
+            println!("(error) {}", e);
+            return Ok(());
+        }
+    };
+
+    if args.is_empty() {
+        return Ok(());
+    }
+
+    let request = Frame::Array(args.into_iter().map(Frame::Bulk).collect());
+    connection.write_frame(&request).await?;
+
+    match connection.read_frame().await? {
+        Some(reply) => {
+            println!("{}", format_reply(&reply, 0));
+            Ok(())
+        }
+        None => Err("connection closed by server".into()),
+    }
+}
+
+/// Split a command line into arguments following redis-cli's rules.
+///
+/// Arguments are separated by whitespace. Double quoted arguments understand
+/// `\n`, `\r`, `\t`, `\b`, `\a`, `\xHH` and backslash-escaped characters, and
+/// single quoted arguments only understand `\'`. A closing quote must be
+/// followed by whitespace or the end of the line.
+fn split_args(line: &str) -> Result<Vec<Bytes>, &'static str> {
+    let mut args = Vec::new();
+    let bytes = line.as_bytes();
+    let mut i = 0;
+
+    loop {
+        while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        if i == bytes.len() {
+            return Ok(args);
+        }
+
+        let mut current = Vec::new();
+        let mut in_double = false;
+        let mut in_single = false;
+
+        loop {
+            if in_double {
+                match bytes.get(i) {
+                    None => return Err("unbalanced quotes"),
+                    Some(b'\\') if i + 3 < bytes.len() && bytes[i + 1] == b'x' => {
+                        match hex_byte(bytes[i + 2], bytes[i + 3]) {
+                            Some(b) => {
+                                current.push(b);
+                                i += 3;
+                            }
+                            None => current.push(b'\\'),
+                        }
+                    }
+                    Some(b'\\') if i + 1 < bytes.len() => {
+                        i += 1;
+                        current.push(match bytes[i] {
+                            b'n' => b'\n',
+                            b'r' => b'\r',
+                            b't' => b'\t',
+                            b'b' => 0x08,
+                            b'a' => 0x07,
+                            c => c,
+                        });
+                    }
+                    Some(b'"') => {
+                        if i + 1 < bytes.len() && !bytes[i + 1].is_ascii_whitespace() {
+                            return Err("closing quote must be followed by a space");
+                        }
+                        i += 1;
+                        break;
+                    }
+                    Some(&c) => current.push(c),
+                }
+            } else if in_single {
+                match bytes.get(i) {
+                    None => return Err("unbalanced quotes"),
+                    Some(b'\\') if bytes.get(i + 1) == Some(&b'\'') => {
+                        current.push(b'\'');
+                        i += 1;
+                    }
+                    Some(b'\'') => {
+                        if i + 1 < bytes.len() && !bytes[i + 1].is_ascii_whitespace() {
+                            return Err("closing quote must be followed by a space");
+                        }
+                        i += 1;
+                        break;
+                    }
+                    Some(&c) => current.push(c),
+                }
+            } else {
+                match bytes.get(i) {
+                    None => break,
+                    Some(c) if c.is_ascii_whitespace() => break,
+                    Some(b'"') => in_double = true,
+                    Some(b'\'') => in_single = true,
+                    Some(&c) => current.push(c),
+                }
+            }
+            i += 1;
+        }
+
+        args.push(Bytes::from(current));
+    }
+}
+
+fn hex_byte(hi: u8, lo: u8) -> Option<u8> {
+    let hi = (hi as char).to_digit(16)?;
+    let lo = (lo as char).to_digit(16)?;
+    Some((hi * 16 + lo) as u8)
+}
+
+/// Render a reply the way redis-cli does in a terminal.
+///
+/// `indent` is the width of the enclosing array's numbering so nested
+/// arrays line up under their parent entry.
+fn format_reply(frame: &Frame, indent: usize) -> String {
+    match frame {
+        Frame::Simple(s) => s.clone(),
+        Frame::Error(e) => format!("(error) {}", e),
+        Frame::Integer(n) => format!("(integer) {}", n),
+        Frame::Bulk(b) => quote_bytes(b),
+        Frame::Null => "(nil)".to_string(),
+        Frame::Array(items) if items.is_empty() => "(empty array)".to_string(),
+        Frame::Array(items) => {
+            let width = items.len().to_string().len();
+            let mut out = String::new();
+
+            for (n, item) in items.iter().enumerate() {
+                if n > 0 {
+                    out.push('\n');
+                    out.push_str(&" ".repeat(indent));
+                }
+                let label = format!("{:>width$}) ", n + 1, width = width);
+                let body = format_reply(item, indent + label.len());
+                out.push_str(&label);
+                out.push_str(&body);
+            }
+
+            out
+        }
+    }
+}
+
+fn quote_bytes(bytes: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for &b in bytes {
+        match b {
+            b'\\' => out.push_str("\\\\"),
+            b'"' => out.push_str("\\\""),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            0x07 => out.push_str("\\a"),
+            0x08 => out.push_str("\\b"),
+            0x20..=0x7e => out.push(b as char),
+            _ => out.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<Bytes> {
+        split_args(line).unwrap()
+    }
+
+    #[test]
+    fn split_plain_and_quoted() {
+        assert_eq!(args("  set foo   bar "), vec!["set", "foo", "bar"]);
+        assert_eq!(
+            args(r#"set "hello world" 'it\'s'"#),
+            vec!["set", "hello world", "it's"]
+        );
+        assert_eq!(args(r#"set k "a\x41\n""#)[2], Bytes::from_static(b"aA\n"));
+        assert!(args("").is_empty());
+    }
+
+    #[test]
+    fn split_rejects_bad_quotes() {
+        assert!(split_args(r#"set "foo"#).is_err());
+        assert!(split_args(r#"set "foo"bar"#).is_err());
+        assert!(split_args("set 'foo").is_err());
+    }
+
+    #[test]
+    fn format_replies() {
+        assert_eq!(format_reply(&Frame::Simple("OK".into()), 0), "OK");
+        assert_eq!(format_reply(&Frame::Null, 0), "(nil)");
+        assert_eq!(format_reply(&Frame::Integer(3), 0), "(integer) 3");
+        assert_eq!(
+            format_reply(&Frame::Bulk("a\"b\x01".into()), 0),
+            r#""a\"b\x01""#
+        );
+        assert_eq!(format_reply(&Frame::Array(vec![]), 0), "(empty array)");
+
+        let nested = Frame::Array(vec![
+            Frame::Bulk("a".into()),
+            Frame::Array(vec![Frame::Integer(1), Frame::Null]),
+        ]);
+        assert_eq!(
+            format_reply(&nested, 0),
+            "1) \"a\"\n2) 1) (integer) 1\n   2) (nil)"
+        );
+    }
+}
@@ -0,0 +1,1209 @@
+use crate::info;
+use bytes::Bytes;
+use mini_redis::Frame;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use tokio_practice::{glob, Db, End, Locked, Value, WrongType};
+
+/// A command parsed from a client frame.
+///
+/// `mini_redis::Command` only knows GET, SET and pub/sub, so the server
+/// parses its own commands from the raw frame.
+#[derive(Debug, Clone)]
+pub enum Command {
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: Bytes,
+        expire: Option<Duration>,
+    },
+    Del {
+        keys: Vec<String>,
+    },
+    Expire {
+        key: String,
+        ttl: Duration,
+    },
+    HSet {
+        key: String,
+        pairs: Vec<(Bytes, Bytes)>,
+    },
+    HGet {
+        key: String,
+        field: Bytes,
+    },
+    HDel {
+        key: String,
+        fields: Vec<Bytes>,
+    },
+    HGetAll {
+        key: String,
+    },
+    Push {
+        key: String,
+        end: End,
+        values: Vec<Bytes>,
+    },
+    Pop {
+        key: String,
+        end: End,
+        count: Option<usize>,
+    },
+    BPop {
+        keys: Vec<String>,
+        end: End,
+        timeout: Option<Duration>,
+    },
+    LRange {
+        key: String,
+        start: i64,
+        stop: i64,
+    },
+    SAdd {
+        key: String,
+        members: Vec<Bytes>,
+    },
+    SRem {
+        key: String,
+        members: Vec<Bytes>,
+    },
+    SMembers {
+        key: String,
+    },
+    SIsMember {
+        key: String,
+        member: Bytes,
+    },
+    Multi,
+    Exec,
+    Discard,
+    Watch {
+        keys: Vec<String>,
+    },
+    Unwatch,
+    ReplicaOf {
+        primary: Option<String>,
+    },
+    PSync {
+        replid: String,
+        offset: Option<u64>,
+    },
+    Info {
+        section: Option<String>,
+    },
+    Keys {
+        pattern: Bytes,
+    },
+    Scan {
+        cursor: u64,
+        pattern: Option<Bytes>,
+        count: usize,
+    },
+    DbSize,
+    FlushDb,
+}
+
+impl Command {
+    /// Parse a command from a request frame. The error is the reply to send
+    /// back to the client.
+    pub fn from_frame(frame: Frame) -> Result<Command, Frame> {
+        let mut parse = Parse::new(frame)?;
+        let name = parse.next_string()?.to_ascii_lowercase();
+        parse.name = name.clone();
+
+        let command = match name.as_str() {
+            "get" => Command::Get {
+                key: parse.next_string()?,
+            },
+            "set" => {
+                let key = parse.next_string()?;
+                let value = parse.next_bytes()?;
+                let mut expire = None;
+                while parse.has_more() {
+                    let unit = match parse.next_string()?.to_ascii_uppercase().as_str() {
+                        "EX" => Duration::from_secs(1),
+                        "PX" => Duration::from_millis(1),
+                        _ => return Err(Frame::Error("ERR syntax error".to_string())),
+                    };
+                    let amount = u32::try_from(parse.next_int()?)
+                        .ok()
+                        .filter(|&n| n > 0)
+                        .ok_or_else(|| {
+                            Frame::Error("ERR invalid expire time in 'set' command".to_string())
+                        })?;
+                    expire = Some(unit * amount);
+                }
+                Command::Set { key, value, expire }
+            }
+            "del" => Command::Del {
+                keys: parse.rest_strings()?,
+            },
+            "expire" | "pexpire" => {
+                let key = parse.next_string()?;
+                // A TTL that is not positive expires the key right away.
+                let amount = u64::try_from(parse.next_int()?).unwrap_or(0);
+                let ttl = if name == "expire" {
+                    Duration::from_secs(amount)
+                } else {
+                    Duration::from_millis(amount)
+                };
+                expires_at(ttl, &name)?;
+                Command::Expire { key, ttl }
+            }
+            "hset" => {
+                let key = parse.next_string()?;
+                let mut pairs = vec![(parse.next_bytes()?, parse.next_bytes()?)];
+                while parse.has_more() {
+                    pairs.push((parse.next_bytes()?, parse.next_bytes()?));
+                }
+                Command::HSet { key, pairs }
+            }
+            "hget" => Command::HGet {
+                key: parse.next_string()?,
+                field: parse.next_bytes()?,
+            },
+            "hdel" => Command::HDel {
+                key: parse.next_string()?,
+                fields: parse.rest_bytes()?,
+            },
+            "hgetall" => Command::HGetAll {
+                key: parse.next_string()?,
+            },
+            "lpush" | "rpush" => Command::Push {
+                key: parse.next_string()?,
+                end: if name == "lpush" {
+                    End::Left
+                } else {
+                    End::Right
+                },
+                values: parse.rest_bytes()?,
+            },
+            "lpop" | "rpop" => {
+                let key = parse.next_string()?;
+                let count = if parse.has_more() {
+                    Some(parse.next_count()?)
+                } else {
+                    None
+                };
+                Command::Pop {
+                    key,
+                    end: if name == "lpop" {
+                        End::Left
+                    } else {
+                        End::Right
+                    },
+                    count,
+                }
+            }
+            "blpop" | "brpop" => {
+                let mut keys = vec![parse.next_string()?];
+                keys.extend(parse.rest_strings()?);
+                let timeout = keys.pop().unwrap();
+                if keys.is_empty() {
+                    return Err(parse.wrong_arity());
+                }
+                Command::BPop {
+                    keys,
+                    end: if name == "blpop" {
+                        End::Left
+                    } else {
+                        End::Right
+                    },
+                    timeout: parse_timeout(&timeout)?,
+                }
+            }
+            "lrange" => Command::LRange {
+                key: parse.next_string()?,
+                start: parse.next_int()?,
+                stop: parse.next_int()?,
+            },
+            "sadd" => Command::SAdd {
+                key: parse.next_string()?,
+                members: parse.rest_bytes()?,
+            },
+            "srem" => Command::SRem {
+                key: parse.next_string()?,
+                members: parse.rest_bytes()?,
+            },
+            "smembers" => Command::SMembers {
+                key: parse.next_string()?,
+            },
+            "sismember" => Command::SIsMember {
+                key: parse.next_string()?,
+                member: parse.next_bytes()?,
+            },
+            "multi" => Command::Multi,
+            "exec" => Command::Exec,
+            "discard" => Command::Discard,
+            "watch" => Command::Watch {
+                keys: parse.rest_strings()?,
+            },
+            "unwatch" => Command::Unwatch,
+            "replicaof" => {
+                let host = parse.next_string()?;
+                let port = parse.next_string()?;
+                let primary = if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one")
+                {
+                    None
+                } else {
+                    Some(format!("{}:{}", host, port))
+                };
+                Command::ReplicaOf { primary }
+            }
+            "psync" => Command::PSync {
+                replid: parse.next_string()?,
+                // `-1` asks for a full sync.
+                offset: u64::try_from(parse.next_int()?).ok(),
+            },
+            "keys" => Command::Keys {
+                pattern: parse.next_bytes()?,
+            },
+            "scan" => {
+                let cursor = parse
+                    .next_string()?
+                    .parse()
+                    .map_err(|_| Frame::Error("ERR invalid cursor".to_string()))?;
+                let mut pattern = None;
+                let mut count = 10;
+                while parse.has_more() {
+                    match parse.next_string()?.to_ascii_uppercase().as_str() {
+                        "MATCH" => pattern = Some(parse.next_bytes()?),
+                        "COUNT" => {
+                            count = parse.next_count()?;
+                            if count == 0 {
+                                return Err(Frame::Error("ERR syntax error".to_string()));
+                            }
+                        }
+                        _ => return Err(Frame::Error("ERR syntax error".to_string())),
+                    }
+                }
+                Command::Scan {
+                    cursor,
+                    pattern,
+                    count,
+                }
+            }
+            "dbsize" => Command::DbSize,
+            "flushdb" => Command::FlushDb,
+            "info" => Command::Info {
+                section: if parse.has_more() {
+                    Some(parse.next_string()?)
+                } else {
+                    None
+                },
+            },
+            _ => return Err(Frame::Error(format!("ERR unknown command '{}'", name))),
+        };
+
+        parse.finish()?;
+        Ok(command)
+    }
+
+    /// The keys this command reads or writes, used to lock their shards.
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            Command::Get { key }
+            | Command::Set { key, .. }
+            | Command::Expire { key, .. }
+            | Command::HSet { key, .. }
+            | Command::HGet { key, .. }
+            | Command::HDel { key, .. }
+            | Command::HGetAll { key }
+            | Command::Push { key, .. }
+            | Command::Pop { key, .. }
+            | Command::LRange { key, .. }
+            | Command::SAdd { key, .. }
+            | Command::SRem { key, .. }
+            | Command::SMembers { key }
+            | Command::SIsMember { key, .. } => vec![key.as_str()],
+            Command::Del { keys } | Command::BPop { keys, .. } | Command::Watch { keys } => {
+                keys.iter().map(String::as_str).collect()
+            }
+            Command::Multi
+            | Command::Exec
+            | Command::Discard
+            | Command::Unwatch
+            | Command::ReplicaOf { .. }
+            | Command::PSync { .. }
+            | Command::Info { .. }
+            | Command::Keys { .. }
+            | Command::Scan { .. }
+            | Command::DbSize
+            | Command::FlushDb => vec![],
+        }
+    }
+
+    /// Whether the command looks at the whole key space rather than a list of
+    /// keys, and so needs every shard when run inside a transaction.
+    pub fn locks_all(&self) -> bool {
+        matches!(
+            self,
+            Command::Info { .. }
+                | Command::Keys { .. }
+                | Command::Scan { .. }
+                | Command::DbSize
+                | Command::FlushDb
+        )
+    }
+
+    /// Lock the shards this command needs.
+    pub fn lock<'a>(&self, db: &'a Db) -> Locked<'a> {
+        match self {
+            // A SCAN call only walks the shard its cursor points into.
+            Command::Scan { cursor, .. } => db.lock_cursor(*cursor),
+            cmd if cmd.locks_all() => db.lock_all(),
+            cmd => db.lock(&cmd.keys()),
+        }
+    }
+
+    /// The command name as INFO reports it.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Get { .. } => "get",
+            Command::Set { .. } => "set",
+            Command::Del { .. } => "del",
+            Command::Expire { .. } => "expire",
+            Command::HSet { .. } => "hset",
+            Command::HGet { .. } => "hget",
+            Command::HDel { .. } => "hdel",
+            Command::HGetAll { .. } => "hgetall",
+            Command::Push { end: End::Left, .. } => "lpush",
+            Command::Push {
+                end: End::Right, ..
+            } => "rpush",
+            Command::Pop { end: End::Left, .. } => "lpop",
+            Command::Pop {
+                end: End::Right, ..
+            } => "rpop",
+            Command::BPop { end: End::Left, .. } => "blpop",
+            Command::BPop {
+                end: End::Right, ..
+            } => "brpop",
+            Command::LRange { .. } => "lrange",
+            Command::SAdd { .. } => "sadd",
+            Command::SRem { .. } => "srem",
+            Command::SMembers { .. } => "smembers",
+            Command::SIsMember { .. } => "sismember",
+            Command::Multi => "multi",
+            Command::Exec => "exec",
+            Command::Discard => "discard",
+            Command::Watch { .. } => "watch",
+            Command::Unwatch => "unwatch",
+            Command::ReplicaOf { .. } => "replicaof",
+            Command::PSync { .. } => "psync",
+            Command::Info { .. } => "info",
+            Command::Keys { .. } => "keys",
+            Command::Scan { .. } => "scan",
+            Command::DbSize => "dbsize",
+            Command::FlushDb => "flushdb",
+        }
+    }
+
+    /// Whether the command changes data. Replicas refuse these from clients.
+    pub fn is_write(&self) -> bool {
+        matches!(self, Command::BPop { .. }) || self.write_args().is_some()
+    }
+
+    /// Whether the command may grow memory use, so it is refused once
+    /// `maxmemory` is reached and nothing can be evicted.
+    pub fn denies_oom(&self) -> bool {
+        matches!(
+            self,
+            Command::Set { .. }
+                | Command::HSet { .. }
+                | Command::Push { .. }
+                | Command::SAdd { .. }
+        )
+    }
+
+    /// The arguments that replay this command on a replica, for commands that
+    /// write. BLPOP/BRPOP are left out as they replicate as the pop they do.
+    fn write_args(&self) -> Option<Vec<Bytes>> {
+        fn args(name: &str, key: &str, rest: impl IntoIterator<Item = Bytes>) -> Vec<Bytes> {
+            let mut args = vec![Bytes::copy_from_slice(name.as_bytes())];
+            args.push(Bytes::copy_from_slice(key.as_bytes()));
+            args.extend(rest);
+            args
+        }
+
+        let args = match self {
+            // TTLs are sent as they were given, so a replica's copy expires
+            // about when the primary's does.
+            Command::Set { key, value, expire } => args(
+                "SET",
+                key,
+                [value.clone()].into_iter().chain(
+                    expire
+                        .iter()
+                        .flat_map(|ttl| ["PX".into(), Bytes::from(ttl.as_millis().to_string())]),
+                ),
+            ),
+            Command::Del { keys } => {
+                let mut args = vec![Bytes::from("DEL")];
+                args.extend(
+                    keys.iter()
+                        .map(|key| Bytes::copy_from_slice(key.as_bytes())),
+                );
+                args
+            }
+            Command::Expire { key, ttl } => {
+                args("PEXPIRE", key, [Bytes::from(ttl.as_millis().to_string())])
+            }
+            Command::FlushDb => vec![Bytes::from("FLUSHDB")],
+            Command::HSet { key, pairs } => args(
+                "HSET",
+                key,
+                pairs.iter().flat_map(|(f, v)| [f.clone(), v.clone()]),
+            ),
+            Command::HDel { key, fields } => args("HDEL", key, fields.iter().cloned()),
+            Command::Push { key, end, values } => {
+                args(end.push_command(), key, values.iter().cloned())
+            }
+            Command::Pop { key, end, count } => args(
+                end.pop_command(),
+                key,
+                count.map(|c| Bytes::from(c.to_string())),
+            ),
+            Command::SAdd { key, members } => args("SADD", key, members.iter().cloned()),
+            Command::SRem { key, members } => args("SREM", key, members.iter().cloned()),
+            _ => return None,
+        };
+        Some(args)
+    }
+
+    /// Lock the shards this command needs and run it.
+    pub fn apply(self, db: &Db) -> Frame {
+        if self.denies_oom() {
+            if let Err(oom) = db.make_room() {
+                return Frame::Error(oom.to_string());
+            }
+        }
+        let mut locked = self.lock(db);
+        self.execute(&mut locked)
+    }
+
+    /// Run the command against shards the caller already holds.
+    pub fn execute(self, db: &mut Locked<'_>) -> Frame {
+        // A write that fails, say with WRONGTYPE, fails the same way on a
+        // replica, so it is safe to send before knowing the outcome.
+        if let Some(args) = self.write_args() {
+            db.propagate(args);
+        }
+
+        match self.try_execute(db) {
+            Ok(frame) => frame,
+            Err(WrongType) => Frame::Error(WrongType.to_string()),
+        }
+    }
+
+    fn try_execute(self, db: &mut Locked<'_>) -> Result<Frame, WrongType> {
+        let frame = match self {
+            Command::Get { key } => match db.get_string(&key)? {
+                Some(value) => Frame::Bulk(value),
+                None => Frame::Null,
+            },
+            Command::Set { key, value, expire } => {
+                let expires_at = match expire.map(|ttl| expires_at(ttl, "set")).transpose() {
+                    Ok(at) => at,
+                    Err(e) => return Ok(e),
+                };
+                db.set(&key, Value::String(value));
+                if let Some(at) = expires_at {
+                    db.set_expiry(&key, Some(at));
+                }
+                Frame::Simple("OK".to_string())
+            }
+            Command::Del { keys } => {
+                let removed = keys.iter().filter(|key| db.remove(key)).count();
+                Frame::Integer(removed as u64)
+            }
+            Command::Expire { key, ttl } => {
+                let expires_at = match expires_at(ttl, "expire") {
+                    Ok(at) => at,
+                    Err(e) => return Ok(e),
+                };
+                let found = db.set_expiry(&key, Some(expires_at));
+                Frame::Integer(found as u64)
+            }
+            Command::HSet { key, pairs } => {
+                let added = db.update_hash(&key, |hash| {
+                    pairs
+                        .into_iter()
+                        .filter(|(field, value)| {
+                            hash.insert(field.clone(), value.clone()).is_none()
+                        })
+                        .count()
+                })?;
+                Frame::Integer(added as u64)
+            }
+            Command::HGet { key, field } => match db.hash(&key)?.and_then(|h| h.get(&field)) {
+                Some(value) => Frame::Bulk(value.clone()),
+                None => Frame::Null,
+            },
+            Command::HDel { key, fields } => {
+                // Leave the hash alone, version and all, unless a field goes.
+                let present = db.hash(&key)?;
+                if !present.is_some_and(|hash| fields.iter().any(|f| hash.contains_key(f))) {
+                    return Ok(Frame::Integer(0));
+                }
+                let removed = db.update_hash(&key, |hash| {
+                    fields.iter().filter(|f| hash.remove(*f).is_some()).count()
+                })?;
+                Frame::Integer(removed as u64)
+            }
+            Command::HGetAll { key } => {
+                let mut out = Vec::new();
+                if let Some(hash) = db.hash(&key)? {
+                    for (field, value) in hash {
+                        out.push(Frame::Bulk(field.clone()));
+                        out.push(Frame::Bulk(value.clone()));
+                    }
+                }
+                Frame::Array(out)
+            }
+            Command::Push { key, end, values } => {
+                let len = db.update_list(&key, |list| {
+                    for value in values {
+                        end.push(list, value);
+                    }
+                    list.len()
+                })?;
+                Frame::Integer(len as u64)
+            }
+            Command::Pop { key, end, count } => {
+                if db.list(&key)?.is_none() {
+                    return Ok(Frame::Null);
+                }
+                let popped = db.update_list(&key, |list| pop(list, end, count.unwrap_or(1)))?;
+                match count {
+                    Some(_) => Frame::Array(popped.into_iter().map(Frame::Bulk).collect()),
+                    None => popped.into_iter().next().map_or(Frame::Null, Frame::Bulk),
+                }
+            }
+            // Outside of the connection loop there is nothing to wait on, so
+            // this behaves like LPOP/RPOP over the first non-empty list.
+            Command::BPop { keys, end, .. } => {
+                for key in keys {
+                    if db.list(&key)?.is_some() {
+                        if let Some(value) = db.update_list(&key, |list| end.pop(list))? {
+                            db.propagate(vec![end.pop_command().into(), key.clone().into()]);
+                            return Ok(key_value(key, value));
+                        }
+                    }
+                }
+                Frame::Null
+            }
+            Command::LRange { key, start, stop } => {
+                let mut out = Vec::new();
+                if let Some(list) = db.list(&key)? {
+                    if let Some((start, stop)) = range(start, stop, list.len()) {
+                        out.extend(list.range(start..=stop).cloned().map(Frame::Bulk));
+                    }
+                }
+                Frame::Array(out)
+            }
+            Command::SAdd { key, members } => {
+                let added = db.update_set(&key, |set| {
+                    members
+                        .into_iter()
+                        .filter(|m| set.insert(m.clone()))
+                        .count()
+                })?;
+                Frame::Integer(added as u64)
+            }
+            Command::SRem { key, members } => {
+                let present = db.set_members(&key)?;
+                if !present.is_some_and(|set| members.iter().any(|m| set.contains(m))) {
+                    return Ok(Frame::Integer(0));
+                }
+                let removed = db.update_set(&key, |set| {
+                    members.iter().filter(|m| set.remove(*m)).count()
+                })?;
+                Frame::Integer(removed as u64)
+            }
+            Command::SMembers { key } => {
+                let members = db.set_members(&key)?.into_iter().flatten();
+                Frame::Array(members.cloned().map(Frame::Bulk).collect())
+            }
+            Command::SIsMember { key, member } => {
+                let found = db.set_members(&key)?.is_some_and(|s| s.contains(&member));
+                Frame::Integer(found as u64)
+            }
+            // Transaction and replication commands are run by the connection
+            // loop. Only UNWATCH can be queued inside MULTI, where EXEC is about to
+            // unwatch everything anyway.
+            Command::Unwatch => Frame::Simple("OK".to_string()),
+            Command::Info { section } => Frame::Bulk(info::render(db, section.as_deref()).into()),
+            Command::Keys { pattern } => {
+                let mut keys = Vec::new();
+                db.for_each(|key, _, _| {
+                    if glob::matches(&pattern, key.as_bytes()) {
+                        keys.push(Frame::Bulk(Bytes::copy_from_slice(key.as_bytes())));
+                    }
+                });
+                Frame::Array(keys)
+            }
+            // MATCH filters the keys a call walked, so a call may return
+            // fewer than COUNT keys, or none, and still not be done.
+            Command::Scan {
+                cursor,
+                pattern,
+                count,
+            } => {
+                let (next, keys) = db.scan(cursor, count);
+                let keys = keys
+                    .into_iter()
+                    .filter(|key| {
+                        pattern
+                            .as_ref()
+                            .is_none_or(|p| glob::matches(p, key.as_bytes()))
+                    })
+                    .map(|key| Frame::Bulk(Bytes::from(key)))
+                    .collect();
+                Frame::Array(vec![
+                    Frame::Bulk(Bytes::from(next.to_string())),
+                    Frame::Array(keys),
+                ])
+            }
+            Command::DbSize => Frame::Integer(db.len() as u64),
+            Command::FlushDb => {
+                db.clear();
+                Frame::Simple("OK".to_string())
+            }
+            Command::Multi
+            | Command::Exec
+            | Command::Discard
+            | Command::Watch { .. }
+            | Command::ReplicaOf { .. }
+            | Command::PSync { .. } => {
+                Frame::Error("ERR command not allowed inside a transaction".to_string())
+            }
+        };
+
+        Ok(frame)
+    }
+}
+
+/// The `[key, value]` reply of BLPOP/BRPOP.
+pub fn key_value(key: String, value: Bytes) -> Frame {
+    Frame::Array(vec![Frame::Bulk(Bytes::from(key)), Frame::Bulk(value)])
+}
+
+/// When a TTL given now runs out. Parsing checks this before any shard is
+/// locked, so one too long to represent is turned away up front. Replicas
+/// are sent the TTL in milliseconds, so it has to fit in those too.
+fn expires_at(ttl: Duration, command: &str) -> Result<Instant, Frame> {
+    i64::try_from(ttl.as_millis())
+        .ok()
+        .and_then(|_| Instant::now().checked_add(ttl))
+        .ok_or_else(|| Frame::Error(format!("ERR invalid expire time in '{}' command", command)))
+}
+
+/// Blocking timeouts are given in seconds and may be fractional. Zero means
+/// wait forever.
+fn parse_timeout(timeout: &str) -> Result<Option<Duration>, Frame> {
+    let secs: f64 = timeout
+        .parse()
+        .ok()
+        .filter(|s: &f64| s.is_finite())
+        .ok_or_else(|| Frame::Error("ERR timeout is not a float or out of range".to_string()))?;
+    if secs < 0.0 {
+        return Err(Frame::Error("ERR timeout is negative".to_string()));
+    }
+    if secs == 0.0 {
+        return Ok(None);
+    }
+    Duration::try_from_secs_f64(secs)
+        .map(Some)
+        .map_err(|_| Frame::Error("ERR timeout is out of range".to_string()))
+}
+
+fn pop(list: &mut VecDeque<Bytes>, end: End, count: usize) -> Vec<Bytes> {
+    (0..count).map_while(|_| end.pop(list)).collect()
+}
+
+/// Resolve LRANGE style indexes, where negative values count from the end,
+/// into an inclusive range, or `None` when the range is empty.
+fn range(start: i64, stop: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        len + stop
+    } else {
+        stop.min(len - 1)
+    };
+
+    if start > stop || start >= len {
+        None
+    } else {
+        Some((start as usize, stop as usize))
+    }
+}
+
+/// Walks the arguments of a request frame, which is an array of bulk
+/// strings. Errors are ready to be sent back as the reply.
+struct Parse {
+    name: String,
+    parts: std::vec::IntoIter<Frame>,
+}
+
+impl Parse {
+    fn new(frame: Frame) -> Result<Parse, Frame> {
+        match frame {
+            Frame::Array(parts) if !parts.is_empty() => Ok(Parse {
+                name: String::new(),
+                parts: parts.into_iter(),
+            }),
+            frame => Err(Frame::Error(format!(
+                "ERR protocol error; expected array frame, got {:?}",
+                frame
+            ))),
+        }
+    }
+
+    fn has_more(&self) -> bool {
+        self.parts.len() > 0
+    }
+
+    fn next_bytes(&mut self) -> Result<Bytes, Frame> {
+        match self.parts.next() {
+            Some(Frame::Bulk(b)) => Ok(b),
+            Some(Frame::Simple(s)) => Ok(Bytes::from(s)),
+            Some(frame) => Err(Frame::Error(format!(
+                "ERR protocol error; expected bulk frame, got {:?}",
+                frame
+            ))),
+            None => Err(self.wrong_arity()),
+        }
+    }
+
+    fn next_string(&mut self) -> Result<String, Frame> {
+        let bytes = self.next_bytes()?;
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_| Frame::Error("ERR protocol error; invalid string".to_string()))
+    }
+
+    fn next_int(&mut self) -> Result<i64, Frame> {
+        // The mini-redis client sends some numbers, like the PX of SET, as
+        // integer frames.
+        let text = match self.parts.as_slice().first() {
+            Some(Frame::Integer(n)) => {
+                let n = n.to_string();
+                self.parts.next();
+                n
+            }
+            _ => self.next_string()?,
+        };
+        text.parse()
+            .map_err(|_| Frame::Error("ERR value is not an integer or out of range".to_string()))
+    }
+
+    fn next_count(&mut self) -> Result<usize, Frame> {
+        usize::try_from(self.next_int()?)
+            .map_err(|_| Frame::Error("ERR value is out of range, must be positive".to_string()))
+    }
+
+    /// All remaining arguments, of which there must be at least one.
+    fn rest_bytes(&mut self) -> Result<Vec<Bytes>, Frame> {
+        let mut out = vec![self.next_bytes()?];
+        while self.has_more() {
+            out.push(self.next_bytes()?);
+        }
+        Ok(out)
+    }
+
+    fn rest_strings(&mut self) -> Result<Vec<String>, Frame> {
+        let mut out = vec![self.next_string()?];
+        while self.has_more() {
+            out.push(self.next_string()?);
+        }
+        Ok(out)
+    }
+
+    fn finish(&mut self) -> Result<(), Frame> {
+        if self.has_more() {
+            Err(self.wrong_arity())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn wrong_arity(&self) -> Frame {
+        Frame::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            self.name
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_practice::memory::{self, OutOfMemory, Policy};
+
+    fn run(db: &Db, args: &[&str]) -> Frame {
+        let frame = Frame::Array(
+            args.iter()
+                .map(|a| Frame::Bulk(Bytes::copy_from_slice(a.as_bytes())))
+                .collect(),
+        );
+        match Command::from_frame(frame) {
+            Ok(cmd) => cmd.apply(db),
+            Err(reply) => reply,
+        }
+    }
+
+    // `Frame` has no `PartialEq`, so replies are compared by their debug output.
+    fn check(db: &Db, args: &[&str], expected: Frame) {
+        assert_eq!(format!("{:?}", run(db, args)), format!("{:?}", expected));
+    }
+
+    fn bulk(s: &str) -> Frame {
+        Frame::Bulk(Bytes::copy_from_slice(s.as_bytes()))
+    }
+
+    #[test]
+    fn hash_commands() {
+        let db = Db::new(4);
+        check(&db, &["HSET", "h", "a", "1", "b", "2"], Frame::Integer(2));
+        check(&db, &["HSET", "h", "a", "3"], Frame::Integer(0));
+        check(&db, &["HGET", "h", "a"], bulk("3"));
+        check(&db, &["HDEL", "h", "a", "x"], Frame::Integer(1));
+        check(
+            &db,
+            &["HGETALL", "h"],
+            Frame::Array(vec![bulk("b"), bulk("2")]),
+        );
+        check(&db, &["HDEL", "h", "b"], Frame::Integer(1));
+        check(&db, &["HGETALL", "h"], Frame::Array(vec![]));
+    }
+
+    #[test]
+    fn list_commands() {
+        let db = Db::new(4);
+        check(&db, &["RPUSH", "l", "b", "c"], Frame::Integer(2));
+        check(&db, &["LPUSH", "l", "a"], Frame::Integer(3));
+        check(
+            &db,
+            &["LRANGE", "l", "0", "-1"],
+            Frame::Array(vec![bulk("a"), bulk("b"), bulk("c")]),
+        );
+        check(
+            &db,
+            &["LRANGE", "l", "-2", "10"],
+            Frame::Array(vec![bulk("b"), bulk("c")]),
+        );
+        check(&db, &["LRANGE", "l", "2", "1"], Frame::Array(vec![]));
+        check(&db, &["RPOP", "l"], bulk("c"));
+        check(
+            &db,
+            &["LPOP", "l", "5"],
+            Frame::Array(vec![bulk("a"), bulk("b")]),
+        );
+        check(&db, &["LPOP", "l"], Frame::Null);
+    }
+
+    #[test]
+    fn set_commands() {
+        let db = Db::new(4);
+        check(&db, &["SADD", "s", "a", "b", "a"], Frame::Integer(2));
+        check(&db, &["SISMEMBER", "s", "a"], Frame::Integer(1));
+        check(&db, &["SREM", "s", "a", "z"], Frame::Integer(1));
+        check(&db, &["SMEMBERS", "s"], Frame::Array(vec![bulk("b")]));
+        check(&db, &["SISMEMBER", "s", "a"], Frame::Integer(0));
+    }
+
+    #[test]
+    fn removing_nothing_leaves_the_version_alone() {
+        let db = Db::new(4);
+        run(&db, &["SADD", "s", "a"]);
+        run(&db, &["HSET", "h", "f", "1"]);
+        let keys = ["s".to_string(), "h".to_string()];
+        let before = db.watch(&keys);
+        check(&db, &["SREM", "s", "z"], Frame::Integer(0));
+        check(&db, &["HDEL", "h", "z"], Frame::Integer(0));
+        assert_eq!(db.watch(&keys), before);
+        check(&db, &["SREM", "s", "a"], Frame::Integer(1));
+        check(&db, &["HDEL", "h", "f"], Frame::Integer(1));
+        assert_ne!(db.watch(&keys), before);
+    }
+
+    #[test]
+    fn expiry() {
+        let db = Db::new(4);
+        run(&db, &["SET", "a", "1", "EX", "100"]);
+        run(&db, &["SET", "b", "2"]);
+        check(&db, &["EXPIRE", "b", "0"], Frame::Integer(1));
+        check(&db, &["EXPIRE", "nope", "10"], Frame::Integer(0));
+        check(&db, &["GET", "a"], bulk("1"));
+        check(&db, &["GET", "b"], Frame::Null);
+        check(&db, &["DEL", "a", "b"], Frame::Integer(1));
+        check(
+            &db,
+            &["SET", "a", "1", "PX", "0"],
+            Frame::Error("ERR invalid expire time in 'set' command".to_string()),
+        );
+    }
+
+    #[test]
+    fn huge_ttls_are_refused() {
+        let db = Db::new(4);
+        run(&db, &["SET", "a", "1"]);
+        let max = i64::MAX.to_string();
+        check(
+            &db,
+            &["EXPIRE", "a", &max],
+            Frame::Error("ERR invalid expire time in 'expire' command".to_string()),
+        );
+        // Fits an `Instant` but not the milliseconds a replica is sent.
+        check(
+            &db,
+            &["EXPIRE", "a", "100000000000000000"],
+            Frame::Error("ERR invalid expire time in 'expire' command".to_string()),
+        );
+        check(
+            &db,
+            &["SET", "a", "2", "EX", &max],
+            Frame::Error("ERR invalid expire time in 'set' command".to_string()),
+        );
+        // The shard lock was never poisoned.
+        check(&db, &["GET", "a"], bulk("1"));
+    }
+
+    #[test]
+    fn blocking_timeouts_must_fit_a_duration() {
+        let db = Db::new(4);
+        check(
+            &db,
+            &["BLPOP", "l", "1e300"],
+            Frame::Error("ERR timeout is out of range".to_string()),
+        );
+        check(
+            &db,
+            &["BRPOP", "l", "-1"],
+            Frame::Error("ERR timeout is negative".to_string()),
+        );
+    }
+
+    /// A database with room for three small keys, holding `a`, `b` and `c`.
+    fn full_db(policy: Policy) -> Db {
+        // A single shard, so eviction samples every key.
+        let db = Db::new(1);
+        let limit = 3 * memory::entry_size("a", &Value::String("1".into()));
+        db.memory().configure(limit, policy);
+        for key in ["a", "b", "c"] {
+            check(&db, &["SET", key, "1"], Frame::Simple("OK".to_string()));
+        }
+        db
+    }
+
+    fn exists(db: &Db, key: &str) -> bool {
+        !matches!(run(db, &["GET", key]), Frame::Null)
+    }
+
+    #[test]
+    fn noeviction_refuses_writes() {
+        let db = full_db(Policy::NoEviction);
+        // Memory is checked before a write, so this one goes over the limit.
+        run(&db, &["SET", "d", "1"]);
+        check(
+            &db,
+            &["SADD", "s", "x"],
+            Frame::Error(OutOfMemory.to_string()),
+        );
+        // Commands that free memory still work.
+        check(&db, &["DEL", "d"], Frame::Integer(1));
+        check(&db, &["SADD", "s", "x"], Frame::Integer(1));
+        assert_eq!(db.memory().evicted(), 0);
+    }
+
+    #[test]
+    fn allkeys_lru_evicts_least_recently_used() {
+        let db = full_db(Policy::AllKeysLru);
+        run(&db, &["GET", "a"]);
+        run(&db, &["SET", "d", "1"]);
+        run(&db, &["SET", "e", "1"]);
+        assert!(exists(&db, "a"));
+        assert!(!exists(&db, "b"));
+        assert_eq!(db.memory().evicted(), 1);
+    }
+
+    #[test]
+    fn allkeys_lfu_evicts_least_frequently_used() {
+        let db = full_db(Policy::AllKeysLfu);
+        for key in ["a", "c", "a", "c"] {
+            run(&db, &["GET", key]);
+        }
+        run(&db, &["SET", "d", "1"]);
+        run(&db, &["SET", "e", "1"]);
+        assert!(!exists(&db, "b"));
+        assert!(exists(&db, "a") && exists(&db, "c"));
+    }
+
+    #[test]
+    fn volatile_ttl_evicts_keys_with_a_ttl() {
+        let db = full_db(Policy::VolatileTtl);
+        run(&db, &["EXPIRE", "b", "100"]);
+        run(&db, &["EXPIRE", "c", "50"]);
+        run(&db, &["SET", "d", "1"]);
+        run(&db, &["SET", "e", "1"]);
+        assert!(!exists(&db, "c"));
+        run(&db, &["SET", "f", "1"]);
+        assert!(!exists(&db, "b"));
+
+        // Only keys without a TTL are left, so nothing may be evicted.
+        check(
+            &db,
+            &["SET", "g", "1"],
+            Frame::Error(OutOfMemory.to_string()),
+        );
+    }
+
+    #[test]
+    fn keyspace_commands() {
+        let db = Db::new(4);
+        for key in ["user:1", "user:2", "session:1"] {
+            run(&db, &["SET", key, "x"]);
+        }
+        check(&db, &["DBSIZE"], Frame::Integer(3));
+        let Frame::Array(mut keys) = run(&db, &["KEYS", "user:*"]) else {
+            panic!("KEYS did not return an array");
+        };
+        keys.sort_by_key(|k| format!("{:?}", k));
+        assert_eq!(
+            format!("{:?}", keys),
+            format!("{:?}", [bulk("user:1"), bulk("user:2")])
+        );
+        check(&db, &["FLUSHDB"], Frame::Simple("OK".to_string()));
+        check(&db, &["DBSIZE"], Frame::Integer(0));
+        check(&db, &["KEYS", "*"], Frame::Array(vec![]));
+    }
+
+    /// Run one SCAN call, returning the next cursor and the keys.
+    fn scan(db: &Db, cursor: &str, extra: &[&str]) -> (String, Vec<String>) {
+        let mut args = vec!["SCAN", cursor];
+        args.extend(extra);
+        let Frame::Array(reply) = run(db, &args) else {
+            panic!("SCAN did not return an array");
+        };
+        let text = |frame: &Frame| match frame {
+            Frame::Bulk(b) => String::from_utf8(b.to_vec()).unwrap(),
+            other => panic!("expected bulk, got {:?}", other),
+        };
+        let Frame::Array(keys) = &reply[1] else {
+            panic!("SCAN keys are not an array");
+        };
+        (text(&reply[0]), keys.iter().map(text).collect())
+    }
+
+    #[test]
+    fn scan_returns_stable_keys_once() {
+        let db = Db::new(4);
+        for i in 0..100 {
+            run(&db, &["SET", &format!("key:{}", i), "x"]);
+        }
+
+        let mut seen = Vec::new();
+        let mut cursor = "0".to_string();
+        let mut calls = 0;
+        loop {
+            let (next, keys) = scan(&db, &cursor, &["COUNT", "7"]);
+            seen.extend(keys);
+            // Churn the key space between calls, growing and shrinking the
+            // shards' maps. Keys 0 to 49 are never touched.
+            calls += 1;
+            run(&db, &["SET", &format!("new:{}", calls), "x"]);
+            run(&db, &["DEL", &format!("key:{}", 50 + calls)]);
+            if next == "0" {
+                break;
+            }
+            cursor = next;
+        }
+
+        for i in 0..50 {
+            let key = format!("key:{}", i);
+            assert_eq!(seen.iter().filter(|k| **k == key).count(), 1, "{}", key);
+        }
+    }
+
+    #[test]
+    fn scan_match_filters_keys() {
+        let db = Db::new(2);
+        for key in ["a:1", "a:2", "b:1"] {
+            run(&db, &["SET", key, "x"]);
+        }
+        let mut seen = Vec::new();
+        let mut cursor = "0".to_string();
+        loop {
+            let (next, keys) = scan(&db, &cursor, &["MATCH", "a:*"]);
+            seen.extend(keys);
+            if next == "0" {
+                break;
+            }
+            cursor = next;
+        }
+        seen.sort();
+        assert_eq!(seen, ["a:1", "a:2"]);
+
+        check(
+            &db,
+            &["SCAN", "x"],
+            Frame::Error("ERR invalid cursor".to_string()),
+        );
+        check(
+            &db,
+            &["SCAN", "0", "COUNT", "0"],
+            Frame::Error("ERR syntax error".to_string()),
+        );
+    }
+
+    #[test]
+    fn info_sections() {
+        let db = Db::new(2);
+        run(&db, &["SET", "a", "1", "EX", "100"]);
+        run(&db, &["SET", "b", "1"]);
+        db.stats().record_command("set");
+        let Frame::Bulk(info) = run(&db, &["INFO"]) else {
+            panic!("INFO did not return a bulk string");
+        };
+        let info = String::from_utf8(info.to_vec()).unwrap();
+        assert!(info.contains("# Server\r\nuptime_in_seconds:"), "{}", info);
+        assert!(info.contains("total_commands_processed:1\r\n"), "{}", info);
+        assert!(info.contains("db0:keys=2,expires=1\r\n"), "{}", info);
+        assert!(!info.contains("cmdstat_"), "{}", info);
+
+        let Frame::Bulk(info) = run(&db, &["INFO", "commandstats"]) else {
+            panic!("INFO did not return a bulk string");
+        };
+        assert_eq!(&info[..], b"# Commandstats\r\ncmdstat_set:calls=1\r\n");
+    }
+
+    #[test]
+    fn wrong_type_and_arity() {
+        let db = Db::new(4);
+        run(&db, &["SET", "k", "v"]);
+        let wrong = Frame::Error(WrongType.to_string());
+        check(&db, &["HGET", "k", "f"], wrong.clone());
+        check(&db, &["LPUSH", "k", "x"], wrong.clone());
+        check(&db, &["SADD", "k", "x"], wrong.clone());
+        run(&db, &["LPUSH", "l", "x"]);
+        check(&db, &["GET", "l"], wrong);
+
+        check(
+            &db,
+            &["HSET", "h", "f"],
+            Frame::Error("ERR wrong number of arguments for 'hset' command".to_string()),
+        );
+        check(
+            &db,
+            &["NOPE"],
+            Frame::Error("ERR unknown command 'nope'".to_string()),
+        );
+    }
+}
@@ -0,0 +1,146 @@
+use bytes::{Buf, BufMut, BytesMut};
+use mini_redis::frame::{self, Frame};
+use std::io::{self, Cursor};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::net::TcpStream;
+
+/// Reads and writes frames on a client socket.
+///
+/// This is `mini_redis::Connection` with two differences. Replies are encoded
+/// into a buffer before being written, so nested arrays (an EXEC reply
+/// holding LRANGE results, say) can be sent, which mini-redis cannot encode.
+/// And replies can be queued and flushed later, so the replies to a pipeline
+/// of commands go out in one write.
+#[derive(Debug)]
+pub struct Connection {
+    stream: BufWriter<TcpStream>,
+    buffer: BytesMut,
+    out: BytesMut,
+}
+
+impl Connection {
+    pub fn new(socket: TcpStream) -> Connection {
+        Connection {
+            stream: BufWriter::new(socket),
+            buffer: BytesMut::with_capacity(4 * 1024),
+            out: BytesMut::with_capacity(4 * 1024),
+        }
+    }
+
+    /// Read a single frame, or `None` when the peer closed the connection
+    /// between frames.
+    ///
+    /// Cancelling this future loses no data, as bytes already read stay in
+    /// the buffer for the next call.
+    pub async fn read_frame(&mut self) -> mini_redis::Result<Option<Frame>> {
+        loop {
+            if let Some(frame) = self.parse_frame()? {
+                return Ok(Some(frame));
+            }
+
+            if 0 == self.stream.read_buf(&mut self.buffer).await? {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                } else {
+                    return Err("connection reset by peer".into());
+                }
+            }
+        }
+    }
+
+    /// Parse a frame the client already sent without reading the socket, or
+    /// `None` when no complete frame is buffered.
+    pub fn buffered_frame(&mut self) -> mini_redis::Result<Option<Frame>> {
+        self.parse_frame()
+    }
+
+    fn parse_frame(&mut self) -> mini_redis::Result<Option<Frame>> {
+        let mut buf = Cursor::new(&self.buffer[..]);
+
+        match Frame::check(&mut buf) {
+            Ok(_) => {
+                let len = buf.position() as usize;
+                buf.set_position(0);
+                let frame = Frame::parse(&mut buf)?;
+                self.buffer.advance(len);
+                Ok(Some(frame))
+            }
+            Err(frame::Error::Incomplete) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Write a frame, along with any queued before it, and flush.
+    pub async fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        self.queue_frame(frame);
+        self.flush().await
+    }
+
+    /// Add a frame to the replies sent by the next [`Connection::flush`].
+    pub fn queue_frame(&mut self, frame: &Frame) {
+        encode(frame, &mut self.out);
+    }
+
+    /// The number of bytes of queued replies.
+    pub fn queued_len(&self) -> usize {
+        self.out.len()
+    }
+
+    /// Write the queued replies to the socket.
+    pub async fn flush(&mut self) -> io::Result<()> {
+        if !self.out.is_empty() {
+            self.stream.write_all(&self.out).await?;
+            self.out.clear();
+        }
+        self.stream.flush().await
+    }
+
+    /// Write bytes that are already RESP encoded, such as the replication
+    /// stream. Queued replies go out first.
+    pub async fn write_raw(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.stream.write_all(&self.out).await?;
+        self.out.clear();
+        self.stream.write_all(bytes).await?;
+        self.stream.flush().await
+    }
+}
+
+/// The number of bytes `frame` takes on the wire.
+pub fn encoded_len(frame: &Frame) -> usize {
+    let mut out = BytesMut::new();
+    encode(frame, &mut out);
+    out.len()
+}
+
+pub fn encode(frame: &Frame, out: &mut BytesMut) {
+    match frame {
+        Frame::Simple(val) => {
+            out.put_u8(b'+');
+            out.put_slice(val.as_bytes());
+            out.put_slice(b"\r\n");
+        }
+        Frame::Error(val) => {
+            out.put_u8(b'-');
+            out.put_slice(val.as_bytes());
+            out.put_slice(b"\r\n");
+        }
+        Frame::Integer(val) => {
+            out.put_u8(b':');
+            out.put_slice(format!("{}\r\n", val).as_bytes());
+        }
+        Frame::Null => out.put_slice(b"$-1\r\n"),
+        Frame::Bulk(val) => {
+            out.put_u8(b'$');
+            out.put_slice(format!("{}\r\n", val.len()).as_bytes());
+            out.put_slice(val);
+            out.put_slice(b"\r\n");
+        }
+        Frame::Array(items) => {
+            out.put_u8(b'*');
+            out.put_slice(format!("{}\r\n", items.len()).as_bytes());
+            for item in items {
+                encode(item, out);
+            }
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use std::fmt::Write;
+use tokio_practice::Locked;
+
+/// The text of an INFO reply. `section` picks a single section. Without it
+/// every section but `commandstats` is included, like Redis does.
+///
+/// Reporting the keyspace needs every shard, so `db` must hold them all.
+pub fn render(db: &mut Locked<'_>, section: Option<&str>) -> String {
+    let section = section.map(str::to_ascii_lowercase);
+    let wanted = |name: &str| match section.as_deref() {
+        None | Some("default") => name != "commandstats",
+        Some("all") | Some("everything") => true,
+        Some(section) => section == name,
+    };
+
+    let stats = db.db().stats();
+    let memory = db.db().memory();
+    let mut out = String::new();
+
+    if wanted("server") {
+        let uptime = stats.uptime().as_secs();
+        header(&mut out, "Server");
+        field(&mut out, "uptime_in_seconds", uptime);
+        field(&mut out, "uptime_in_days", uptime / (24 * 60 * 60));
+    }
+    if wanted("clients") {
+        header(&mut out, "Clients");
+        field(&mut out, "connected_clients", stats.connected_clients());
+    }
+    if wanted("memory") {
+        header(&mut out, "Memory");
+        field(&mut out, "used_memory", memory.used());
+        field(&mut out, "maxmemory", memory.limit());
+        field(&mut out, "maxmemory_policy", memory.policy());
+    }
+    if wanted("stats") {
+        let commands: u64 = stats.commands().values().sum();
+        header(&mut out, "Stats");
+        field(
+            &mut out,
+            "total_connections_received",
+            stats.total_connections(),
+        );
+        field(&mut out, "total_commands_processed", commands);
+        field(&mut out, "evicted_keys", memory.evicted());
+    }
+    if wanted("commandstats") {
+        header(&mut out, "Commandstats");
+        for (name, calls) in stats.commands() {
+            field(
+                &mut out,
+                &format!("cmdstat_{}", name),
+                format!("calls={}", calls),
+            );
+        }
+    }
+    if wanted("keyspace") {
+        header(&mut out, "Keyspace");
+        let keys = db.len();
+        if keys > 0 {
+            let value = format!("keys={},expires={}", keys, db.expires());
+            field(&mut out, "db0", value);
+        }
+    }
+    out
+}
+
+fn header(out: &mut String, name: &str) {
+    if !out.is_empty() {
+        out.push_str("\r\n");
+    }
+    let _ = write!(out, "# {}\r\n", name);
+}
+
+fn field(out: &mut String, name: &str, value: impl std::fmt::Display) {
+    let _ = write!(out, "{}:{}\r\n", name, value);
+}
@@ -0,0 +1,426 @@
+mod cmd;
+mod connection;
+mod info;
+mod replication;
+mod shutdown;
+mod transaction;
+
+use clap::{Arg, Command as App};
+use cmd::Command;
+use connection::Connection;
+use mini_redis::Frame;
+use replication::Replication;
+use shutdown::Shutdown;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc, Semaphore};
+use tokio::time::{self, Instant};
+use tokio_practice::memory::{self, Policy};
+use tokio_practice::{BlockingPop, Db, End};
+use transaction::Transaction;
+
+const NUM_SHARDS: usize = 16;
+
+/// Connections beyond this wait in the accept loop until one closes.
+const MAX_CONNECTIONS: usize = 250;
+
+/// A client that sends nothing for this long is disconnected. Clients
+/// blocked in BLPOP/BRPOP are waiting on the server, so they are exempt.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Queued replies are flushed once they reach this size, even when more
+/// pipelined commands are waiting, so a long pipeline cannot pile up an
+/// unbounded reply buffer.
+const MAX_QUEUED_REPLIES: usize = 64 * 1024;
+
+#[tokio::main]
+async fn main() -> mini_redis::Result<()> {
+    let matches = App::new("server")
+        .about("A mini-redis server")
+        .arg(
+            Arg::new("port")
+                .short('p')
+                .long("port")
+                .default_value("6379")
+                .value_parser(clap::value_parser!(u16))
+                .help("Port to listen on"),
+        )
+        .arg(
+            Arg::new("maxmemory")
+                .long("maxmemory")
+                .default_value("0")
+                .value_parser(memory::parse_bytes)
+                .help("Memory limit for data, such as 100mb. 0 means no limit"),
+        )
+        .arg(
+            Arg::new("maxmemory-policy")
+                .long("maxmemory-policy")
+                .default_value("noeviction")
+                .value_parser(Policy::NAMES)
+                .help("Which keys to evict once the memory limit is reached"),
+        )
+        .get_matches();
+    let port = *matches.get_one::<u16>("port").unwrap();
+    let maxmemory = *matches.get_one::<usize>("maxmemory").unwrap();
+    let policy = matches
+        .get_one::<String>("maxmemory-policy")
+        .unwrap()
+        .parse()?;
+
+    let db = Db::new(NUM_SHARDS);
+    db.memory().configure(maxmemory, policy);
+
+    // Bind the listener to the address
+    let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+    println!("Listening on {:?}", &listener);
+
+    run(listener, db, tokio::signal::ctrl_c()).await;
+    Ok(())
+}
+
+/// Accept connections until `shutdown` completes, then wait for the open
+/// connections to finish the command they are running and close.
+async fn run(listener: TcpListener, db: Db, shutdown: impl Future) {
+    // Dropping `notify_shutdown` tells every connection to stop. Each
+    // connection holds a clone of `shutdown_complete_tx`, so `recv` on the
+    // other end returns `None` once they have all gone.
+    let (notify_shutdown, _) = broadcast::channel(1);
+    let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel::<()>(1);
+
+    let server = Server {
+        listener,
+        replication: Replication::new(db.clone()),
+        db,
+        limit_connections: Arc::new(Semaphore::new(MAX_CONNECTIONS)),
+        notify_shutdown,
+        shutdown_complete_tx,
+    };
+
+    tokio::select! {
+        res = server.accept_loop() => {
+            if let Err(err) = res {
+                eprintln!("failed to accept: {}", err);
+            }
+        }
+        _ = shutdown => println!("shutting down"),
+    }
+
+    let Server {
+        notify_shutdown,
+        shutdown_complete_tx,
+        ..
+    } = server;
+    drop(notify_shutdown);
+    drop(shutdown_complete_tx);
+
+    let _ = shutdown_complete_rx.recv().await;
+}
+
+struct Server {
+    listener: TcpListener,
+    db: Db,
+    replication: Replication,
+    limit_connections: Arc<Semaphore>,
+    notify_shutdown: broadcast::Sender<()>,
+    shutdown_complete_tx: mpsc::Sender<()>,
+}
+
+impl Server {
+    async fn accept_loop(&self) -> mini_redis::Result<()> {
+        loop {
+            // Wait for a free slot before accepting, so excess clients queue
+            // in the listen backlog instead of being served without limit.
+            let permit = self.limit_connections.clone().acquire_owned().await?;
+            let socket = self.accept().await?;
+
+            let db = self.db.clone();
+            let replication = self.replication.clone();
+            let shutdown = Shutdown::new(self.notify_shutdown.subscribe());
+            let shutdown_complete = self.shutdown_complete_tx.clone();
+
+            // A new task is spawned for each inbound socket. The socket is
+            // moved to the new task and processed there.
+            tokio::spawn(async move {
+                let stats = db.clone();
+                stats.stats().client_connected();
+                if let Err(err) = process(socket, db, replication, shutdown).await {
+                    eprintln!("connection error: {}", err);
+                }
+                stats.stats().client_disconnected();
+                drop(permit);
+                drop(shutdown_complete);
+            });
+        }
+    }
+
+    /// Accept a socket, retrying with backoff on errors such as running out
+    /// of file descriptors. Gives up after the wait reaches a minute.
+    async fn accept(&self) -> mini_redis::Result<TcpStream> {
+        let mut backoff = 1;
+
+        loop {
+            match self.listener.accept().await {
+                Ok((socket, _)) => return Ok(socket),
+                Err(err) => {
+                    if backoff > 64 {
+                        return Err(err.into());
+                    }
+                    eprintln!("accept failed, retrying in {}s: {}", backoff, err);
+                }
+            }
+
+            time::sleep(Duration::from_secs(backoff)).await;
+            backoff *= 2;
+        }
+    }
+}
+
+async fn process(
+    socket: TcpStream,
+    db: Db,
+    replication: Replication,
+    mut shutdown: Shutdown,
+) -> mini_redis::Result<()> {
+    // Connection handles parsing frames from the socket
+    let mut connection = Connection::new(socket);
+    let mut transaction = Transaction::new(db.clone());
+
+    // Frames that arrived while the client was blocked in BLPOP/BRPOP. They
+    // are answered in order once the blocking command returns.
+    let mut pending = VecDeque::new();
+
+    while !shutdown.is_shutdown() {
+        let frame = match pending.pop_front() {
+            Some(frame) => frame,
+            None => match next_frame(&mut connection, &mut shutdown).await? {
+                Some(frame) => frame,
+                None => return Ok(()),
+            },
+        };
+
+        // Commands the server does not understand are answered with an
+        // error instead of taking the task down.
+        let command = Command::from_frame(frame);
+        if let Ok(cmd) = &command {
+            db.stats().record_command(cmd.name());
+        }
+
+        let response = match command {
+            Ok(Command::Multi) => transaction.multi(),
+            Ok(Command::Exec) => transaction.exec(),
+            Ok(Command::Discard) => transaction.discard(),
+            Ok(Command::Watch { keys }) => transaction.watch(keys),
+            Ok(cmd) if cmd.is_write() && replication.is_replica() => {
+                let error =
+                    Frame::Error("READONLY You can't write against a read only replica.".into());
+                if transaction.is_queuing() {
+                    transaction.abort_with(error)
+                } else {
+                    error
+                }
+            }
+            Ok(cmd) if transaction.is_queuing() => transaction.queue(cmd),
+            Err(error) if transaction.is_queuing() => transaction.abort_with(error),
+            Ok(Command::Unwatch) => transaction.unwatch(),
+            Ok(Command::ReplicaOf { primary }) => replication.replicate_from(primary),
+            // The connection now belongs to a replica and only carries the
+            // replication stream from here on.
+            Ok(Command::PSync { replid, offset }) => {
+                return replication
+                    .serve_replica(&mut connection, &mut shutdown, replid, offset)
+                    .await;
+            }
+            Ok(Command::BPop { keys, end, timeout }) => {
+                let popped = blocking_pop(
+                    &mut connection,
+                    &mut pending,
+                    &mut shutdown,
+                    &db,
+                    keys,
+                    end,
+                    timeout,
+                );
+                match popped.await {
+                    Some(response) => response,
+                    None => return Ok(()),
+                }
+            }
+            Ok(cmd) => cmd.apply(&db),
+            Err(error) => error,
+        };
+
+        // The reply is sent once no pipelined commands are left to run.
+        connection.queue_frame(&response);
+        if connection.queued_len() >= MAX_QUEUED_REPLIES {
+            connection.flush().await?;
+        }
+    }
+
+    connection.flush().await?;
+    Ok(())
+}
+
+/// Read the next request. `None` means the connection should close.
+///
+/// Commands a client pipelined are already buffered and come back without
+/// touching the socket. The queued replies are only flushed once none are
+/// left and we are about to wait, so a whole pipeline is answered with a
+/// single write.
+async fn next_frame(
+    connection: &mut Connection,
+    shutdown: &mut Shutdown,
+) -> mini_redis::Result<Option<Frame>> {
+    let read = match connection.buffered_frame() {
+        Ok(Some(frame)) => return Ok(Some(frame)),
+        Ok(None) => {
+            connection.flush().await?;
+            tokio::select! {
+                read = time::timeout(IDLE_TIMEOUT, connection.read_frame()) => read,
+                _ = shutdown.recv() => return Ok(None),
+            }
+        }
+        Err(err) => Ok(Err(err)),
+    };
+
+    match read {
+        Ok(Ok(frame)) => Ok(frame),
+        // The bytes on the socket are not RESP. Tell the client why before
+        // hanging up, there is no way to resync.
+        Ok(Err(err)) => {
+            let reply = Frame::Error(format!("ERR Protocol error: {}", err));
+            let _ = connection.write_frame(&reply).await;
+            Err(err)
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// Run BLPOP/BRPOP, waiting up to `timeout` for an element when all lists
+/// are empty.
+///
+/// The socket is still read while waiting so a client that disconnects is
+/// noticed and dequeued. `None` means the client has gone away or the
+/// server is shutting down.
+async fn blocking_pop(
+    connection: &mut Connection,
+    pending: &mut VecDeque<Frame>,
+    shutdown: &mut Shutdown,
+    db: &Db,
+    keys: Vec<String>,
+    end: End,
+    timeout: Option<Duration>,
+) -> Option<Frame> {
+    let mut blocked = match db.pop_or_block(keys, end) {
+        Ok(BlockingPop::Ready(key, value)) => return Some(cmd::key_value(key, value)),
+        Ok(BlockingPop::Blocked(blocked)) => blocked,
+        Err(e) => return Some(Frame::Error(e.to_string())),
+    };
+    // Replies to commands pipelined before this one must not wait with it.
+    connection.flush().await.ok()?;
+    // A deadline too far off to represent may as well be no deadline.
+    let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+
+    loop {
+        tokio::select! {
+            popped = blocked.recv() => {
+                return Some(match popped {
+                    Some((key, value)) => cmd::key_value(key, value),
+                    None => Frame::Null,
+                });
+            }
+            _ = sleep_until(deadline) => return Some(Frame::Null),
+            _ = shutdown.recv() => return None,
+            frame = connection.read_frame() => match frame {
+                Ok(Some(frame)) => pending.push_back(frame),
+                _ => return None,
+            },
+        }
+    }
+}
+
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::oneshot;
+
+    #[tokio::test]
+    async fn shutdown_drains_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(run(listener, Db::new(NUM_SHARDS), stop_rx));
+
+        let mut client = mini_redis::client::connect(addr).await.unwrap();
+        client.set("k", "v".into()).await.unwrap();
+        assert_eq!(client.get("k").await.unwrap().unwrap(), "v");
+
+        stop_tx.send(()).unwrap();
+        time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("server did not drain")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn pipelined_commands_are_answered_in_order() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(run(
+            listener,
+            Db::new(NUM_SHARDS),
+            std::future::pending::<()>(),
+        ));
+
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        socket
+            .write_all(
+                b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n\
+                  *2\r\n$3\r\nGET\r\n$1\r\na\r\n\
+                  *3\r\n$5\r\nRPUSH\r\n$1\r\nl\r\n$1\r\nx\r\n\
+                  *3\r\n$5\r\nBLPOP\r\n$1\r\nl\r\n$1\r\n0\r\n\
+                  *2\r\n$4\r\nNOPE\r\n$1\r\nx\r\n",
+            )
+            .await
+            .unwrap();
+
+        let expected: &[u8] = b"+OK\r\n$1\r\n1\r\n:1\r\n*2\r\n$1\r\nl\r\n$1\r\nx\r\n\
+                                -ERR unknown command 'nope'\r\n";
+        let mut reply = vec![0; expected.len()];
+        time::timeout(Duration::from_secs(5), socket.read_exact(&mut reply))
+            .await
+            .expect("replies did not arrive")
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&reply),
+            String::from_utf8_lossy(expected)
+        );
+    }
+
+    #[tokio::test]
+    async fn malformed_frames_get_a_protocol_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(run(
+            listener,
+            Db::new(NUM_SHARDS),
+            std::future::pending::<()>(),
+        ));
+
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        socket.write_all(b"$abc\r\n").await.unwrap();
+        let mut reply = String::new();
+        socket.read_to_string(&mut reply).await.unwrap();
+        assert!(reply.starts_with("-ERR Protocol error"), "{}", reply);
+    }
+}
@@ -0,0 +1,273 @@
+use crate::cmd::Command;
+use crate::connection::{self, Connection};
+use crate::shutdown::Shutdown;
+use bytes::{Buf, Bytes, BytesMut};
+use mini_redis::Frame;
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio_practice::{Db, Value};
+
+fn command_frame(args: Vec<Bytes>) -> Frame {
+    Frame::Array(args.into_iter().map(Frame::Bulk).collect())
+}
+
+/// The replication role of the server, shared by all connections.
+#[derive(Clone)]
+pub struct Replication {
+    db: Db,
+    /// Set while this server is a replica, holding the task that syncs from
+    /// the primary.
+    primary: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+impl Replication {
+    pub fn new(db: Db) -> Replication {
+        Replication {
+            db,
+            primary: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.primary.lock().unwrap().is_some()
+    }
+
+    /// REPLICAOF: start replicating from `primary`, or with `None` stop and
+    /// keep the current data as a primary.
+    pub fn replicate_from(&self, primary: Option<String>) -> Frame {
+        let mut current = self.primary.lock().unwrap();
+        if let Some(task) = current.take() {
+            task.abort();
+        }
+
+        if let Some(addr) = primary {
+            let db = self.db.clone();
+            *current = Some(tokio::spawn(replicate(addr, db)));
+        }
+
+        Frame::Simple("OK".to_string())
+    }
+
+    /// Answer a replica's PSYNC and stream writes to it until it goes away
+    /// or the server shuts down.
+    pub async fn serve_replica(
+        &self,
+        connection: &mut Connection,
+        shutdown: &mut Shutdown,
+        replid: String,
+        offset: Option<u64>,
+    ) -> mini_redis::Result<()> {
+        let backlog = self.db.backlog();
+        let mut end_rx = backlog.watch_end();
+
+        let mut offset = match offset.filter(|&o| backlog.can_continue(&replid, o)) {
+            Some(offset) => {
+                connection
+                    .write_frame(&Frame::Simple("CONTINUE".to_string()))
+                    .await?;
+                offset
+            }
+            None => {
+                let (snapshot, replid, offset) = snapshot(&self.db);
+                let header = Frame::Simple(format!("FULLRESYNC {} {}", replid, offset));
+                connection.write_frame(&header).await?;
+                connection.write_frame(&Frame::Bulk(snapshot)).await?;
+                offset
+            }
+        };
+
+        loop {
+            let chunk = backlog
+                .read_from(offset)
+                .ok_or("replica fell behind the replication backlog")?;
+            if !chunk.is_empty() {
+                connection.write_raw(&chunk).await?;
+                offset += chunk.len() as u64;
+                continue;
+            }
+
+            // Replicas send nothing, so reading only serves to notice one
+            // that has disconnected.
+            tokio::select! {
+                changed = end_rx.changed() => changed?,
+                frame = connection.read_frame() => {
+                    if !matches!(frame, Ok(Some(_))) {
+                        return Ok(());
+                    }
+                }
+                _ = shutdown.recv() => return Ok(()),
+            }
+        }
+    }
+}
+
+/// Encode the whole key space as commands that recreate it, along with the
+/// stream position the snapshot corresponds to.
+fn snapshot(db: &Db) -> (Bytes, String, u64) {
+    let mut locked = db.lock_all();
+    let mut out = BytesMut::new();
+
+    let now = Instant::now();
+    locked.for_each(|key, value, expires_at| {
+        let name = Bytes::copy_from_slice(key.as_bytes());
+        let key = name.clone();
+        let args = match value {
+            Value::String(b) => vec!["SET".into(), key, b.clone()],
+            Value::Hash(h) => {
+                let mut args = vec!["HSET".into(), key];
+                for (field, value) in h {
+                    args.extend([field.clone(), value.clone()]);
+                }
+                args
+            }
+            Value::List(l) => [Bytes::from("RPUSH"), key]
+                .into_iter()
+                .chain(l.iter().cloned())
+                .collect(),
+            Value::Set(s) => [Bytes::from("SADD"), key]
+                .into_iter()
+                .chain(s.iter().cloned())
+                .collect(),
+        };
+        connection::encode(&command_frame(args), &mut out);
+
+        if let Some(at) = expires_at {
+            let ttl = at.saturating_duration_since(now).as_millis().max(1);
+            let args = vec!["PEXPIRE".into(), name, ttl.to_string().into()];
+            connection::encode(&command_frame(args), &mut out);
+        }
+    });
+
+    // Writes append to the backlog while holding their shard locks, and all
+    // of them are held here, so nothing can slip in between.
+    let (replid, offset) = db.backlog().position();
+    (out.freeze(), replid, offset)
+}
+
+/// Keep this server in sync with `addr`, reconnecting when the link drops.
+async fn replicate(addr: String, db: Db) {
+    // Where we are in the primary's stream, used to resume after a reconnect.
+    let mut position: Option<(String, u64)> = None;
+
+    loop {
+        if let Err(err) = sync_from(&addr, &db, &mut position).await {
+            eprintln!("replication from {} failed: {}", addr, err);
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+async fn sync_from(
+    addr: &str,
+    db: &Db,
+    position: &mut Option<(String, u64)>,
+) -> mini_redis::Result<()> {
+    let mut connection = Connection::new(TcpStream::connect(addr).await?);
+
+    let (replid, offset) = match position {
+        Some((replid, offset)) => (replid.clone(), offset.to_string()),
+        None => ("?".to_string(), "-1".to_string()),
+    };
+    let psync = vec!["PSYNC".into(), replid.into(), offset.into()];
+    connection.write_frame(&command_frame(psync)).await?;
+
+    match connection.read_frame().await? {
+        Some(Frame::Simple(reply)) if reply == "CONTINUE" => {}
+        Some(Frame::Simple(reply)) if reply.starts_with("FULLRESYNC ") => {
+            let mut parts = reply.split_whitespace().skip(1);
+            let replid = parts.next().ok_or("bad FULLRESYNC reply")?.to_string();
+            let offset = parts.next().ok_or("bad FULLRESYNC reply")?.parse()?;
+
+            let snapshot = match connection.read_frame().await? {
+                Some(Frame::Bulk(snapshot)) => snapshot,
+                other => return Err(format!("expected snapshot, got {:?}", other).into()),
+            };
+            load_snapshot(db, snapshot)?;
+            *position = Some((replid, offset));
+        }
+        other => return Err(format!("unexpected PSYNC reply {:?}", other).into()),
+    }
+
+    loop {
+        let frame = connection
+            .read_frame()
+            .await?
+            .ok_or("primary closed the connection")?;
+        let len = connection::encoded_len(&frame);
+        apply(db, frame);
+
+        if let Some((_, offset)) = position {
+            *offset += len as u64;
+        }
+    }
+}
+
+fn load_snapshot(db: &Db, snapshot: Bytes) -> mini_redis::Result<()> {
+    db.lock_all().clear();
+    // Nothing replicating from us has seen this data set.
+    db.backlog().reset();
+
+    let mut buf = Cursor::new(&snapshot[..]);
+    while buf.has_remaining() {
+        apply(db, Frame::parse(&mut buf)?);
+    }
+    Ok(())
+}
+
+/// Run a command from the primary. Replicas do not evict on their own, the
+/// primary sends a DEL for each key it evicts.
+fn apply(db: &Db, frame: Frame) {
+    match Command::from_frame(frame) {
+        Ok(cmd) => {
+            let mut locked = cmd.lock(db);
+            cmd.execute(&mut locked);
+        }
+        Err(err) => eprintln!("bad command in replication stream: {:?}", err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn replica_syncs_then_resumes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(crate::run(
+            listener,
+            Db::new(4),
+            std::future::pending::<()>(),
+        ));
+
+        let mut primary = mini_redis::client::connect(&addr).await.unwrap();
+        primary.set("a", "1".into()).await.unwrap();
+
+        let replica = Db::new(4);
+        let mut position = None;
+        let sync = sync_from(&addr, &replica, &mut position);
+        let _ = timeout(Duration::from_millis(200), sync).await;
+        assert_eq!(
+            replica.lock(&["a"]).get_string("a").unwrap(),
+            Some("1".into())
+        );
+
+        // Writes made while the link is down are picked up from the backlog
+        // without another full sync, which would have reset our own stream.
+        let own_stream = replica.backlog().position().0;
+        primary.set("b", "2".into()).await.unwrap();
+        let sync = sync_from(&addr, &replica, &mut position);
+        let _ = timeout(Duration::from_millis(200), sync).await;
+
+        assert_eq!(
+            replica.lock(&["b"]).get_string("b").unwrap(),
+            Some("2".into())
+        );
+        assert_eq!(replica.backlog().position().0, own_stream);
+    }
+}
@@ -0,0 +1,36 @@
+use tokio::sync::broadcast;
+
+/// Listens for the server shutdown signal.
+///
+/// Every connection task holds one. The signal is sent once, by dropping or
+/// sending on the `broadcast::Sender` in `main`, and `recv` keeps returning
+/// immediately after that.
+#[derive(Debug)]
+pub struct Shutdown {
+    shutdown: bool,
+    notify: broadcast::Receiver<()>,
+}
+
+impl Shutdown {
+    pub fn new(notify: broadcast::Receiver<()>) -> Shutdown {
+        Shutdown {
+            shutdown: false,
+            notify,
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown
+    }
+
+    /// Wait for the shutdown signal.
+    pub async fn recv(&mut self) {
+        if self.shutdown {
+            return;
+        }
+
+        // Lagging or the sender being dropped both mean shut down.
+        let _ = self.notify.recv().await;
+        self.shutdown = true;
+    }
+}
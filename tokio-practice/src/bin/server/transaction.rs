@@ -0,0 +1,215 @@
+use crate::cmd::Command;
+use mini_redis::Frame;
+use tokio_practice::Db;
+
+/// The MULTI/EXEC/WATCH state of one connection.
+///
+/// Between MULTI and EXEC commands are queued instead of run. EXEC locks
+/// every shard the queued and watched keys live in, checks that no watched
+/// key changed since WATCH, and runs the whole queue while holding the locks
+/// so no other client sees a half applied transaction.
+pub struct Transaction {
+    db: Db,
+    queued: Option<Vec<Command>>,
+    /// Set when a command failed to parse while queuing. EXEC then refuses
+    /// to run anything, like Redis does.
+    aborted: bool,
+    watched: Vec<(String, u64)>,
+}
+
+impl Transaction {
+    pub fn new(db: Db) -> Transaction {
+        Transaction {
+            db,
+            queued: None,
+            aborted: false,
+            watched: Vec::new(),
+        }
+    }
+
+    /// Whether a MULTI is open and commands should be queued.
+    pub fn is_queuing(&self) -> bool {
+        self.queued.is_some()
+    }
+
+    pub fn multi(&mut self) -> Frame {
+        if self.is_queuing() {
+            return Frame::Error("ERR MULTI calls can not be nested".to_string());
+        }
+        self.queued = Some(Vec::new());
+        Frame::Simple("OK".to_string())
+    }
+
+    pub fn queue(&mut self, cmd: Command) -> Frame {
+        self.queued
+            .as_mut()
+            .expect("queue called outside of MULTI")
+            .push(cmd);
+        Frame::Simple("QUEUED".to_string())
+    }
+
+    /// Record that a command could not be queued and pass its error on.
+    pub fn abort_with(&mut self, error: Frame) -> Frame {
+        self.aborted = true;
+        error
+    }
+
+    pub fn discard(&mut self) -> Frame {
+        if self.queued.take().is_none() {
+            return Frame::Error("ERR DISCARD without MULTI".to_string());
+        }
+        self.aborted = false;
+        self.unwatch()
+    }
+
+    pub fn watch(&mut self, keys: Vec<String>) -> Frame {
+        if self.is_queuing() {
+            return Frame::Error("ERR WATCH inside MULTI is not allowed".to_string());
+        }
+        let versions = self.db.watch(&keys);
+        self.watched.extend(keys.into_iter().zip(versions));
+        Frame::Simple("OK".to_string())
+    }
+
+    pub fn unwatch(&mut self) -> Frame {
+        let keys: Vec<String> = self.watched.drain(..).map(|(key, _)| key).collect();
+        if !keys.is_empty() {
+            self.db.unwatch(&keys);
+        }
+        Frame::Simple("OK".to_string())
+    }
+
+    pub fn exec(&mut self) -> Frame {
+        let Some(queued) = self.queued.take() else {
+            return Frame::Error("ERR EXEC without MULTI".to_string());
+        };
+        if std::mem::take(&mut self.aborted) {
+            self.unwatch();
+            return Frame::Error(
+                "EXECABORT Transaction discarded because of previous errors.".to_string(),
+            );
+        }
+
+        if queued.iter().any(Command::denies_oom) {
+            if let Err(oom) = self.db.make_room() {
+                self.unwatch();
+                return Frame::Error(oom.to_string());
+            }
+        }
+
+        let mut keys: Vec<String> = self.watched.iter().map(|(key, _)| key.clone()).collect();
+        for cmd in &queued {
+            keys.extend(cmd.keys().into_iter().map(String::from));
+        }
+
+        let mut locked = if queued.iter().any(Command::locks_all) {
+            self.db.lock_all()
+        } else {
+            self.db.lock(&keys)
+        };
+        let changed = self
+            .watched
+            .iter()
+            .any(|(key, version)| locked.version(key) != *version);
+
+        let reply = if changed {
+            Frame::Null
+        } else {
+            Frame::Array(
+                queued
+                    .into_iter()
+                    .map(|cmd| cmd.execute(&mut locked))
+                    .collect(),
+            )
+        };
+        drop(locked);
+
+        self.unwatch();
+        reply
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        self.unwatch();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn cmd(args: &[&str]) -> Command {
+        let frame = Frame::Array(
+            args.iter()
+                .map(|a| Frame::Bulk(Bytes::copy_from_slice(a.as_bytes())))
+                .collect(),
+        );
+        Command::from_frame(frame).unwrap()
+    }
+
+    fn reply(frame: Frame) -> String {
+        format!("{:?}", frame)
+    }
+
+    #[test]
+    fn exec_runs_queued_commands() {
+        let db = Db::new(4);
+        let mut tx = Transaction::new(db.clone());
+        tx.multi();
+        assert_eq!(
+            reply(tx.queue(cmd(&["RPUSH", "l", "a", "b"]))),
+            r#"Simple("QUEUED")"#
+        );
+        tx.queue(cmd(&["LRANGE", "l", "0", "-1"]));
+        assert_eq!(
+            reply(tx.exec()),
+            r#"Array([Integer(2), Array([Bulk(b"a"), Bulk(b"b")])])"#
+        );
+        assert!(!tx.is_queuing());
+    }
+
+    #[test]
+    fn exec_aborts_when_watched_key_changes() {
+        let db = Db::new(4);
+        let mut tx = Transaction::new(db.clone());
+        tx.watch(vec!["k".to_string()]);
+        cmd(&["SET", "k", "other"]).apply(&db);
+
+        tx.multi();
+        tx.queue(cmd(&["SET", "k", "mine"]));
+        assert_eq!(reply(tx.exec()), "Null");
+        assert_eq!(reply(cmd(&["GET", "k"]).apply(&db)), r#"Bulk(b"other")"#);
+
+        // The watch ended with EXEC, so the next transaction goes through.
+        tx.multi();
+        tx.queue(cmd(&["SET", "k", "mine"]));
+        assert_eq!(reply(tx.exec()), r#"Array([Simple("OK")])"#);
+    }
+
+    #[test]
+    fn deleting_a_watched_key_is_a_change() {
+        let db = Db::new(4);
+        cmd(&["SADD", "s", "a"]).apply(&db);
+        let mut tx = Transaction::new(db.clone());
+        tx.watch(vec!["s".to_string()]);
+        cmd(&["SREM", "s", "a"]).apply(&db);
+
+        tx.multi();
+        tx.queue(cmd(&["SADD", "s", "b"]));
+        assert_eq!(reply(tx.exec()), "Null");
+    }
+
+    #[test]
+    fn queuing_errors_abort_exec() {
+        let mut tx = Transaction::new(Db::new(4));
+        tx.multi();
+        tx.abort_with(Frame::Error("ERR unknown command 'nope'".to_string()));
+        assert_eq!(
+            reply(tx.exec()),
+            r#"Error("EXECABORT Transaction discarded because of previous errors.")"#
+        );
+        assert_eq!(reply(tx.discard()), r#"Error("ERR DISCARD without MULTI")"#);
+    }
+}
@@ -0,0 +1,1060 @@
+use bytes::Bytes;
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, oneshot};
+
+use crate::backlog::Backlog;
+use crate::memory::{self, Memory, OutOfMemory, Policy};
+use crate::pubsub::PubSub;
+use crate::stats::Stats;
+
+/// SCAN cursors hold the shard index above this bit and the position to
+/// continue from within the shard below it.
+const CURSOR_SHARD_SHIFT: u32 = 48;
+
+/// How many keys of a shard are compared when picking one to evict.
+const EVICTION_SAMPLES: usize = 5;
+
+/// How far into a shard's map samples may start. A `HashMap` cannot be
+/// indexed, so reaching a random entry means walking to it; keeping the
+/// start near the front bounds that walk, and the front moves on as keys
+/// are evicted.
+const EVICTION_WINDOW: usize = 1024;
+
+/// The longest TTL the typed API sets. Longer ones are cut down to it, as
+/// an `Instant` that far off may not exist and replicas are sent the TTL
+/// in milliseconds.
+pub const MAX_TTL: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+/// How long it takes an unused key to lose half of its LFU hit count.
+const LFU_HALF_LIFE: Duration = Duration::from_secs(60);
+
+/// The hit count new keys start with, so they are not the first to go
+/// before they had a chance to be used.
+const LFU_INITIAL_HITS: u32 = 5;
+
+/// The value stored under a key. Each command only works on one kind of value
+/// and answers with `WRONGTYPE` when the key holds another.
+#[derive(Debug, Clone)]
+pub enum Value {
+    String(Bytes),
+    Hash(HashMap<Bytes, Bytes>),
+    List(VecDeque<Bytes>),
+    Set(HashSet<Bytes>),
+}
+
+impl Value {
+    fn is_empty_collection(&self) -> bool {
+        match self {
+            Value::String(_) => false,
+            Value::Hash(h) => h.is_empty(),
+            Value::List(l) => l.is_empty(),
+            Value::Set(s) => s.is_empty(),
+        }
+    }
+}
+
+/// Which end of a list a push or pop works on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum End {
+    Left,
+    Right,
+}
+
+impl End {
+    pub fn push(self, list: &mut VecDeque<Bytes>, value: Bytes) {
+        match self {
+            End::Left => list.push_front(value),
+            End::Right => list.push_back(value),
+        }
+    }
+
+    pub fn pop(self, list: &mut VecDeque<Bytes>) -> Option<Bytes> {
+        match self {
+            End::Left => list.pop_front(),
+            End::Right => list.pop_back(),
+        }
+    }
+
+    /// The commands that push and pop at this end, for replication.
+    pub fn push_command(self) -> &'static str {
+        match self {
+            End::Left => "LPUSH",
+            End::Right => "RPUSH",
+        }
+    }
+
+    pub fn pop_command(self) -> &'static str {
+        match self {
+            End::Left => "LPOP",
+            End::Right => "RPOP",
+        }
+    }
+}
+
+/// Returned when a command is used against a key holding a different type.
+#[derive(Debug, PartialEq, Eq)]
+pub struct WrongType;
+
+impl std::fmt::Display for WrongType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        "WRONGTYPE Operation against a key holding the wrong kind of value".fmt(f)
+    }
+}
+
+impl std::error::Error for WrongType {}
+
+#[derive(Debug)]
+struct Entry {
+    value: Value,
+    /// Changes on every write, so WATCH can tell whether the key was touched.
+    version: u64,
+    /// Estimated bytes used by the key and value.
+    size: usize,
+    expires_at: Option<Instant>,
+    last_access: Instant,
+    hits: u32,
+}
+
+impl Entry {
+    fn accessed(&mut self) {
+        self.last_access = Instant::now();
+        self.hits = self.hits.saturating_add(1);
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+
+    /// The hit count, halved for every [`LFU_HALF_LIFE`] since the last
+    /// access so keys that were popular long ago can still be evicted.
+    fn frequency(&self, now: Instant) -> u32 {
+        let idle = now.saturating_duration_since(self.last_access);
+        let halvings = idle.as_secs() / LFU_HALF_LIFE.as_secs();
+        self.hits.checked_shr(halvings as u32).unwrap_or(0)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Shard {
+    entries: HashMap<String, Entry>,
+    memory: Arc<Memory>,
+    /// Clients blocked in BLPOP/BRPOP, in the order they started waiting.
+    blocked: HashMap<String, VecDeque<Arc<Waiter>>>,
+    /// Keys under WATCH. A watched key that is deleted keeps its last
+    /// version here so deleting it still counts as a change.
+    watched: HashMap<String, WatchedKey>,
+    next_version: u64,
+}
+
+#[derive(Debug, Default)]
+struct WatchedKey {
+    watchers: usize,
+    removed_at: Option<u64>,
+}
+
+impl Shard {
+    fn bump(&mut self) -> u64 {
+        self.next_version += 1;
+        self.next_version
+    }
+
+    fn version(&self, key: &str) -> u64 {
+        match self.entries.get(key) {
+            Some(entry) => entry.version,
+            None => self
+                .watched
+                .get(key)
+                .and_then(|w| w.removed_at)
+                .unwrap_or(0),
+        }
+    }
+
+    fn touch(&mut self, key: &str) {
+        let version = self.bump();
+        if let Some(entry) = self.entries.get_mut(key) {
+            entry.version = version;
+            entry.accessed();
+            let size = memory::entry_size(key, &entry.value);
+            self.memory.resize(entry.size, size);
+            entry.size = size;
+        }
+    }
+
+    fn insert(&mut self, key: &str, value: Value) {
+        let version = self.bump();
+        let size = memory::entry_size(key, &value);
+        self.memory.resize(0, size);
+        let entry = Entry {
+            value,
+            version,
+            size,
+            expires_at: None,
+            last_access: Instant::now(),
+            hits: LFU_INITIAL_HITS,
+        };
+        if let Some(old) = self.entries.insert(key.to_string(), entry) {
+            self.memory.resize(old.size, 0);
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Value> {
+        let entry = self.entries.remove(key)?;
+        self.memory.resize(entry.size, 0);
+        let version = self.bump();
+        if let Some(watched) = self.watched.get_mut(key) {
+            watched.removed_at = Some(version);
+        }
+        Some(entry.value)
+    }
+
+    /// Remove `key` if its TTL has passed. Keys are only expired when they
+    /// are next used, so this returns whether it happened.
+    fn expire(&mut self, key: &str) -> bool {
+        let expired = self
+            .entries
+            .get(key)
+            .is_some_and(|entry| entry.is_expired(Instant::now()));
+        if expired {
+            self.remove(key);
+        }
+        expired
+    }
+
+    /// Pick the key `policy` would evict among a few sampled ones. Like
+    /// Redis this approximates LRU and LFU instead of keeping every key
+    /// ordered.
+    fn eviction_candidate(&self, policy: Policy) -> Option<String> {
+        let len = self.entries.len();
+        if len == 0 {
+            return None;
+        }
+        let offset = random() as usize % len.min(EVICTION_WINDOW);
+        let sample = self
+            .entries
+            .iter()
+            .skip(offset)
+            .chain(self.entries.iter().take(offset))
+            .filter(|(_, entry)| policy != Policy::VolatileTtl || entry.expires_at.is_some())
+            .take(EVICTION_SAMPLES);
+
+        let now = Instant::now();
+        let victim = match policy {
+            Policy::NoEviction => None,
+            Policy::AllKeysLru => sample.min_by_key(|(_, entry)| entry.last_access),
+            Policy::AllKeysLfu => {
+                sample.min_by_key(|(_, entry)| (entry.frequency(now), entry.last_access))
+            }
+            Policy::VolatileTtl => sample.min_by_key(|(_, entry)| entry.expires_at),
+        };
+        victim.map(|(key, _)| key.clone())
+    }
+}
+
+fn key_hash(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Where a key sits in the SCAN order of its shard. It only depends on the
+/// key, so keys come out in the same order however the shard's map grows or
+/// shrinks between calls. Never zero, as that is the cursor ending a scan.
+fn scan_position(key: &str) -> u64 {
+    (key_hash(key) >> (64 - CURSOR_SHARD_SHIFT)).max(1)
+}
+
+/// A cheap random number, good enough to spread eviction samples around.
+fn random() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    RandomState::new().hash_one(COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+/// A client blocked on one or more lists. It sits in the queue of every key
+/// it waits on and is served by whichever of them gets an element first.
+#[derive(Debug)]
+struct Waiter {
+    end: End,
+    tx: Mutex<Option<oneshot::Sender<(String, Bytes)>>>,
+}
+
+/// The outcome of [`Db::pop_or_block`].
+pub enum BlockingPop {
+    Ready(String, Bytes),
+    Blocked(Blocked),
+}
+
+/// A queued BLPOP/BRPOP. Dropping it, because the wait timed out or the
+/// client went away, takes it out of the wait queues again.
+pub struct Blocked {
+    db: Db,
+    keys: Vec<String>,
+    waiter: Arc<Waiter>,
+    rx: oneshot::Receiver<(String, Bytes)>,
+}
+
+impl Blocked {
+    /// Wait until one of the lists hands this client an element.
+    pub async fn recv(&mut self) -> Option<(String, Bytes)> {
+        (&mut self.rx).await.ok()
+    }
+}
+
+impl Drop for Blocked {
+    fn drop(&mut self) {
+        let mut locked = self.db.lock(&self.keys);
+        for key in &self.keys {
+            locked.unblock(key, &self.waiter);
+        }
+
+        // An element may have been handed over after the client stopped
+        // listening. Put it back where it came from so it is not lost.
+        if let Ok((key, value)) = self.rx.try_recv() {
+            let end = self.waiter.end;
+            locked.propagate(vec![
+                end.push_command().into(),
+                key.clone().into(),
+                value.clone(),
+            ]);
+            let _ = locked.update_list(&key, |list| end.push(list, value));
+        }
+    }
+}
+
+/// The key space, split into shards that are locked independently so
+/// connections touching different keys do not wait on each other.
+#[derive(Debug, Clone)]
+pub struct Db {
+    shards: Arc<Vec<Mutex<Shard>>>,
+    backlog: Arc<Backlog>,
+    memory: Arc<Memory>,
+    stats: Arc<Stats>,
+    pubsub: Arc<PubSub>,
+}
+
+impl Db {
+    pub fn new(num_shards: usize) -> Db {
+        let memory = Arc::new(Memory::default());
+        let mut shards = Vec::with_capacity(num_shards);
+        for _ in 0..num_shards {
+            shards.push(Mutex::new(Shard {
+                memory: memory.clone(),
+                ..Shard::default()
+            }));
+        }
+        Db {
+            shards: Arc::new(shards),
+            backlog: Arc::new(Backlog::new()),
+            memory,
+            stats: Arc::new(Stats::default()),
+            pubsub: Arc::new(PubSub::default()),
+        }
+    }
+
+    /// Counters reported by INFO.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Memory use and the `maxmemory` settings.
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    /// Evict keys until memory use is back under `maxmemory`.
+    ///
+    /// Called before a write that may grow memory, without holding any shard
+    /// locks as each eviction locks the shard it evicts from. Fails when the
+    /// policy is `noeviction` or no key may be evicted.
+    pub fn make_room(&self) -> Result<(), OutOfMemory> {
+        while self.memory.over_limit() {
+            let policy = self.memory.policy();
+            if policy == Policy::NoEviction || !self.evict_one(policy) {
+                return Err(OutOfMemory);
+            }
+        }
+        Ok(())
+    }
+
+    /// Evict one key from the first shard, starting at a random one, that
+    /// has a key `policy` allows evicting.
+    fn evict_one(&self, policy: Policy) -> bool {
+        let start = random() as usize;
+        for i in 0..self.shards.len() {
+            let index = (start + i) % self.shards.len();
+            let mut shard = self.shards[index].lock().unwrap();
+            if let Some(key) = shard.eviction_candidate(policy) {
+                shard.remove(&key);
+                self.backlog.append(vec!["DEL".into(), key.into()]);
+                self.memory.record_eviction();
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Where writes are recorded for replicas.
+    pub fn backlog(&self) -> &Backlog {
+        &self.backlog
+    }
+
+    fn shard_index(&self, key: &str) -> usize {
+        key_hash(key) as usize % self.shards.len()
+    }
+
+    /// The shard a SCAN cursor points into.
+    pub fn cursor_shard(&self, cursor: u64) -> usize {
+        (cursor >> CURSOR_SHARD_SHIFT) as usize
+    }
+
+    /// Lock the one shard a SCAN cursor points into, or none for a cursor
+    /// that points past the last shard.
+    pub fn lock_cursor(&self, cursor: u64) -> Locked<'_> {
+        let index = self.cursor_shard(cursor);
+        self.lock_where(|i| i == index)
+    }
+
+    /// Lock the shards holding `keys`.
+    ///
+    /// Shards are always locked in index order, so two callers locking
+    /// overlapping sets of keys cannot deadlock.
+    pub fn lock<S: AsRef<str>>(&self, keys: &[S]) -> Locked<'_> {
+        let mut wanted = vec![false; self.shards.len()];
+        for key in keys {
+            wanted[self.shard_index(key.as_ref())] = true;
+        }
+        self.lock_where(|i| wanted[i])
+    }
+
+    /// Lock every shard, for commands that look at the whole key space.
+    pub fn lock_all(&self) -> Locked<'_> {
+        self.lock_where(|_| true)
+    }
+
+    /// Pop from the first non-empty list among `keys`, or queue the caller
+    /// on all of them when they are all empty.
+    pub fn pop_or_block(&self, keys: Vec<String>, end: End) -> Result<BlockingPop, WrongType> {
+        let mut locked = self.lock(&keys);
+
+        for key in &keys {
+            if locked.list(key)?.is_some() {
+                if let Some(value) = locked.update_list(key, |list| end.pop(list))? {
+                    locked.propagate(vec![end.pop_command().into(), key.clone().into()]);
+                    return Ok(BlockingPop::Ready(key.clone(), value));
+                }
+            }
+        }
+
+        let (tx, rx) = oneshot::channel();
+        let waiter = Arc::new(Waiter {
+            end,
+            tx: Mutex::new(Some(tx)),
+        });
+        for key in &keys {
+            locked
+                .shard(key)
+                .blocked
+                .entry(key.clone())
+                .or_default()
+                .push_back(waiter.clone());
+        }
+        drop(locked);
+
+        Ok(BlockingPop::Blocked(Blocked {
+            db: self.clone(),
+            keys,
+            waiter,
+            rx,
+        }))
+    }
+
+    /// Start watching `keys`, returning the version of each.
+    pub fn watch(&self, keys: &[String]) -> Vec<u64> {
+        let mut locked = self.lock(keys);
+        keys.iter()
+            .map(|key| {
+                let shard = locked.shard(key);
+                shard.watched.entry(key.clone()).or_default().watchers += 1;
+                shard.version(key)
+            })
+            .collect()
+    }
+
+    /// Stop watching `keys`, each of which was passed to [`Db::watch`].
+    pub fn unwatch(&self, keys: &[String]) {
+        let mut locked = self.lock(keys);
+        for key in keys {
+            let shard = locked.shard(key);
+            if let Some(watched) = shard.watched.get_mut(key) {
+                watched.watchers -= 1;
+                if watched.watchers == 0 {
+                    shard.watched.remove(key);
+                }
+            }
+        }
+    }
+
+    fn lock_where(&self, wanted: impl Fn(usize) -> bool) -> Locked<'_> {
+        let guards = self
+            .shards
+            .iter()
+            .enumerate()
+            .map(|(i, shard)| wanted(i).then(|| shard.lock().unwrap()))
+            .collect();
+        Locked { db: self, guards }
+    }
+}
+
+/// The typed API for using the store in-process. Writes are recorded for
+/// replicas just like the server's commands are.
+impl Db {
+    /// The string at `key`, or `None` when it is missing or has expired.
+    pub fn get(&self, key: &str) -> Result<Option<Bytes>, WrongType> {
+        self.lock(&[key]).get_string(key)
+    }
+
+    /// Set `key` to a string, replacing whatever value and TTL it had.
+    pub fn set(&self, key: &str, value: impl Into<Bytes>) -> Result<(), OutOfMemory> {
+        self.set_string(key, value.into(), None)
+    }
+
+    /// Set `key` to a string that expires after `ttl`, at most [`MAX_TTL`].
+    pub fn set_ex(
+        &self,
+        key: &str,
+        value: impl Into<Bytes>,
+        ttl: Duration,
+    ) -> Result<(), OutOfMemory> {
+        self.set_string(key, value.into(), Some(ttl))
+    }
+
+    fn set_string(
+        &self,
+        key: &str,
+        value: Bytes,
+        ttl: Option<Duration>,
+    ) -> Result<(), OutOfMemory> {
+        let ttl = ttl.map(|ttl| ttl.min(MAX_TTL));
+        self.make_room()?;
+        let mut locked = self.lock(&[key]);
+        let mut args = vec!["SET".into(), key_bytes(key), value.clone()];
+        if let Some(ttl) = ttl {
+            args.extend(["PX".into(), ttl.as_millis().to_string().into()]);
+        }
+        locked.propagate(args);
+        locked.set(key, Value::String(value));
+        if let Some(ttl) = ttl {
+            locked.set_expiry(key, Some(Instant::now() + ttl));
+        }
+        Ok(())
+    }
+
+    /// Remove `key`, returning whether it existed.
+    pub fn del(&self, key: &str) -> bool {
+        let mut locked = self.lock(&[key]);
+        let removed = locked.remove(key);
+        if removed {
+            locked.propagate(vec!["DEL".into(), key_bytes(key)]);
+        }
+        removed
+    }
+
+    /// Make `key` expire after `ttl`, at most [`MAX_TTL`]. Returns false when
+    /// there is no such key.
+    pub fn expire(&self, key: &str, ttl: Duration) -> bool {
+        let ttl = ttl.min(MAX_TTL);
+        let mut locked = self.lock(&[key]);
+        let found = locked.set_expiry(key, Some(Instant::now() + ttl));
+        if found {
+            let ttl = ttl.as_millis().to_string();
+            locked.propagate(vec!["PEXPIRE".into(), key_bytes(key), ttl.into()]);
+        }
+        found
+    }
+
+    /// How long until `key` expires, or `None` when it is missing or has no
+    /// TTL.
+    pub fn ttl(&self, key: &str) -> Option<Duration> {
+        let expires_at = self.lock(&[key]).expiry(key)?;
+        Some(expires_at.saturating_duration_since(Instant::now()))
+    }
+
+    /// Send `message` to the subscribers of `channel`, returning how many
+    /// received it.
+    pub fn publish(&self, channel: &str, message: impl Into<Bytes>) -> usize {
+        self.pubsub.publish(channel, message.into())
+    }
+
+    /// Receive the messages published to `channel` from now on.
+    pub fn subscribe(&self, channel: &str) -> broadcast::Receiver<Bytes> {
+        self.pubsub.subscribe(channel)
+    }
+}
+
+fn key_bytes(key: &str) -> Bytes {
+    Bytes::copy_from_slice(key.as_bytes())
+}
+
+/// A set of locked shards. Only keys whose shard was locked may be used.
+pub struct Locked<'a> {
+    db: &'a Db,
+    guards: Vec<Option<MutexGuard<'a, Shard>>>,
+}
+
+impl<'a> Locked<'a> {
+    fn shard(&mut self, key: &str) -> &mut Shard {
+        let index = self.db.shard_index(key);
+        self.guards[index]
+            .as_deref_mut()
+            .expect("key used without locking its shard")
+    }
+
+    /// The database the shards belong to.
+    pub fn db(&self) -> &'a Db {
+        self.db
+    }
+
+    /// The shard holding `key`, after removing the key if it has expired.
+    fn live_shard(&mut self, key: &str) -> &mut Shard {
+        let db = self.db;
+        let shard = self.shard(key);
+        if shard.expire(key) {
+            db.backlog
+                .append(vec!["DEL".into(), key.to_string().into()]);
+        }
+        shard
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<&Value> {
+        let entry = self.live_shard(key).entries.get_mut(key)?;
+        entry.accessed();
+        Some(&entry.value)
+    }
+
+    /// Remove `key`, returning whether it existed.
+    pub fn remove(&mut self, key: &str) -> bool {
+        self.live_shard(key).remove(key).is_some()
+    }
+
+    /// When `key` expires, if it exists and has a TTL.
+    pub fn expiry(&mut self, key: &str) -> Option<Instant> {
+        self.live_shard(key).entries.get(key)?.expires_at
+    }
+
+    /// Set or clear the time `key` expires at. Returns false when there is no
+    /// such key.
+    pub fn set_expiry(&mut self, key: &str, expires_at: Option<Instant>) -> bool {
+        let shard = self.live_shard(key);
+        match shard.entries.get_mut(key) {
+            Some(entry) => {
+                entry.expires_at = expires_at;
+                shard.touch(key);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn set(&mut self, key: &str, value: Value) {
+        self.shard(key).insert(key, value);
+    }
+
+    /// Record a write for replicas. Called with the shards it touches still
+    /// locked, so replicas see writes in the order they were applied.
+    pub fn propagate(&mut self, args: Vec<Bytes>) {
+        self.db.backlog.append(args);
+    }
+
+    /// Visit every key in the locked shards that has not expired, along with
+    /// when it expires.
+    pub fn for_each(&mut self, mut f: impl FnMut(&str, &Value, Option<Instant>)) {
+        let now = Instant::now();
+        for shard in self.guards.iter().flatten() {
+            for (key, entry) in &shard.entries {
+                if !entry.is_expired(now) {
+                    f(key, &entry.value, entry.expires_at);
+                }
+            }
+        }
+    }
+
+    /// The number of keys in the locked shards, including expired keys that
+    /// have not been removed yet.
+    pub fn len(&self) -> usize {
+        self.guards.iter().flatten().map(|s| s.entries.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of keys in the locked shards that have a TTL.
+    pub fn expires(&self) -> usize {
+        self.guards
+            .iter()
+            .flatten()
+            .flat_map(|s| s.entries.values())
+            .filter(|entry| entry.expires_at.is_some())
+            .count()
+    }
+
+    /// Return about `count` keys starting at `cursor`, along with the cursor
+    /// to continue from, which is 0 once every shard has been walked.
+    ///
+    /// Keys are visited shard by shard, in [`scan_position`] order within a
+    /// shard, so a key that exists for the whole scan is returned exactly
+    /// once. The cursor's shard must be locked, see [`Db::lock_cursor`].
+    pub fn scan(&mut self, cursor: u64, count: usize) -> (u64, Vec<String>) {
+        let index = self.db.cursor_shard(cursor);
+        let from = cursor & ((1 << CURSOR_SHARD_SHIFT) - 1);
+        let Some(Some(shard)) = self.guards.get(index) else {
+            return (0, Vec::new());
+        };
+
+        let now = Instant::now();
+        let mut found: Vec<(u64, &String)> = shard
+            .entries
+            .iter()
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .map(|(key, _)| (scan_position(key), key))
+            .filter(|(position, _)| *position >= from)
+            .collect();
+        found.sort_unstable();
+
+        // Keys sharing a position go out together, the cursor cannot point
+        // between them.
+        let mut end = count.min(found.len());
+        while end > 0 && end < found.len() && found[end].0 == found[end - 1].0 {
+            end += 1;
+        }
+
+        let next = if end < found.len() {
+            ((index as u64) << CURSOR_SHARD_SHIFT) | found[end].0
+        } else if index + 1 < self.guards.len() {
+            ((index + 1) as u64) << CURSOR_SHARD_SHIFT
+        } else {
+            0
+        };
+        let keys = found[..end].iter().map(|(_, key)| (*key).clone()).collect();
+        (next, keys)
+    }
+
+    /// Remove every key in the locked shards.
+    pub fn clear(&mut self) {
+        for shard in self.guards.iter_mut().flatten() {
+            let keys: Vec<String> = shard.entries.keys().cloned().collect();
+            for key in keys {
+                shard.remove(&key);
+            }
+        }
+    }
+
+    /// The current version of `key`, compared against the one taken by WATCH.
+    pub fn version(&mut self, key: &str) -> u64 {
+        self.live_shard(key).version(key)
+    }
+
+    pub fn get_string(&mut self, key: &str) -> Result<Option<Bytes>, WrongType> {
+        match self.get(key) {
+            Some(Value::String(b)) => Ok(Some(b.clone())),
+            Some(_) => Err(WrongType),
+            None => Ok(None),
+        }
+    }
+
+    pub fn hash(&mut self, key: &str) -> Result<Option<&HashMap<Bytes, Bytes>>, WrongType> {
+        match self.get(key) {
+            Some(Value::Hash(h)) => Ok(Some(h)),
+            Some(_) => Err(WrongType),
+            None => Ok(None),
+        }
+    }
+
+    pub fn list(&mut self, key: &str) -> Result<Option<&VecDeque<Bytes>>, WrongType> {
+        match self.get(key) {
+            Some(Value::List(l)) => Ok(Some(l)),
+            Some(_) => Err(WrongType),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_members(&mut self, key: &str) -> Result<Option<&HashSet<Bytes>>, WrongType> {
+        match self.get(key) {
+            Some(Value::Set(s)) => Ok(Some(s)),
+            Some(_) => Err(WrongType),
+            None => Ok(None),
+        }
+    }
+
+    pub fn update_hash<T>(
+        &mut self,
+        key: &str,
+        f: impl FnOnce(&mut HashMap<Bytes, Bytes>) -> T,
+    ) -> Result<T, WrongType> {
+        self.update(
+            key,
+            || Value::Hash(HashMap::new()),
+            |v| match v {
+                Value::Hash(h) => Some(h),
+                _ => None,
+            },
+            f,
+        )
+    }
+
+    /// Change the list at `key`. Elements left in it afterwards go to clients
+    /// blocked on the key.
+    pub fn update_list<T>(
+        &mut self,
+        key: &str,
+        f: impl FnOnce(&mut VecDeque<Bytes>) -> T,
+    ) -> Result<T, WrongType> {
+        let out = self.update(
+            key,
+            || Value::List(VecDeque::new()),
+            |v| match v {
+                Value::List(l) => Some(l),
+                _ => None,
+            },
+            f,
+        )?;
+        self.serve_blocked(key);
+        Ok(out)
+    }
+
+    pub fn update_set<T>(
+        &mut self,
+        key: &str,
+        f: impl FnOnce(&mut HashSet<Bytes>) -> T,
+    ) -> Result<T, WrongType> {
+        self.update(
+            key,
+            || Value::Set(HashSet::new()),
+            |v| match v {
+                Value::Set(s) => Some(s),
+                _ => None,
+            },
+            f,
+        )
+    }
+
+    /// Run `f` on the collection stored at `key`, creating an empty one when
+    /// the key is missing. Like Redis, a collection left empty is removed so
+    /// an empty hash, list or set never exists.
+    fn update<C, T>(
+        &mut self,
+        key: &str,
+        create: fn() -> Value,
+        project: fn(&mut Value) -> Option<&mut C>,
+        f: impl FnOnce(&mut C) -> T,
+    ) -> Result<T, WrongType> {
+        let shard = self.live_shard(key);
+        if !shard.entries.contains_key(key) {
+            shard.insert(key, create());
+        }
+        let value = &mut shard.entries.get_mut(key).unwrap().value;
+        let out = match project(value) {
+            Some(collection) => f(collection),
+            None => return Err(WrongType),
+        };
+        if value.is_empty_collection() {
+            shard.remove(key);
+        } else {
+            shard.touch(key);
+        }
+        Ok(out)
+    }
+
+    /// Hand elements of the list at `key` to blocked clients, oldest first.
+    fn serve_blocked(&mut self, key: &str) {
+        let backlog = &self.db.backlog;
+        let shard = self.shard(key);
+        let Some(mut queue) = shard.blocked.remove(key) else {
+            return;
+        };
+
+        while let Some(Entry {
+            value: Value::List(list),
+            ..
+        }) = shard.entries.get_mut(key)
+        {
+            let Some(waiter) = queue.pop_front() else {
+                break;
+            };
+            // Already served through another key it was waiting on.
+            let Some(tx) = waiter.tx.lock().unwrap().take() else {
+                continue;
+            };
+            let value = waiter.end.pop(list).unwrap();
+            // The push that fed this list is already in the stream, so the
+            // pop goes right after it.
+            backlog.append(vec![
+                waiter.end.pop_command().into(),
+                Bytes::copy_from_slice(key.as_bytes()),
+            ]);
+            let emptied = match tx.send((key.to_string(), value)) {
+                Ok(()) => list.is_empty(),
+                Err((_, value)) => {
+                    waiter.end.push(list, value);
+                    false
+                }
+            };
+            if emptied {
+                shard.remove(key);
+            } else {
+                shard.touch(key);
+            }
+        }
+
+        if !queue.is_empty() {
+            shard.blocked.insert(key.to_string(), queue);
+        }
+    }
+
+    fn unblock(&mut self, key: &str, waiter: &Arc<Waiter>) {
+        let blocked = &mut self.shard(key).blocked;
+        if let Some(queue) = blocked.get_mut(key) {
+            queue.retain(|w| !Arc::ptr_eq(w, waiter));
+            if queue.is_empty() {
+                blocked.remove(key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push(db: &Db, key: &str, value: &'static str) {
+        db.lock(&[key])
+            .update_list(key, |list| list.push_back(Bytes::from(value)))
+            .unwrap();
+    }
+
+    fn blocked(db: &Db, keys: &[&str]) -> Blocked {
+        let keys = keys.iter().map(|k| k.to_string()).collect();
+        match db.pop_or_block(keys, End::Left).unwrap() {
+            BlockingPop::Blocked(blocked) => blocked,
+            BlockingPop::Ready(..) => panic!("expected to block"),
+        }
+    }
+
+    #[tokio::test]
+    async fn blocked_clients_are_served_in_order() {
+        let db = Db::new(4);
+        let mut first = blocked(&db, &["a", "b"]);
+        let mut second = blocked(&db, &["b"]);
+
+        push(&db, "b", "1");
+        push(&db, "b", "2");
+
+        assert_eq!(first.recv().await, Some(("b".into(), Bytes::from("1"))));
+        assert_eq!(second.recv().await, Some(("b".into(), Bytes::from("2"))));
+
+        // `first` was served through "b" and must not take from "a" too.
+        push(&db, "a", "3");
+        assert_eq!(db.lock(&["a"]).list("a").unwrap().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn dropped_clients_are_dequeued() {
+        let db = Db::new(4);
+        let gone = blocked(&db, &["k"]);
+        let mut waiting = blocked(&db, &["k"]);
+        drop(gone);
+
+        push(&db, "k", "v");
+        assert_eq!(waiting.recv().await, Some(("k".into(), Bytes::from("v"))));
+        assert!(db.lock(&["k"]).shard("k").blocked.is_empty());
+    }
+
+    #[tokio::test]
+    async fn undelivered_elements_are_returned() {
+        let db = Db::new(4);
+        let served = blocked(&db, &["k"]);
+        push(&db, "k", "v");
+
+        // Served, but the client went away before reading the element.
+        drop(served);
+        let value = db.lock(&["k"]).list("k").unwrap().cloned();
+        assert_eq!(value, Some(VecDeque::from([Bytes::from("v")])));
+    }
+
+    #[test]
+    fn typed_api() {
+        let db = Db::new(4);
+        assert_eq!(db.get("k").unwrap(), None);
+        db.set("k", "v").unwrap();
+        assert_eq!(db.get("k").unwrap(), Some(Bytes::from("v")));
+        assert_eq!(db.ttl("k"), None);
+
+        assert!(db.expire("k", Duration::from_secs(10)));
+        assert!(db.ttl("k").unwrap() > Duration::from_secs(9));
+        // SET replaces the TTL along with the value.
+        db.set("k", "w").unwrap();
+        assert_eq!(db.ttl("k"), None);
+
+        db.set_ex("gone", "v", Duration::ZERO).unwrap();
+        assert_eq!(db.get("gone").unwrap(), None);
+        assert!(!db.expire("gone", Duration::from_secs(1)));
+
+        db.set_ex("far", "v", Duration::MAX).unwrap();
+        assert!(db.ttl("far").unwrap() > MAX_TTL - Duration::from_secs(1));
+        assert!(db.expire("far", Duration::MAX));
+        assert!(db.ttl("far").unwrap() <= MAX_TTL);
+
+        assert!(db.del("k"));
+        assert!(!db.del("k"));
+
+        push(&db, "list", "x");
+        assert_eq!(db.get("list"), Err(WrongType));
+    }
+
+    #[test]
+    fn typed_writes_are_replicated() {
+        let db = Db::new(4);
+        let (_, start) = db.backlog().position();
+        db.set_ex("k", "v", Duration::from_secs(5)).unwrap();
+        db.del("k");
+        db.del("missing");
+        assert_eq!(
+            db.backlog().read_from(start).unwrap(),
+            b"*5\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n$2\r\nPX\r\n$4\r\n5000\r\n\
+              *2\r\n$3\r\nDEL\r\n$1\r\nk\r\n"
+        );
+    }
+
+    #[test]
+    fn typed_set_respects_maxmemory() {
+        let db = Db::new(1);
+        db.set("a", "1").unwrap();
+        db.memory().configure(1, Policy::NoEviction);
+        assert_eq!(db.set("b", "1"), Err(OutOfMemory));
+
+        db.memory().configure(1, Policy::AllKeysLru);
+        db.set("b", "1").unwrap();
+        assert_eq!(db.get("a").unwrap(), None);
+        assert_eq!(db.memory().evicted(), 1);
+    }
+
+    #[tokio::test]
+    async fn publish_reaches_subscribers() {
+        let db = Db::new(4);
+        assert_eq!(db.publish("news", "nobody"), 0);
+
+        let mut first = db.subscribe("news");
+        let mut second = db.subscribe("news");
+        assert_eq!(db.publish("news", "hello"), 2);
+        assert_eq!(first.recv().await.unwrap(), Bytes::from("hello"));
+        assert_eq!(second.recv().await.unwrap(), Bytes::from("hello"));
+
+        drop(first);
+        drop(second);
+        assert_eq!(db.publish("news", "again"), 0);
+    }
+}
@@ -0,0 +1,126 @@
+/// Whether `text` matches the glob `pattern`, with the syntax of Redis KEYS:
+/// `*`, `?`, `[abc]`, `[^abc]`, `[a-z]` and `\` to escape the next byte.
+pub fn matches(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // The last `*` seen and where in `text` it currently ends. When the rest
+    // of the pattern fails, the star swallows one more byte and we retry.
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    star = Some((p, t));
+                    p += 1;
+                    continue;
+                }
+                b'?' => {
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+                b'[' => {
+                    let (matched, next) = class(pattern, p, text[t]);
+                    if matched {
+                        p = next;
+                        t += 1;
+                        continue;
+                    }
+                }
+                b'\\' if p + 1 < pattern.len() => {
+                    if pattern[p + 1] == text[t] {
+                        p += 2;
+                        t += 1;
+                        continue;
+                    }
+                }
+                c => {
+                    if c == text[t] {
+                        p += 1;
+                        t += 1;
+                        continue;
+                    }
+                }
+            }
+        }
+
+        match star {
+            Some((star_p, star_t)) => {
+                p = star_p + 1;
+                t = star_t + 1;
+                star = Some((star_p, star_t + 1));
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Match `c` against the class opening at `pattern[start]`. Returns whether
+/// it matched and where the pattern continues after the closing `]`. An
+/// unclosed class runs to the end of the pattern.
+fn class(pattern: &[u8], start: usize, c: u8) -> (bool, usize) {
+    let mut i = start + 1;
+    let negate = pattern.get(i) == Some(&b'^');
+    if negate {
+        i += 1;
+    }
+
+    let mut matched = false;
+    while i < pattern.len() && pattern[i] != b']' {
+        if pattern[i] == b'\\' && i + 1 < pattern.len() {
+            matched |= pattern[i + 1] == c;
+            i += 2;
+        } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']' {
+            let (lo, hi) = if pattern[i] <= pattern[i + 2] {
+                (pattern[i], pattern[i + 2])
+            } else {
+                (pattern[i + 2], pattern[i])
+            };
+            matched |= (lo..=hi).contains(&c);
+            i += 3;
+        } else {
+            matched |= pattern[i] == c;
+            i += 1;
+        }
+    }
+
+    (matched != negate, (i + 1).min(pattern.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::matches;
+
+    fn m(pattern: &str, text: &str) -> bool {
+        matches(pattern.as_bytes(), text.as_bytes())
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(m("*", ""));
+        assert!(m("*", "anything"));
+        assert!(m("user:*", "user:42"));
+        assert!(!m("user:*", "session:42"));
+        assert!(m("*:*:name", "user:42:name"));
+        assert!(m("h?llo", "hello"));
+        assert!(!m("h?llo", "hllo"));
+        assert!(m("a*b*c", "aXXbYYbc"));
+        assert!(!m("a*b*c", "aXXbYY"));
+    }
+
+    #[test]
+    fn classes_and_escapes() {
+        assert!(m("h[ae]llo", "hallo"));
+        assert!(!m("h[ae]llo", "hillo"));
+        assert!(m("h[^e]llo", "hallo"));
+        assert!(!m("h[^e]llo", "hello"));
+        assert!(m("key[0-9]", "key7"));
+        assert!(m("key[9-0]", "key7"));
+        assert!(!m("key[0-9]", "keyx"));
+        assert!(m(r"a\*b", "a*b"));
+        assert!(!m(r"a\*b", "aXb"));
+        assert!(m(r"[\]]", "]"));
+    }
+}
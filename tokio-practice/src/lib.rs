@@ -0,0 +1,29 @@
+//! The storage engine behind the mini-redis `server` binary.
+//!
+//! [`Db`] is a sharded key space holding strings, hashes, lists and sets,
+//! with TTLs, a `maxmemory` limit with eviction, and publish/subscribe. The
+//! server puts the RESP protocol in front of it, but other crates can use it
+//! in-process through its typed API:
+//!
+//! ```
+//! use std::time::Duration;
+//! use tokio_practice::Db;
+//!
+//! let db = Db::new(16);
+//! db.set("greeting", "hello").unwrap();
+//! assert_eq!(db.get("greeting").unwrap(), Some("hello".into()));
+//!
+//! assert!(db.expire("greeting", Duration::from_secs(60)));
+//! assert!(db.ttl("greeting").unwrap() <= Duration::from_secs(60));
+//! assert!(db.del("greeting"));
+//! ```
+
+pub mod backlog;
+pub mod db;
+pub mod glob;
+pub mod memory;
+pub mod pubsub;
+pub mod stats;
+
+pub use db::{Blocked, BlockingPop, Db, End, Locked, Value, WrongType};
+pub use memory::{OutOfMemory, Policy};
@@ -0,0 +1,174 @@
+use crate::db::Value;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Rough per-key cost of the map entry, version and bookkeeping fields.
+const ENTRY_OVERHEAD: usize = 64;
+
+/// Rough cost of each element of a hash, list or set beyond its bytes.
+const ELEMENT_OVERHEAD: usize = 16;
+
+/// Which keys to evict once `maxmemory` is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Policy {
+    /// Evict nothing and fail writes instead.
+    #[default]
+    NoEviction,
+    /// Evict the least recently used key.
+    AllKeysLru,
+    /// Evict the least frequently used key.
+    AllKeysLfu,
+    /// Evict the key with a TTL that expires soonest.
+    VolatileTtl,
+}
+
+impl Policy {
+    pub const NAMES: [&'static str; 4] =
+        ["noeviction", "allkeys-lru", "allkeys-lfu", "volatile-ttl"];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Policy::NoEviction => "noeviction",
+            Policy::AllKeysLru => "allkeys-lru",
+            Policy::AllKeysLfu => "allkeys-lfu",
+            Policy::VolatileTtl => "volatile-ttl",
+        }
+    }
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Policy, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "noeviction" => Ok(Policy::NoEviction),
+            "allkeys-lru" => Ok(Policy::AllKeysLru),
+            "allkeys-lfu" => Ok(Policy::AllKeysLfu),
+            "volatile-ttl" => Ok(Policy::VolatileTtl),
+            _ => Err(format!("unknown maxmemory policy '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.name().fmt(f)
+    }
+}
+
+/// Returned for writes that would need memory the policy does not allow
+/// freeing.
+#[derive(Debug, PartialEq, Eq)]
+pub struct OutOfMemory;
+
+impl fmt::Display for OutOfMemory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        "OOM command not allowed when used memory > 'maxmemory'.".fmt(f)
+    }
+}
+
+impl std::error::Error for OutOfMemory {}
+
+/// Memory accounting shared by every shard.
+///
+/// Sizes are estimates made from key and value lengths plus a fixed overhead,
+/// not what the allocator actually hands out, so `maxmemory` is approximate.
+#[derive(Debug, Default)]
+pub struct Memory {
+    used: AtomicUsize,
+    /// Zero means no limit.
+    limit: AtomicUsize,
+    policy: Mutex<Policy>,
+    evicted: AtomicU64,
+}
+
+impl Memory {
+    pub fn configure(&self, limit: usize, policy: Policy) {
+        self.limit.store(limit, Ordering::Relaxed);
+        *self.policy.lock().unwrap() = policy;
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit.load(Ordering::Relaxed)
+    }
+
+    pub fn policy(&self) -> Policy {
+        *self.policy.lock().unwrap()
+    }
+
+    /// How many keys have been evicted to stay under the limit.
+    pub fn evicted(&self) -> u64 {
+        self.evicted.load(Ordering::Relaxed)
+    }
+
+    pub fn over_limit(&self) -> bool {
+        let limit = self.limit();
+        limit != 0 && self.used() > limit
+    }
+
+    pub fn resize(&self, old: usize, new: usize) {
+        if new > old {
+            self.used.fetch_add(new - old, Ordering::Relaxed);
+        } else {
+            self.used.fetch_sub(old - new, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_eviction(&self) {
+        self.evicted.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Collections are sized from this many of their elements, so sizing a key
+/// after each write does not cost time proportional to its length.
+const SIZE_SAMPLES: usize = 5;
+
+/// The estimated size of `key` holding `value`.
+pub fn entry_size(key: &str, value: &Value) -> usize {
+    let value = match value {
+        Value::String(b) => b.len(),
+        Value::Hash(h) => elements(h.len(), h.iter().map(|(f, v)| f.len() + v.len())),
+        Value::List(l) => elements(l.len(), l.iter().map(|e| e.len())),
+        Value::Set(s) => elements(s.len(), s.iter().map(|m| m.len())),
+    };
+    ENTRY_OVERHEAD + key.len() + value
+}
+
+/// The size of `len` elements, extrapolated from the first few sizes, like
+/// the MEMORY USAGE command of Redis does.
+fn elements(len: usize, sizes: impl Iterator<Item = usize>) -> usize {
+    let (sampled, bytes) = sizes
+        .take(SIZE_SAMPLES)
+        .fold((0, 0), |(n, total), size| (n + 1, total + size));
+    if sampled == 0 {
+        return 0;
+    }
+    len * ELEMENT_OVERHEAD + bytes * len / sampled
+}
+
+/// Parse a byte count such as `1048576`, `512kb`, `100mb` or `1gb`.
+pub fn parse_bytes(s: &str) -> Result<usize, String> {
+    let lower = s.to_ascii_lowercase();
+    let (digits, unit) = match lower.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => lower.split_at(i),
+        None => (lower.as_str(), ""),
+    };
+    let unit = match unit {
+        "" | "b" => 1,
+        "k" | "kb" => 1024,
+        "m" | "mb" => 1024 * 1024,
+        "g" | "gb" => 1024 * 1024 * 1024,
+        _ => return Err(format!("invalid memory size '{}'", s)),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .ok_or_else(|| format!("invalid memory size '{}'", s))
+}
@@ -0,0 +1,48 @@
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+
+/// Messages a subscriber may fall behind by before it starts missing them.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Publish/subscribe channels. Messages are not stored, a message reaches
+/// whoever is subscribed to its channel when it is published.
+#[derive(Debug, Default)]
+pub struct PubSub {
+    channels: Mutex<HashMap<String, broadcast::Sender<Bytes>>>,
+}
+
+impl PubSub {
+    /// Receive the messages published to `channel` from now on. A receiver
+    /// that falls more than [`CHANNEL_CAPACITY`] messages behind gets
+    /// `RecvError::Lagged` and skips ahead.
+    pub fn subscribe(&self, channel: &str) -> broadcast::Receiver<Bytes> {
+        let mut channels = self.channels.lock().unwrap();
+        match channels.get(channel) {
+            Some(tx) => tx.subscribe(),
+            None => {
+                let (tx, rx) = broadcast::channel(CHANNEL_CAPACITY);
+                channels.insert(channel.to_string(), tx);
+                rx
+            }
+        }
+    }
+
+    /// Send `message` to the subscribers of `channel`, returning how many
+    /// there were.
+    pub fn publish(&self, channel: &str, message: Bytes) -> usize {
+        let mut channels = self.channels.lock().unwrap();
+        let Some(tx) = channels.get(channel) else {
+            return 0;
+        };
+        match tx.send(message) {
+            Ok(receivers) => receivers,
+            // Everyone unsubscribed, so the channel can go.
+            Err(_) => {
+                channels.remove(channel);
+                0
+            }
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Server wide counters, reported by INFO.
+#[derive(Debug)]
+pub struct Stats {
+    started: Instant,
+    connected_clients: AtomicUsize,
+    total_connections: AtomicU64,
+    /// Calls per command name, sorted so they are listed in a stable order.
+    commands: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl Default for Stats {
+    fn default() -> Stats {
+        Stats {
+            started: Instant::now(),
+            connected_clients: AtomicUsize::new(0),
+            total_connections: AtomicU64::new(0),
+            commands: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl Stats {
+    pub fn client_connected(&self) {
+        self.connected_clients.fetch_add(1, Ordering::Relaxed);
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn client_disconnected(&self) {
+        self.connected_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn record_command(&self, name: &'static str) {
+        *self.commands.lock().unwrap().entry(name).or_default() += 1;
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn connected_clients(&self) -> usize {
+        self.connected_clients.load(Ordering::Relaxed)
+    }
+
+    pub fn total_connections(&self) -> u64 {
+        self.total_connections.load(Ordering::Relaxed)
+    }
+
+    /// Calls per command name.
+    pub fn commands(&self) -> BTreeMap<&'static str, u64> {
+        self.commands.lock().unwrap().clone()
+    }
+}
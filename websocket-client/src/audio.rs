@@ -0,0 +1,675 @@
+//! Live PCM audio for streaming in [`websocket_protocol::audio`] frames.
+//!
+//! A [`Source`] yields chunks of interleaved little-endian samples as they
+//! are captured: from a microphone, or paced in real time from a WAV file or
+//! a sine generator, which stand in for one when there is no hardware. A
+//! [`Framer`] cuts those chunks into frames of a fixed duration, and
+//! compresses them with the codec agreed on with the server.
+//!
+//! [`command`] and [`stream`] put the two together behind the command line
+//! the streaming clients share.
+
+use std::f64::consts::TAU;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use futures::sink::SinkExt;
+use futures::stream::StreamExt;
+use tokio::sync::mpsc;
+use tokio::time::{interval, sleep, MissedTickBehavior};
+use tokio_tungstenite::tungstenite::Message;
+use websocket_codec::Encoder;
+use websocket_protocol::audio::{
+    Codec, Format, Header, SampleFormat, MAX_CHANNELS, MAX_SAMPLE_RATE,
+};
+use websocket_protocol::Envelope;
+
+use crate::{tls, Client, Config};
+
+type AnyError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Chunks held between a source and whoever reads it. A microphone whose
+/// reader falls further behind than this loses audio rather than blocking
+/// its callback.
+const QUEUE: usize = 64;
+
+/// How loud the sine generator is, out of 1.
+const AMPLITUDE: f64 = 0.5;
+
+/// Audio as it becomes available.
+pub struct Source {
+    pub format: Format,
+    chunks: mpsc::Receiver<Vec<u8>>,
+    dropped: Arc<AtomicU64>,
+    /// Capture stops when the stream is dropped.
+    _stream: Option<cpal::Stream>,
+}
+
+impl Source {
+    /// The next chunk of samples, or `None` once the source has ended.
+    pub async fn next(&mut self) -> Option<Vec<u8>> {
+        self.chunks.recv().await
+    }
+
+    /// Chunks lost because they were not read in time.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Capture from an input device, or the default one. Samples stay in
+    /// the device's format, except unsigned ones, which become `i16`.
+    pub fn microphone(device: Option<&str>) -> Result<Source, AnyError> {
+        let host = cpal::default_host();
+        let device = match device {
+            None => host
+                .default_input_device()
+                .ok_or("no input device available")?,
+            Some(name) => host
+                .input_devices()?
+                .find(|device| device.name().map(|n| n == name).unwrap_or(false))
+                .ok_or_else(|| format!("no input device named {:?}", name))?,
+        };
+        let config = device.default_input_config()?;
+        let format = Format {
+            sample_format: match config.sample_format() {
+                cpal::SampleFormat::F32 => SampleFormat::F32,
+                cpal::SampleFormat::I16 | cpal::SampleFormat::U16 => SampleFormat::I16,
+            },
+            channels: config.channels(),
+            sample_rate: config.sample_rate().0,
+        };
+
+        let (tx, chunks) = mpsc::channel(QUEUE);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let counter = dropped.clone();
+        // The callback runs on the audio thread, so it must never wait.
+        let push = move |chunk: Vec<u8>| {
+            if tx.try_send(chunk).is_err() {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        };
+        let err_fn = |err| eprintln!("an error occurred on stream: {}", err);
+        let sample_format = format.sample_format;
+        let stream = match config.sample_format() {
+            cpal::SampleFormat::F32 => device.build_input_stream(
+                &config.config(),
+                move |data: &[f32], _: &_| push(to_bytes(data, sample_format)),
+                err_fn,
+            )?,
+            cpal::SampleFormat::I16 => device.build_input_stream(
+                &config.config(),
+                move |data: &[i16], _: &_| push(to_bytes(data, sample_format)),
+                err_fn,
+            )?,
+            cpal::SampleFormat::U16 => device.build_input_stream(
+                &config.config(),
+                move |data: &[u16], _: &_| push(to_bytes(data, sample_format)),
+                err_fn,
+            )?,
+        };
+        stream.play()?;
+
+        Ok(Source {
+            format,
+            chunks,
+            dropped,
+            _stream: Some(stream),
+        })
+    }
+
+    /// Play a 16-bit integer or 32-bit float WAV file, a `chunk` at a time,
+    /// as fast as it would be heard.
+    pub fn wav_file(path: &Path, chunk: Duration) -> Result<Source, AnyError> {
+        let mut reader = hound::WavReader::open(path)?;
+        let spec = reader.spec();
+        let sample_format = match (spec.sample_format, spec.bits_per_sample) {
+            (hound::SampleFormat::Int, 16) => SampleFormat::I16,
+            (hound::SampleFormat::Float, 32) => SampleFormat::F32,
+            (format, bits) => {
+                let message = format!(
+                    "{}-bit {:?} WAV files can't be streamed; use 16-bit integer or 32-bit float",
+                    bits, format
+                );
+                return Err(message.into());
+            }
+        };
+        let format = Format {
+            sample_format,
+            channels: spec.channels,
+            sample_rate: spec.sample_rate,
+        };
+        let data: Vec<u8> = match sample_format {
+            SampleFormat::I16 => reader
+                .samples::<i16>()
+                .map(|s| s.map(i16::to_le_bytes))
+                .collect::<Result<Vec<_>, _>>()?
+                .concat(),
+            SampleFormat::F32 => reader
+                .samples::<f32>()
+                .map(|s| s.map(f32::to_le_bytes))
+                .collect::<Result<Vec<_>, _>>()?
+                .concat(),
+        };
+
+        let mut offset = 0;
+        Ok(Source::paced(format, chunk, move |len| {
+            let end = data.len().min(offset + len);
+            let chunk = data[offset..end].to_vec();
+            offset = end;
+            Some(chunk).filter(|chunk| !chunk.is_empty())
+        }))
+    }
+
+    /// A tone at `frequency` Hz on every channel, for `length` or for ever.
+    pub fn sine(
+        format: Format,
+        frequency: f64,
+        length: Option<Duration>,
+        chunk: Duration,
+    ) -> Source {
+        let rate = format.sample_rate as f64;
+        let total = length.map(|length| (length.as_secs_f64() * rate) as u64);
+        let mut position = 0;
+        Source::paced(format, chunk, move |len| {
+            let mut frames = (len / format.frame_bytes()) as u64;
+            if let Some(total) = total {
+                frames = frames.min(total - position);
+            }
+            if frames == 0 {
+                return None;
+            }
+            let mut chunk = Vec::with_capacity(frames as usize * format.frame_bytes());
+            for n in position..position + frames {
+                let value = (TAU * frequency * n as f64 / rate).sin() * AMPLITUDE;
+                for _ in 0..format.channels {
+                    match format.sample_format {
+                        SampleFormat::I16 => {
+                            let sample = (value * i16::MAX as f64) as i16;
+                            chunk.extend_from_slice(&sample.to_le_bytes());
+                        }
+                        SampleFormat::F32 => chunk.extend_from_slice(&(value as f32).to_le_bytes()),
+                    }
+                }
+            }
+            position += frames;
+            Some(chunk)
+        })
+    }
+
+    /// Ask `next_chunk` for a `chunk` worth of bytes at a time, at the rate
+    /// they would play, until it returns `None`.
+    fn paced<F>(format: Format, chunk: Duration, mut next_chunk: F) -> Source
+    where
+        F: FnMut(usize) -> Option<Vec<u8>> + Send + 'static,
+    {
+        let len = frame_len(format, chunk);
+        let (tx, chunks) = mpsc::channel(QUEUE);
+        tokio::spawn(async move {
+            let mut ticks = interval(chunk);
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                ticks.tick().await;
+                let Some(chunk) = next_chunk(len) else { break };
+                if tx.send(chunk).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Source {
+            format,
+            chunks,
+            dropped: Arc::new(AtomicU64::new(0)),
+            _stream: None,
+        }
+    }
+}
+
+/// The names of the input devices [`Source::microphone`] can record from.
+pub fn input_devices() -> Result<Vec<String>, AnyError> {
+    let mut names = Vec::new();
+    for device in cpal::default_host().input_devices()? {
+        names.push(device.name()?);
+    }
+    Ok(names)
+}
+
+fn to_bytes<T: cpal::Sample>(data: &[T], format: SampleFormat) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(data.len() * format.bytes());
+    for sample in data {
+        match format {
+            SampleFormat::I16 => bytes.extend_from_slice(&sample.to_i16().to_le_bytes()),
+            SampleFormat::F32 => bytes.extend_from_slice(&sample.to_f32().to_le_bytes()),
+        }
+    }
+    bytes
+}
+
+/// Bytes of samples that play for `duration`, and at least one sample for
+/// every channel.
+pub fn frame_len(format: Format, duration: Duration) -> usize {
+    let frames = (duration.as_secs_f64() * format.sample_rate as f64).round() as usize;
+    frames.max(1) * format.frame_bytes()
+}
+
+/// Cuts samples into frames of one duration, numbered and timestamped in
+/// the order they were pushed.
+pub struct Framer {
+    format: Format,
+    encoder: Encoder,
+    frame_len: usize,
+    pending: Vec<u8>,
+    seq: u64,
+    /// Bytes of samples framed so far.
+    framed: usize,
+}
+
+impl Framer {
+    /// A framer for uncompressed frames.
+    pub fn new(format: Format, duration: Duration) -> Self {
+        Framer::with_codec(format, duration, Codec::Pcm).expect("PCM takes any format")
+    }
+
+    /// A framer for frames compressed with `codec`. Fails if the codec
+    /// cannot carry the format, or frames of that duration.
+    pub fn with_codec(format: Format, duration: Duration, codec: Codec) -> Result<Self, String> {
+        let frame_len = frame_len(format, duration);
+        let encoder = Encoder::new(codec, format, frame_len / format.frame_bytes())
+            .map_err(|e| format!("cannot use {}: {}", codec, e))?;
+        Ok(Framer {
+            format,
+            encoder,
+            frame_len,
+            pending: Vec::with_capacity(frame_len),
+            seq: 0,
+            framed: 0,
+        })
+    }
+
+    /// The frames `samples` completes.
+    pub fn push(&mut self, samples: &[u8]) -> Vec<Vec<u8>> {
+        self.pending.extend_from_slice(samples);
+        let mut frames = Vec::new();
+        while self.pending.len() >= self.frame_len {
+            let rest = self.pending.split_off(self.frame_len);
+            let samples = std::mem::replace(&mut self.pending, rest);
+            frames.push(self.frame(&samples));
+        }
+        frames
+    }
+
+    /// Whatever is left over, as a short last frame.
+    pub fn finish(&mut self) -> Option<Vec<u8>> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        let samples = std::mem::take(&mut self.pending);
+        Some(self.frame(&samples))
+    }
+
+    /// Frames made so far.
+    pub fn frames(&self) -> u64 {
+        self.seq
+    }
+
+    /// How long the frames made so far play for.
+    pub fn duration(&self) -> Duration {
+        Duration::from_micros(self.format.micros(self.framed))
+    }
+
+    fn frame(&mut self, samples: &[u8]) -> Vec<u8> {
+        let header = Header {
+            seq: self.seq,
+            timestamp: self.format.micros(self.framed),
+            format: self.format,
+            codec: self.encoder.codec(),
+        };
+        let payload = self
+            .encoder
+            .encode(samples)
+            .expect("the encoder was made for frames this long");
+        self.seq += 1;
+        self.framed += samples.len();
+        header.encode(&payload)
+    }
+}
+
+/// The command line of a streaming client called `name`.
+pub fn command(name: &'static str) -> Command {
+    Command::new(name)
+        .about("Stream a microphone, a WAV file or a test tone to a WebSocket server")
+        .arg(
+            Arg::new("url")
+                .default_value("ws://127.0.0.1:9000")
+                .help("ws:// or wss:// URL to connect to"),
+        )
+        .arg(
+            Arg::new("list-devices")
+                .short('l')
+                .long("list-devices")
+                .action(ArgAction::SetTrue)
+                .help("List the input devices and exit"),
+        )
+        .arg(
+            Arg::new("device")
+                .short('D')
+                .long("device")
+                .conflicts_with_all(["file", "tone"])
+                .help("Input device to record from, by name or by number from --list-devices"),
+        )
+        .arg(
+            Arg::new("file")
+                .short('f')
+                .long("file")
+                .value_parser(clap::value_parser!(PathBuf))
+                .conflicts_with("tone")
+                .help("Stream a 16-bit or float WAV file instead of recording"),
+        )
+        .arg(
+            Arg::new("tone")
+                .long("tone")
+                .num_args(0..=1)
+                .default_missing_value("440")
+                .value_parser(clap::value_parser!(f64))
+                .help("Stream a sine tone, 440 Hz unless given, instead of recording"),
+        )
+        .arg(
+            Arg::new("sample-rate")
+                .long("sample-rate")
+                .default_value("48000")
+                .value_parser(clap::value_parser!(u32).range(1..=MAX_SAMPLE_RATE as i64))
+                .help("Sample rate of the --tone"),
+        )
+        .arg(
+            Arg::new("channels")
+                .long("channels")
+                .default_value("1")
+                .value_parser(clap::value_parser!(u16).range(1..=MAX_CHANNELS as i64))
+                .help("Channels of the --tone"),
+        )
+        .arg(
+            Arg::new("sample-format")
+                .long("sample-format")
+                .default_value("i16")
+                .value_parser(SampleFormat::NAMES)
+                .help("Sample format of the --tone"),
+        )
+        .arg(
+            Arg::new("frame-ms")
+                .long("frame-ms")
+                .default_value("20")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Milliseconds of audio in each frame"),
+        )
+        .arg(
+            Arg::new("codec")
+                .short('c')
+                .long("codec")
+                .default_value("pcm")
+                .value_parser(Codec::NAMES)
+                .help("Codec to offer the server; it falls back to pcm if refused"),
+        )
+        .arg(
+            Arg::new("duration")
+                .short('d')
+                .long("duration")
+                .default_value("0")
+                .value_parser(clap::value_parser!(u64))
+                .help("Seconds to stream; 0 streams until the source ends"),
+        )
+        .arg(
+            Arg::new("ca-file")
+                .long("ca-file")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("PEM file with the CA to trust for wss:// instead of the Mozilla roots"),
+        )
+        .arg(
+            Arg::new("token")
+                .long("token")
+                .help("Bearer token for servers that ask for one"),
+        )
+        .arg(
+            Arg::new("quiet")
+                .short('q')
+                .long("quiet")
+                .action(ArgAction::SetTrue)
+                .help("Don't print progress"),
+        )
+}
+
+/// Do what a [`command`] line asks: list the input devices, or stream the
+/// chosen source until it ends or `--duration` is up.
+pub async fn stream(matches: &ArgMatches) -> Result<(), AnyError> {
+    if matches.get_flag("list-devices") {
+        for (n, name) in input_devices()?.iter().enumerate() {
+            println!("{}: {}", n, name);
+        }
+        return Ok(());
+    }
+
+    let frame = Duration::from_millis(*matches.get_one::<u64>("frame-ms").unwrap());
+    let duration = match *matches.get_one::<u64>("duration").unwrap() {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    let mut source = if let Some(path) = matches.get_one::<PathBuf>("file") {
+        Source::wav_file(path, frame)?
+    } else if let Some(&frequency) = matches.get_one::<f64>("tone") {
+        let format = Format {
+            sample_format: matches
+                .get_one::<String>("sample-format")
+                .unwrap()
+                .parse()?,
+            channels: *matches.get_one::<u16>("channels").unwrap(),
+            sample_rate: *matches.get_one::<u32>("sample-rate").unwrap(),
+        };
+        Source::sine(format, frequency, duration, frame)
+    } else {
+        let device = select_device(matches.get_one::<String>("device"))?;
+        Source::microphone(device.as_deref())
+            .map_err(|e| format!("{} (without an input device, use --file or --tone)", e))?
+    };
+    let format = source.format;
+    let requested: Codec = matches.get_one::<String>("codec").unwrap().parse()?;
+    // Fails before connecting if the codec cannot carry this audio.
+    let mut framer = Framer::with_codec(format, frame, requested)?;
+    eprintln!(
+        "Streaming {} Hz, {} channel {} audio in {} ms frames",
+        format.sample_rate,
+        format.channels,
+        format.sample_format,
+        frame.as_millis()
+    );
+
+    let url = matches.get_one::<String>("url").unwrap();
+    let config = Config {
+        tls: match matches.get_one::<PathBuf>("ca-file") {
+            Some(ca_file) => Some(tls::with_roots(&[ca_file])?),
+            None => None,
+        },
+        token: matches.get_one::<String>("token").cloned(),
+        // Samples hardly compress, and the time spent trying adds latency.
+        compression: None,
+        codecs: vec![requested],
+        ..Config::default()
+    };
+    eprintln!("Connecting to {:?}", url);
+    let client = Client::with_config(url.as_str(), config);
+    let mut negotiated = client.codec();
+    let (mut sink, mut stream) = client.split();
+    let reader = tokio::spawn(async move {
+        while let Some(msg) = stream.next().await {
+            let Message::Text(text) = msg else { continue };
+            match Envelope::<serde_json::Value>::from_json(&text) {
+                Ok(Envelope::Error { code, message }) => eprintln!("Error ({}): {}", code, message),
+                Ok(Envelope::Data { body, .. }) => eprintln!("Received: {}", body),
+                _ => {}
+            }
+        }
+    });
+
+    let codec = negotiated
+        .wait_for(Option::is_some)
+        .await
+        .map_err(|_| "the connection ended before the server said hello")?
+        .unwrap();
+    if codec != requested {
+        eprintln!("The server does not take {}; sending {}", requested, codec);
+        framer = Framer::with_codec(format, frame, codec)?;
+    }
+
+    let quiet = matches.get_flag("quiet");
+    // Progress goes out every 50 frames, however many frames a chunk makes.
+    let mut reported = 0;
+    let stop = sleep(duration.unwrap_or(Duration::MAX));
+    tokio::pin!(stop);
+    loop {
+        let chunk = tokio::select! {
+            chunk = source.next() => chunk,
+            _ = &mut stop => None,
+        };
+        let Some(chunk) = chunk else { break };
+        for frame in framer.push(&chunk) {
+            sink.send(Message::Binary(frame)).await?;
+        }
+        if !quiet && framer.frames() / 50 != reported {
+            reported = framer.frames() / 50;
+            eprintln!("Sent {:.1} s", framer.duration().as_secs_f64());
+        }
+    }
+    if let Some(frame) = framer.finish() {
+        sink.send(Message::Binary(frame)).await?;
+    }
+    eprintln!(
+        "Streamed {} frames, {:.1} s of audio",
+        framer.frames(),
+        framer.duration().as_secs_f64()
+    );
+    if source.dropped() > 0 {
+        eprintln!(
+            "Dropped {} chunks the sender fell behind on",
+            source.dropped()
+        );
+    }
+
+    sink.close().await?;
+    reader.await?;
+    eprintln!("Closing...");
+    Ok(())
+}
+
+/// The name of the `--device` asked for, or `None` for the default one.
+fn select_device(device: Option<&String>) -> Result<Option<String>, AnyError> {
+    let Some(device) = device else {
+        return Ok(None);
+    };
+    let Ok(n) = device.parse::<usize>() else {
+        return Ok(Some(device.clone()));
+    };
+    match input_devices()?.into_iter().nth(n) {
+        Some(name) => Ok(Some(name)),
+        None => Err(format!("there is no input device {}; see --list-devices", n).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_command_line_is_consistent() {
+        command("stream-audio").debug_assert();
+        let matches = command("stream-audio").try_get_matches_from([
+            "stream-audio",
+            "--tone",
+            "-d",
+            "5",
+            "-D",
+            "1",
+        ]);
+        // A device and a tone are two different sources.
+        assert!(matches.is_err());
+        let matches = command("stream-audio")
+            .try_get_matches_from(["stream-audio", "--tone", "-d", "5"])
+            .unwrap();
+        assert_eq!(matches.get_one::<f64>("tone"), Some(&440.0));
+        assert_eq!(matches.get_one::<u64>("duration"), Some(&5));
+    }
+
+    const MONO: Format = Format {
+        sample_format: SampleFormat::I16,
+        channels: 1,
+        sample_rate: 8000,
+    };
+
+    #[test]
+    fn samples_are_cut_into_timestamped_frames() {
+        // 20 ms at 8 kHz is 160 samples, or 320 bytes.
+        let mut framer = Framer::new(MONO, Duration::from_millis(20));
+        assert!(framer.push(&[0; 300]).is_empty());
+        let frames = framer.push(&[0; 400]);
+        assert_eq!(frames.len(), 2);
+        let last = framer.finish().unwrap();
+        assert_eq!(framer.finish(), None);
+
+        let (first, samples) = Header::decode(&frames[0]).unwrap();
+        assert_eq!((first.seq, first.timestamp, samples.len()), (0, 0, 320));
+        let (second, _) = Header::decode(&frames[1]).unwrap();
+        assert_eq!((second.seq, second.timestamp), (1, 20_000));
+        let (last, samples) = Header::decode(&last).unwrap();
+        assert_eq!((last.seq, last.timestamp, samples.len()), (2, 40_000, 60));
+        assert_eq!(framer.duration(), Duration::from_micros(43_750));
+    }
+
+    #[test]
+    fn frames_are_compressed_with_the_codec() {
+        let mut framer = Framer::with_codec(MONO, Duration::from_millis(20), Codec::Flac).unwrap();
+        let frames = framer.push(&[0; 320]);
+        let (header, payload) = Header::decode(&frames[0]).unwrap();
+        assert_eq!(header.codec, Codec::Flac);
+        assert!(payload.len() < 320);
+
+        // Opus has no 7 ms frames.
+        assert!(Framer::with_codec(MONO, Duration::from_millis(7), Codec::Opus).is_err());
+    }
+
+    #[tokio::test]
+    async fn files_and_tones_are_streamed_whole() {
+        let length = Duration::from_millis(50);
+        let chunk = Duration::from_millis(10);
+        let mut sine = Source::sine(MONO, 440.0, Some(length), chunk);
+        let mut tone = Vec::new();
+        while let Some(chunk) = sine.next().await {
+            assert!(chunk.len() <= frame_len(MONO, Duration::from_millis(10)));
+            tone.extend(chunk);
+        }
+        assert_eq!(tone.len(), 400 * 2);
+
+        let path = std::env::temp_dir().join(format!("tone-{}.wav", std::process::id()));
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 8000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for sample in tone.chunks(2) {
+            writer
+                .write_sample(i16::from_le_bytes([sample[0], sample[1]]))
+                .unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let mut file = Source::wav_file(&path, chunk).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(file.format, MONO);
+        let mut played = Vec::new();
+        while let Some(chunk) = file.next().await {
+            played.extend(chunk);
+        }
+        assert_eq!(played, tone);
+    }
+}
@@ -0,0 +1,534 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use clap::{Arg, Command};
+use futures::sink::SinkExt;
+use futures::stream::StreamExt;
+use hdrhistogram::Histogram;
+use serde_json::{json, Value};
+
+use tokio::time::{interval_at, sleep_until, timeout, MissedTickBehavior};
+
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async_tls_with_config, Connector};
+use websocket_client::tls;
+use websocket_protocol::Envelope;
+
+type AnyError = Box<dyn std::error::Error + Send + Sync>;
+
+/// How long to wait for the last echoes once the sending stops.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The `--rate`s allowed. Outside them the period between messages gets too
+/// long to be of use or too short for a timer, down to the zero that
+/// `interval_at` panics on.
+const RATES: std::ops::RangeInclusive<f64> = 1e-3..=1e6;
+
+fn rate(s: &str) -> Result<f64, String> {
+    let rate: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if RATES.contains(&rate) {
+        Ok(rate)
+    } else {
+        Err(format!(
+            "expected between {} and {}",
+            RATES.start(),
+            RATES.end()
+        ))
+    }
+}
+
+/// Opens many connections to a websocket-server in echo mode, sends on each
+/// at a steady rate and reports round-trip latency and throughput as JSON.
+///
+/// Every message carries the connection it came from and when it was sent,
+/// so the echo alone is enough to time it. Messages from other connections,
+/// as in broadcast mode, are not counted.
+#[tokio::main]
+async fn main() -> Result<(), AnyError> {
+    let matches = Command::new("loadgen")
+        .about("Load test a websocket-server running in echo mode")
+        .arg(
+            Arg::new("url")
+                .default_value("ws://127.0.0.1:9000")
+                .help("ws:// or wss:// URL of the server"),
+        )
+        .arg(
+            Arg::new("connections")
+                .short('c')
+                .long("connections")
+                .default_value("100")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Number of concurrent connections"),
+        )
+        .arg(
+            Arg::new("rate")
+                .short('r')
+                .long("rate")
+                .default_value("10")
+                .value_parser(rate)
+                .help("Messages per second on each connection, up to a million"),
+        )
+        .arg(
+            Arg::new("duration")
+                .short('d')
+                .long("duration")
+                .default_value("10")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Seconds to keep sending"),
+        )
+        .arg(
+            Arg::new("size")
+                .short('s')
+                .long("size")
+                .default_value("64")
+                .value_parser(clap::value_parser!(usize))
+                .help("Bytes of padding in each message"),
+        )
+        .arg(
+            Arg::new("connect-timeout")
+                .long("connect-timeout")
+                .default_value("10")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Seconds to wait for a connection and its handshake"),
+        )
+        .arg(
+            Arg::new("token")
+                .long("token")
+                .help("Bearer token for servers that ask for one"),
+        )
+        .arg(
+            Arg::new("ca-file")
+                .long("ca-file")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("PEM file with the CA to trust for wss:// instead of the Mozilla roots"),
+        )
+        .get_matches();
+
+    let rate = *matches.get_one::<f64>("rate").unwrap();
+    let config = Config {
+        url: matches.get_one::<String>("url").unwrap().clone(),
+        connections: *matches.get_one::<u64>("connections").unwrap() as usize,
+        period: Duration::from_secs_f64(1.0 / rate),
+        duration: Duration::from_secs(*matches.get_one::<u64>("duration").unwrap()),
+        padding: "x".repeat(*matches.get_one::<usize>("size").unwrap()),
+        connect_timeout: Duration::from_secs(*matches.get_one::<u64>("connect-timeout").unwrap()),
+        token: matches.get_one::<String>("token").cloned(),
+        tls: match matches.get_one::<PathBuf>("ca-file") {
+            Some(ca_file) => Some(tls::with_roots(&[ca_file])?),
+            None => None,
+        },
+    };
+
+    let report = run(Arc::new(config)).await?;
+    println!("{:#}", report.to_json());
+    Ok(())
+}
+
+struct Config {
+    url: String,
+    connections: usize,
+    /// The wait between two messages on one connection.
+    period: Duration,
+    duration: Duration,
+    padding: String,
+    connect_timeout: Duration,
+    token: Option<String>,
+    tls: Option<Arc<rustls::ClientConfig>>,
+}
+
+/// What one connection saw.
+struct Outcome {
+    /// How long connecting and saying hello took, or why it failed.
+    connect: Result<Duration, String>,
+    /// Why the connection broke before the test was over.
+    dropped: Option<String>,
+    sent: u64,
+    received: u64,
+    bytes_sent: u64,
+    bytes_received: u64,
+    /// Round trips in microseconds.
+    rtt: Histogram<u64>,
+}
+
+impl Outcome {
+    fn new(connect: Result<Duration, String>) -> Self {
+        Outcome {
+            connect,
+            dropped: None,
+            sent: 0,
+            received: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
+            rtt: histogram(),
+        }
+    }
+}
+
+struct Report {
+    url: String,
+    attempted: usize,
+    /// Connect failures and drops, by error.
+    errors: BTreeMap<String, u64>,
+    failed: u64,
+    dropped: u64,
+    /// Connect times in microseconds.
+    connect: Histogram<u64>,
+    sending: Duration,
+    sent: u64,
+    received: u64,
+    bytes_sent: u64,
+    bytes_received: u64,
+    rtt: Histogram<u64>,
+}
+
+impl Report {
+    fn to_json(&self) -> Value {
+        let secs = self.sending.as_secs_f64();
+        json!({
+            "url": self.url,
+            "connections": {
+                "attempted": self.attempted,
+                "established": self.attempted as u64 - self.failed,
+                "failed": self.failed,
+                "dropped": self.dropped,
+                "errors": self.errors,
+            },
+            "connect_ms": summary(&self.connect),
+            "duration_secs": secs,
+            "messages": {
+                "sent": self.sent,
+                "received": self.received,
+                "lost": self.sent.saturating_sub(self.received),
+            },
+            "throughput": {
+                "sent_per_sec": self.sent as f64 / secs,
+                "received_per_sec": self.received as f64 / secs,
+                "bytes_sent_per_sec": self.bytes_sent as f64 / secs,
+                "bytes_received_per_sec": self.bytes_received as f64 / secs,
+            },
+            "rtt_ms": summary(&self.rtt),
+        })
+    }
+}
+
+fn histogram() -> Histogram<u64> {
+    // From a microsecond to a minute, to three significant figures.
+    Histogram::new_with_bounds(1, 60_000_000, 3).unwrap()
+}
+
+fn micros(d: Duration) -> u64 {
+    d.as_micros().try_into().unwrap_or(u64::MAX)
+}
+
+/// Percentiles and doubling buckets of a histogram of microseconds, in
+/// milliseconds.
+fn summary(histogram: &Histogram<u64>) -> Value {
+    let ms = |us: u64| us as f64 / 1000.0;
+    if histogram.is_empty() {
+        return json!({ "count": 0 });
+    }
+    let buckets: Vec<Value> = histogram
+        .iter_log(100, 2.0)
+        .filter(|bucket| bucket.count_since_last_iteration() > 0)
+        .map(|bucket| {
+            json!({
+                "le": ms(bucket.value_iterated_to()),
+                "count": bucket.count_since_last_iteration(),
+            })
+        })
+        .collect();
+    json!({
+        "count": histogram.len(),
+        "min": ms(histogram.min()),
+        "mean": histogram.mean() / 1000.0,
+        "p50": ms(histogram.value_at_quantile(0.5)),
+        "p90": ms(histogram.value_at_quantile(0.9)),
+        "p99": ms(histogram.value_at_quantile(0.99)),
+        "p999": ms(histogram.value_at_quantile(0.999)),
+        "max": ms(histogram.max()),
+        "histogram": buckets,
+    })
+}
+
+/// Open every connection at once and let each send for `duration`.
+async fn run(config: Arc<Config>) -> Result<Report, AnyError> {
+    let start = Instant::now();
+    let tasks: Vec<_> = (0..config.connections)
+        .map(|id| tokio::spawn(run_connection(config.clone(), id, start)))
+        .collect();
+
+    let mut report = Report {
+        url: config.url.clone(),
+        attempted: config.connections,
+        errors: BTreeMap::new(),
+        failed: 0,
+        dropped: 0,
+        connect: histogram(),
+        sending: config.duration,
+        sent: 0,
+        received: 0,
+        bytes_sent: 0,
+        bytes_received: 0,
+        rtt: histogram(),
+    };
+    for task in tasks {
+        let outcome = task.await?;
+        match outcome.connect {
+            Ok(took) => report.connect.saturating_record(micros(took)),
+            Err(e) => {
+                report.failed += 1;
+                *report.errors.entry(e).or_default() += 1;
+            }
+        }
+        if let Some(e) = outcome.dropped {
+            report.dropped += 1;
+            *report.errors.entry(e).or_default() += 1;
+        }
+        report.sent += outcome.sent;
+        report.received += outcome.received;
+        report.bytes_sent += outcome.bytes_sent;
+        report.bytes_received += outcome.bytes_received;
+        report.rtt.add(&outcome.rtt)?;
+    }
+    Ok(report)
+}
+
+async fn run_connection(config: Arc<Config>, id: usize, start: Instant) -> Outcome {
+    let connecting = Instant::now();
+    let mut ws = match timeout(config.connect_timeout, connect(&config)).await {
+        Ok(Ok(ws)) => ws,
+        Ok(Err(e)) => return Outcome::new(Err(e.to_string())),
+        Err(_) => return Outcome::new(Err("timed out connecting".to_string())),
+    };
+    let mut outcome = Outcome::new(Ok(connecting.elapsed()));
+
+    // Spread the connections over one period, so they don't all send at
+    // the same instant.
+    let offset = config.period.mul_f64(id as f64 / config.connections as f64);
+    let mut ticks = interval_at((Instant::now() + offset).into(), config.period);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let sending_until = Instant::now() + config.duration;
+    let drain_until = sending_until + DRAIN_TIMEOUT;
+
+    loop {
+        let sending = Instant::now() < sending_until;
+        if !sending && outcome.received >= outcome.sent {
+            break;
+        }
+        tokio::select! {
+            _ = ticks.tick(), if sending => {
+                let body = json!({
+                    "c": id,
+                    "t": micros(start.elapsed()),
+                    "pad": config.padding,
+                });
+                let msg = Envelope::data(body).to_message();
+                outcome.bytes_sent += msg.len() as u64;
+                if let Err(e) = ws.send(msg).await {
+                    outcome.dropped = Some(e.to_string());
+                    return outcome;
+                }
+                outcome.sent += 1;
+            }
+            msg = ws.next() => match msg {
+                Some(Ok(Message::Text(text))) => {
+                    let Ok(Envelope::Data { body, .. }) = Envelope::<Value>::from_json(&text) else {
+                        continue;
+                    };
+                    if body["c"].as_u64() != Some(id as u64) {
+                        continue;
+                    }
+                    let Some(sent_at) = body["t"].as_u64() else { continue };
+                    let rtt = micros(start.elapsed()).saturating_sub(sent_at);
+                    outcome.rtt.saturating_record(rtt.max(1));
+                    outcome.received += 1;
+                    outcome.bytes_received += text.len() as u64;
+                }
+                Some(Ok(Message::Close(frame))) => {
+                    let reason = frame.map(|frame| frame.to_string());
+                    outcome.dropped = Some(format!("closed by the server: {}", reason.unwrap_or_default()));
+                    return outcome;
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => {
+                    outcome.dropped = Some(e.to_string());
+                    return outcome;
+                }
+                None => {
+                    outcome.dropped = Some("connection closed".to_string());
+                    return outcome;
+                }
+            },
+            _ = sleep_until(drain_until.into()), if !sending => break,
+        }
+    }
+
+    let _ = ws.close(None).await;
+    outcome
+}
+
+type Socket =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// Connect and get through the hello handshake.
+async fn connect(config: &Config) -> Result<Socket, AnyError> {
+    let mut request = config.url.as_str().into_client_request()?;
+    let headers = request.headers_mut();
+    headers.insert(
+        "Sec-WebSocket-Protocol",
+        websocket_protocol::SUBPROTOCOL.parse()?,
+    );
+    if let Some(token) = &config.token {
+        headers.insert("Authorization", format!("Bearer {}", token).parse()?);
+    }
+    let connector = config.tls.clone().map(Connector::Rustls);
+    let (mut ws, _) = connect_async_tls_with_config(request, None, connector).await?;
+
+    ws.send(Envelope::<()>::hello().to_message()).await?;
+    loop {
+        let msg = ws.next().await.ok_or("closed during the handshake")??;
+        let Message::Text(text) = msg else { continue };
+        return match Envelope::<Value>::from_json(&text) {
+            Ok(Envelope::Hello { version, .. }) if websocket_protocol::is_compatible(version) => {
+                Ok(ws)
+            }
+            Ok(Envelope::Hello { version, .. }) => {
+                Err(format!("the server speaks version {}", version).into())
+            }
+            Ok(Envelope::Error { code, message }) => Err(format!("{}: {}", code, message).into()),
+            _ => Err("the server did not say hello".into()),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// An echo server that says hello first, like websocket-server in echo
+    /// mode.
+    async fn echo_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+                    ws.send(Envelope::<()>::hello().to_message()).await.unwrap();
+                    while let Some(Ok(msg)) = ws.next().await {
+                        if msg.is_text() && ws.send(msg).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn reports_a_run_against_an_echo_server() {
+        let config = Config {
+            url: echo_server().await,
+            connections: 4,
+            period: Duration::from_millis(20),
+            duration: Duration::from_millis(500),
+            padding: "x".repeat(16),
+            connect_timeout: Duration::from_secs(5),
+            token: None,
+            tls: None,
+        };
+        let report = run(Arc::new(config)).await.unwrap().to_json();
+
+        let keys: Vec<&str> = report
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                "connect_ms",
+                "connections",
+                "duration_secs",
+                "messages",
+                "rtt_ms",
+                "throughput",
+                "url"
+            ]
+        );
+        let connections = &report["connections"];
+        assert_eq!(connections["attempted"], 4);
+        assert_eq!(connections["established"], 4);
+        assert_eq!(connections["failed"], 0);
+        assert_eq!(connections["dropped"], 0);
+        assert_eq!(connections["errors"], json!({}));
+
+        let messages = &report["messages"];
+        assert!(messages["sent"].as_u64().unwrap() >= 4);
+        assert_eq!(messages["received"], messages["sent"]);
+        assert_eq!(messages["lost"], 0);
+        assert_eq!(report["rtt_ms"]["count"], messages["sent"]);
+        assert_eq!(report["connect_ms"]["count"], 4);
+        for key in [
+            "sent_per_sec",
+            "received_per_sec",
+            "bytes_sent_per_sec",
+            "bytes_received_per_sec",
+        ] {
+            assert!(report["throughput"][key].as_f64().unwrap() > 0.0, "{}", key);
+        }
+    }
+
+    #[tokio::test]
+    async fn counts_connections_that_fail() {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let config = Config {
+            url: format!("ws://{}", addr),
+            connections: 2,
+            period: Duration::from_millis(20),
+            duration: Duration::from_millis(100),
+            padding: String::new(),
+            connect_timeout: Duration::from_secs(5),
+            token: None,
+            tls: None,
+        };
+        let report = run(Arc::new(config)).await.unwrap().to_json();
+        assert_eq!(report["connections"]["established"], 0);
+        assert_eq!(report["connections"]["failed"], 2);
+        assert_eq!(report["messages"]["sent"], 0);
+        assert_eq!(report["rtt_ms"], json!({ "count": 0 }));
+    }
+
+    #[test]
+    fn rate_is_bounded() {
+        for ok in ["10", "0.001", "1e6", "1000000"] {
+            assert!(rate(ok).is_ok(), "{}", ok);
+        }
+        for bad in ["0", "-1", "0.0009", "1000001", "inf", "NaN", "ten", ""] {
+            assert!(rate(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn summary_of_an_empty_histogram_is_just_its_count() {
+        assert_eq!(summary(&histogram()), json!({ "count": 0 }));
+
+        let mut rtt = histogram();
+        rtt.record(1500).unwrap();
+        let summary = summary(&rtt);
+        assert_eq!(summary["count"], 1);
+        assert_eq!(summary["min"], 1.5);
+        assert_eq!(summary["max"], 1.5);
+        assert_eq!(summary["histogram"].as_array().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,16 @@
+use websocket_client::audio;
+
+type AnyError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Streams live audio to a websocket-server as it is captured, in binary
+/// frames of a fixed duration.
+///
+/// The audio comes from a microphone, a WAV file or a sine tone; the last
+/// two are sent no faster than they play, so either can stand in for a
+/// microphone. The command line and the sending live in
+/// [`websocket_client::audio`], shared with websocket-streamaudio's client.
+#[tokio::main]
+async fn main() -> Result<(), AnyError> {
+    let matches = audio::command("stream-audio").get_matches();
+    audio::stream(&matches).await
+}
@@ -0,0 +1,776 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::channel::mpsc;
+use futures::sink::{Sink, SinkExt};
+use futures::stream::{SplitStream, Stream, StreamExt};
+
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::time::{sleep, Duration};
+
+use rustls::ServerName;
+use tokio_rustls::TlsConnector;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::header::{AUTHORIZATION, SEC_WEBSOCKET_PROTOCOL};
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Role};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::{client_async, MaybeTlsStream, WebSocketStream};
+use websocket_deflate::DeflateStream;
+use websocket_protocol::audio::Codec;
+use websocket_protocol::{Envelope, ErrorCode, INCOMPATIBLE_VERSION};
+
+type Socket = WebSocketStream<DeflateStream<MaybeTlsStream<TcpStream>>>;
+
+type AnyError = Box<dyn std::error::Error + Send + Sync>;
+
+/// How a [`Client`] connects, and what it does when the connection drops.
+#[derive(Clone)]
+pub struct Config {
+    /// The wait before the first reconnect attempt.
+    pub initial_backoff: Duration,
+    /// The wait doubles after each failed attempt, up to this much.
+    pub max_backoff: Duration,
+    /// Failed attempts in a row before giving up, or `None` to keep trying.
+    pub max_retries: Option<u32>,
+    /// Connect again after the connection drops. Without it the client ends
+    /// with its first connection, for exchanges that have to start over on
+    /// a new one rather than have their queue replayed there.
+    pub reconnect: bool,
+    /// Messages held while disconnected. Past this the oldest are dropped.
+    pub max_queued: usize,
+    /// Message compression to offer the server, or `None` for none.
+    pub compression: Option<websocket_deflate::Config>,
+    /// The TLS config for `wss://` URLs, such as one from
+    /// [`tls::with_roots`](crate::tls::with_roots). `None` trusts the
+    /// Mozilla roots.
+    pub tls: Option<Arc<rustls::ClientConfig>>,
+    /// Open every connection with a [`websocket_protocol`] hello and wait
+    /// for the server's before sending anything else. A server speaking an
+    /// incompatible version ends the client. The protocol is also asked
+    /// for by name in the upgrade request.
+    pub hello: bool,
+    /// Sent as a bearer token in the upgrade request.
+    pub token: Option<String>,
+    /// Audio codecs to offer in the hello, best first. The one the server
+    /// chose comes out of [`Client::codec`].
+    pub codecs: Vec<Codec>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            max_retries: None,
+            reconnect: true,
+            max_queued: 1024,
+            compression: Some(websocket_deflate::Config::default()),
+            tls: None,
+            hello: true,
+            token: None,
+            codecs: Vec::new(),
+        }
+    }
+}
+
+/// Exponential backoff between reconnect attempts.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Backoff {
+            initial,
+            max,
+            current: initial,
+        }
+    }
+
+    /// The wait before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+
+    /// Start over from the initial wait, after a successful connect.
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+}
+
+/// Returned when sending on a [`Client`] that has been closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Closed;
+
+impl fmt::Display for Closed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the client has been closed")
+    }
+}
+
+impl std::error::Error for Closed {}
+
+/// A WebSocket connection that reconnects by itself.
+///
+/// A background task owns the socket. Messages sent while it is down are
+/// queued and go out, in order, once it is back. A message whose write fails
+/// is sent again on the next connection, so it may arrive twice.
+///
+/// Only text and binary messages come out of the stream; pings are answered
+/// on the way. The stream ends once the client is closed and the queue has
+/// been sent, once `max_retries` attempts in a row have failed, or when the
+/// connection drops with `reconnect` off.
+pub struct Client {
+    outbound: mpsc::UnboundedSender<Message>,
+    inbound: mpsc::UnboundedReceiver<Message>,
+    codec: watch::Receiver<Option<Codec>>,
+}
+
+impl Client {
+    /// Connect to `url` with the default [`Config`].
+    ///
+    /// Connecting happens in the background, so this returns at once.
+    pub fn new(url: impl Into<String>) -> Self {
+        Client::with_config(url, Config::default())
+    }
+
+    pub fn with_config(url: impl Into<String>, config: Config) -> Self {
+        let (outbound, outbound_rx) = mpsc::unbounded();
+        let (inbound_tx, inbound) = mpsc::unbounded();
+        // Without a hello there is nothing to agree on, and PCM needs none.
+        let (codec_tx, codec) = watch::channel((!config.hello).then_some(Codec::Pcm));
+        let worker = Worker {
+            url: url.into(),
+            config,
+            outbound: outbound_rx,
+            inbound: inbound_tx,
+            codec: codec_tx,
+            queue: VecDeque::new(),
+            closing: false,
+        };
+        tokio::spawn(worker.run());
+        Client {
+            outbound,
+            inbound,
+            codec,
+        }
+    }
+
+    /// The audio codec to send frames in: the server's choice from
+    /// [`Config::codecs`] once it has answered the hello, or PCM if it
+    /// named none of them. `None` until then.
+    pub fn codec(&self) -> watch::Receiver<Option<Codec>> {
+        self.codec.clone()
+    }
+}
+
+impl Stream for Client {
+    type Item = Message;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Message>> {
+        self.inbound.poll_next_unpin(cx)
+    }
+}
+
+/// Sending only hands the message to the background task. Flushing does
+/// not wait for it to reach the server.
+impl Sink<Message> for Client {
+    type Error = Closed;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Closed>> {
+        self.outbound.poll_ready_unpin(cx).map_err(|_| Closed)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, msg: Message) -> Result<(), Closed> {
+        self.outbound.start_send_unpin(msg).map_err(|_| Closed)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Closed>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// Stop taking messages. What is queued is still sent before the
+    /// connection is closed.
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Closed>> {
+        self.outbound.close_channel();
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The background task behind a [`Client`].
+struct Worker {
+    url: String,
+    config: Config,
+    outbound: mpsc::UnboundedReceiver<Message>,
+    inbound: mpsc::UnboundedSender<Message>,
+    codec: watch::Sender<Option<Codec>>,
+    /// Messages waiting for a connection.
+    queue: VecDeque<Message>,
+    /// The client stopped taking messages; finish the queue and stop.
+    closing: bool,
+}
+
+impl Worker {
+    async fn run(mut self) {
+        let mut backoff = Backoff::new(self.config.initial_backoff, self.config.max_backoff);
+        let mut failures = 0;
+        loop {
+            if self.closing && self.queue.is_empty() {
+                return;
+            }
+
+            let url = self.url.clone();
+            let config = self.config.clone();
+            match self.buffering(connect(&url, &config)).await {
+                Ok(socket) => {
+                    failures = 0;
+                    backoff.reset();
+                    if self.serve(socket).await || !self.config.reconnect {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    failures += 1;
+                    println!("Error connecting to {}: {}", self.url, e);
+                    if refused(&e) || self.config.max_retries.is_some_and(|max| failures > max) {
+                        return;
+                    }
+                }
+            }
+
+            self.buffering(sleep(backoff.next_delay())).await;
+        }
+    }
+
+    /// Wait for `fut` while queueing whatever the client sends meanwhile.
+    async fn buffering<F: Future>(&mut self, fut: F) -> F::Output {
+        tokio::pin!(fut);
+        loop {
+            tokio::select! {
+                output = &mut fut => return output,
+                msg = self.outbound.next(), if !self.closing => match msg {
+                    Some(msg) => self.enqueue(msg),
+                    None => self.closing = true,
+                },
+            }
+        }
+    }
+
+    fn enqueue(&mut self, msg: Message) {
+        if self.queue.len() >= self.config.max_queued {
+            self.queue.pop_front();
+        }
+        self.queue.push_back(msg);
+    }
+
+    /// Pass messages both ways until the connection drops, which returns
+    /// false, or the client is closed or turned away, which returns true.
+    async fn serve(&mut self, socket: Socket) -> bool {
+        let (mut sink, mut stream) = socket.split();
+
+        if self.config.hello {
+            let hello = Envelope::<()>::Hello {
+                version: websocket_protocol::VERSION,
+                codecs: self.config.codecs.clone(),
+            };
+            if sink.send(hello.to_message()).await.is_err() {
+                return false;
+            }
+            match self.buffering(handshake(&mut stream)).await {
+                Ok(chosen) => {
+                    let codec = Codec::choose(&chosen, &self.config.codecs);
+                    self.codec.send_replace(Some(codec));
+                }
+                Err(Handshake::Dropped) => return false,
+                Err(Handshake::Incompatible(reason)) => {
+                    println!("Giving up on {}: {}", self.url, reason);
+                    let frame = CloseFrame {
+                        code: INCOMPATIBLE_VERSION,
+                        reason: reason.into(),
+                    };
+                    let _ = sink.send(Message::Close(Some(frame))).await;
+                    return true;
+                }
+            }
+        }
+
+        while let Some(msg) = self.queue.pop_front() {
+            // A clone, so the message can be queued again if the write fails.
+            if sink.feed(msg.clone()).await.is_err() {
+                self.queue.push_front(msg);
+                return false;
+            }
+        }
+        if sink.flush().await.is_err() {
+            return false;
+        }
+
+        loop {
+            if self.closing {
+                let _ = sink.close().await;
+                // Anything the server sent before its close reply still
+                // reaches the client.
+                while let Some(Ok(msg)) = stream.next().await {
+                    self.deliver(msg);
+                }
+                return true;
+            }
+
+            tokio::select! {
+                msg = self.outbound.next() => match msg {
+                    Some(msg) => {
+                        if sink.send(msg.clone()).await.is_err() {
+                            self.queue.push_back(msg);
+                            return false;
+                        }
+                    }
+                    None => self.closing = true,
+                },
+                item = stream.next() => match item {
+                    Some(Ok(msg)) => self.deliver(msg),
+                    Some(Err(e)) => {
+                        println!("Error receiving message: {}", e);
+                        return false;
+                    }
+                    None => return false,
+                },
+            }
+        }
+    }
+
+    fn deliver(&self, msg: Message) {
+        if msg.is_text() || msg.is_binary() {
+            // Nobody listening is fine; the client may only be sending.
+            let _ = self.inbound.unbounded_send(msg);
+        }
+    }
+}
+
+/// Why a handshake did not get through.
+enum Handshake {
+    /// The connection went away; try again.
+    Dropped,
+    /// The server cannot be talked to, for this reason.
+    Incompatible(String),
+}
+
+/// Wait for the server to answer the client's hello with its own, and
+/// return the codecs it named.
+async fn handshake(stream: &mut SplitStream<Socket>) -> Result<Vec<Codec>, Handshake> {
+    loop {
+        let msg = match stream.next().await {
+            Some(Ok(msg)) => msg,
+            _ => return Err(Handshake::Dropped),
+        };
+        let text = match msg {
+            Message::Text(text) => text,
+            Message::Close(Some(frame)) if frame.code == INCOMPATIBLE_VERSION => {
+                return Err(Handshake::Incompatible(frame.reason.into_owned()));
+            }
+            Message::Close(_) => return Err(Handshake::Dropped),
+            Message::Binary(_) => {
+                return Err(Handshake::Incompatible(
+                    "the server did not say hello".into(),
+                ));
+            }
+            _ => continue,
+        };
+        return match Envelope::<serde_json::Value>::from_json(&text) {
+            Ok(Envelope::Hello { version, codecs })
+                if websocket_protocol::is_compatible(version) =>
+            {
+                Ok(codecs)
+            }
+            Ok(Envelope::Hello { version, .. }) => Err(Handshake::Incompatible(format!(
+                "the server speaks version {}",
+                version
+            ))),
+            Ok(Envelope::Error {
+                code: ErrorCode::IncompatibleVersion,
+                message,
+            }) => Err(Handshake::Incompatible(message)),
+            _ => Err(Handshake::Incompatible(
+                "the server did not say hello".into(),
+            )),
+        };
+    }
+}
+
+/// Open a connection, offering compression if there is a config for it.
+async fn connect(url: &str, config: &Config) -> Result<Socket, AnyError> {
+    let mut request = url.into_client_request()?;
+    if let Some(compression) = &config.compression {
+        compression.offer(&mut request);
+    }
+    let headers = request.headers_mut();
+    if config.hello {
+        let subprotocol = HeaderValue::from_static(websocket_protocol::SUBPROTOCOL);
+        headers.insert(SEC_WEBSOCKET_PROTOCOL, subprotocol);
+    }
+    if let Some(token) = &config.token {
+        headers.insert(AUTHORIZATION, format!("Bearer {}", token).parse()?);
+    }
+
+    let uri = request.uri();
+    let host = uri.host().ok_or("URL has no host")?;
+    // IPv6 addresses come in brackets.
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = match (uri.port_u16(), uri.scheme_str()) {
+        (Some(port), _) => port,
+        (None, Some("wss")) => 443,
+        (None, _) => 80,
+    };
+    let stream = TcpStream::connect((host, port)).await?;
+    let stream = match uri.scheme_str() {
+        Some("wss") => {
+            let tls = config.tls.clone().unwrap_or_else(crate::tls::mozilla_roots);
+            let name = ServerName::try_from(host)?;
+            MaybeTlsStream::Rustls(TlsConnector::from(tls).connect(name, stream).await?)
+        }
+        _ => MaybeTlsStream::Plain(stream),
+    };
+
+    // Compression goes on inside TLS and under tungstenite, which cannot
+    // do it itself.
+    let threshold = config.compression.as_ref().map_or(0, |c| c.threshold);
+    let stream = DeflateStream::new(stream, Role::Client, threshold);
+    let (socket, response) = client_async(request, stream).await?;
+    if let Some(compression) = &config.compression {
+        compression.accepted(&response)?;
+    }
+    Ok(socket)
+}
+
+/// Whether the server turned the upgrade down in a way that asking again
+/// will not change, such as a bad token or a foreign origin.
+fn refused(e: &AnyError) -> bool {
+    match e.downcast_ref::<WsError>() {
+        Some(WsError::Http(response)) => response.status().is_client_error(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio::time::timeout;
+
+    fn config() -> Config {
+        Config {
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(50),
+            // The servers here just echo, so there is nobody to say hello to.
+            hello: false,
+            ..Config::default()
+        }
+    }
+
+    /// An echo server that drops each connection after `per_connection`
+    /// messages.
+    async fn echo_server(listener: TcpListener, per_connection: usize) {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+                for _ in 0..per_connection {
+                    match ws.next().await {
+                        Some(Ok(msg)) if msg.is_text() => ws.send(msg).await.unwrap(),
+                        _ => return,
+                    }
+                }
+            });
+        }
+    }
+
+    async fn recv(client: &mut Client) -> String {
+        let msg = timeout(Duration::from_secs(5), client.next()).await;
+        msg.unwrap().unwrap().into_text().unwrap()
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_max() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(350));
+        let delays: Vec<u128> = (0..4).map(|_| backoff.next_delay().as_millis()).collect();
+        assert_eq!(delays, [100, 200, 350, 350]);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn messages_sent_before_the_server_is_up_are_queued() {
+        // Find a free port, then leave it closed for a while.
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut client = Client::with_config(format!("ws://{}", addr), config());
+        for n in 0..3 {
+            client.send(Message::Text(n.to_string())).await.unwrap();
+        }
+
+        sleep(Duration::from_millis(50)).await;
+        let listener = TcpListener::bind(addr).await.unwrap();
+        tokio::spawn(echo_server(listener, usize::MAX));
+
+        for n in 0..3 {
+            assert_eq!(recv(&mut client).await, n.to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_the_server_drops_it() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(echo_server(listener, 1));
+
+        let mut client = Client::with_config(url, config());
+        for n in 0..3 {
+            client.send(Message::Text(n.to_string())).await.unwrap();
+            assert_eq!(recv(&mut client).await, n.to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn ends_with_the_connection_without_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(echo_server(listener, 1));
+
+        let config = Config {
+            reconnect: false,
+            ..config()
+        };
+        let mut client = Client::with_config(url, config);
+        client.send(Message::Text("once".into())).await.unwrap();
+        assert_eq!(recv(&mut client).await, "once");
+        let end = timeout(Duration::from_secs(5), client.next()).await;
+        assert_eq!(end.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_retries() {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let config = Config {
+            max_retries: Some(2),
+            ..config()
+        };
+        let mut client = Client::with_config(format!("ws://{}", addr), config);
+        let end = timeout(Duration::from_secs(5), client.next()).await;
+        assert_eq!(end.unwrap(), None);
+        assert_eq!(client.send(Message::Text("late".into())).await, Err(Closed));
+    }
+
+    #[tokio::test]
+    async fn compression_is_negotiated() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let stream = DeflateStream::new(stream, Role::Server, 64);
+            // The error type is tungstenite's.
+            #[allow(clippy::result_large_err)]
+            let negotiate = |request: &_, mut response| {
+                websocket_deflate::Config::default().accept(request, &mut response);
+                Ok(response)
+            };
+            let mut ws = tokio_tungstenite::accept_hdr_async(stream, negotiate)
+                .await
+                .unwrap();
+            assert!(
+                ws.get_ref().params().is_some(),
+                "the client offers compression"
+            );
+            let msg = ws.next().await.unwrap().unwrap();
+            assert!(msg.is_text());
+            ws.send(msg).await.unwrap();
+        });
+
+        let mut client = Client::with_config(url, config());
+        let text = "compress me, ".repeat(20);
+        client.send(Message::Text(text.clone())).await.unwrap();
+        assert_eq!(recv(&mut client).await, text);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn connects_over_wss_with_a_custom_root() {
+        use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![Certificate(cert.serialize_der().unwrap())],
+                PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let stream = acceptor.accept(stream).await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let msg = ws.next().await.unwrap().unwrap();
+            ws.send(msg).await.unwrap();
+        });
+
+        let dir = std::env::temp_dir().join(format!("wss-root-{}", port));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ca = dir.join("ca.pem");
+        std::fs::write(&ca, cert.serialize_pem().unwrap()).unwrap();
+        let config = Config {
+            tls: Some(crate::tls::with_roots(&[&ca]).unwrap()),
+            compression: None,
+            ..config()
+        };
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mut client = Client::with_config(format!("wss://localhost:{}", port), config);
+        client.send(Message::Text("secure".into())).await.unwrap();
+        assert_eq!(recv(&mut client).await, "secure");
+    }
+
+    /// A server that answers the client's hello with `version`, then echoes.
+    async fn protocol_server(listener: TcpListener, version: u32) -> Option<CloseFrame<'static>> {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+        let hello = ws.next().await.unwrap().unwrap().into_text().unwrap();
+        assert_eq!(hello, Envelope::<()>::hello().to_json());
+        let reply = Envelope::<()>::Hello {
+            version,
+            codecs: Vec::new(),
+        };
+        ws.send(reply.to_message()).await.unwrap();
+        while let Some(Ok(msg)) = ws.next().await {
+            match msg {
+                Message::Close(frame) => return frame,
+                msg => ws.send(msg).await.unwrap(),
+            }
+        }
+        None
+    }
+
+    #[tokio::test]
+    async fn says_hello_before_sending() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(protocol_server(listener, websocket_protocol::VERSION));
+
+        let config = Config {
+            hello: true,
+            ..config()
+        };
+        let mut client = Client::with_config(url, config);
+        let msg = Envelope::data("after hello").to_message();
+        client.send(msg.clone()).await.unwrap();
+        // The server's hello is not passed on.
+        assert_eq!(recv(&mut client).await, msg.into_text().unwrap());
+    }
+
+    #[tokio::test]
+    async fn learns_the_codec_the_server_chose() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let hello = ws.next().await.unwrap().unwrap().into_text().unwrap();
+            let Ok(Envelope::<()>::Hello { codecs, .. }) = Envelope::from_json(&hello) else {
+                panic!("expected a hello, got {}", hello);
+            };
+            assert_eq!(codecs, [Codec::Opus, Codec::Flac]);
+            let reply = Envelope::<()>::Hello {
+                version: websocket_protocol::VERSION,
+                codecs: vec![Codec::Flac],
+            };
+            ws.send(reply.to_message()).await.unwrap();
+            while ws.next().await.is_some() {}
+        });
+
+        let offering = Config {
+            hello: true,
+            codecs: vec![Codec::Opus, Codec::Flac],
+            ..config()
+        };
+        let client = Client::with_config(url, offering);
+        let mut codec = client.codec();
+        let chosen = timeout(Duration::from_secs(5), codec.wait_for(Option::is_some)).await;
+        assert_eq!(*chosen.unwrap().unwrap(), Some(Codec::Flac));
+
+        // Without a hello, there is nothing to wait for.
+        let client = Client::with_config("ws://127.0.0.1:9", config());
+        assert_eq!(*client.codec().borrow(), Some(Codec::Pcm));
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_an_incompatible_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(protocol_server(listener, websocket_protocol::VERSION + 1));
+
+        let config = Config {
+            hello: true,
+            ..config()
+        };
+        let mut client = Client::with_config(url, config);
+        let end = timeout(Duration::from_secs(5), client.next()).await;
+        assert_eq!(end.unwrap(), None);
+        let frame = server.await.unwrap().unwrap();
+        assert_eq!(frame.code, INCOMPATIBLE_VERSION);
+    }
+
+    #[tokio::test]
+    async fn sends_the_token_and_stops_when_refused() {
+        use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+        use tokio_tungstenite::tungstenite::http::StatusCode;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            // The error type is tungstenite's.
+            #[allow(clippy::result_large_err)]
+            let refuse = |request: &Request, _: Response| {
+                assert_eq!(request.headers()[AUTHORIZATION], "Bearer wrong");
+                let mut refusal = ErrorResponse::new(None);
+                *refusal.status_mut() = StatusCode::UNAUTHORIZED;
+                Err(refusal)
+            };
+            let (stream, _) = listener.accept().await.unwrap();
+            assert!(tokio_tungstenite::accept_hdr_async(stream, refuse)
+                .await
+                .is_err());
+            // A second attempt would get the same answer, so none comes.
+            let again = timeout(Duration::from_millis(200), listener.accept()).await;
+            assert!(again.is_err());
+        });
+
+        let config = Config {
+            token: Some("wrong".to_string()),
+            ..config()
+        };
+        let mut client = Client::with_config(url, config);
+        let end = timeout(Duration::from_secs(5), client.next()).await;
+        assert_eq!(end.unwrap(), None);
+        server.await.unwrap();
+    }
+}
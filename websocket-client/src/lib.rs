@@ -0,0 +1,30 @@
+//! Building blocks for WebSocket clients.
+//!
+//! [`Client`] keeps a connection to a server open, reconnecting with
+//! exponential backoff whenever it drops. It is a `Stream` of the messages
+//! the server sends and a `Sink` for the ones to send back. Each connection
+//! opens with the [`websocket_protocol`] hello, so what goes over it should
+//! be envelopes:
+//!
+//! ```no_run
+//! use futures::{SinkExt, StreamExt};
+//! use websocket_client::Client;
+//! use websocket_protocol::Envelope;
+//!
+//! # async fn run() -> Result<(), websocket_client::Closed> {
+//! let mut client = Client::new("ws://127.0.0.1:9000");
+//! client.send(Envelope::data("hello").to_message()).await?;
+//! while let Some(msg) = client.next().await {
+//!     println!("{:?}", msg);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The [`audio`] module streams live audio over a client in binary frames.
+
+pub mod audio;
+pub mod client;
+pub mod tls;
+
+pub use client::{Backoff, Client, Closed, Config};
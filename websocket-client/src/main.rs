@@ -1,98 +1,352 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgAction, Command};
 use futures::sink::SinkExt;
-use futures::stream::StreamExt;
+use futures::stream::{SplitSink, SplitStream, StreamExt};
+use sha2::{Digest, Sha256};
 
-use tokio::net::TcpStream;
-use tokio::time::{sleep, Duration};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::time::{interval, Duration, MissedTickBehavior};
 
-use tokio_tungstenite::client_async;
-use tokio_tungstenite::tungstenite::{Message, Error as WsError};
+use tokio_tungstenite::tungstenite::Message;
+use websocket_client::{tls, Backoff, Client, Config};
+use websocket_protocol::Envelope;
 
 type AnyError = Box<dyn std::error::Error + Send + Sync>;
 
+const OUTPUTS: [&str; 3] = ["text", "json", "raw"];
+
+/// Sends messages to a websocket-server and prints what comes back.
+///
+/// Each message is sent as the body of a `data` envelope: as JSON if it
+/// parses as JSON, as a JSON string otherwise. Received messages go to
+/// stdout in the chosen format; everything else the client has to say goes
+/// to stderr.
 #[tokio::main]
 async fn main() -> Result<(), AnyError> {
-    let addr = "127.0.0.1:9000";
-    let url = format!("ws://{}", addr);
+    let matches = Command::new("websocket-client")
+        .about("Send messages or files to a WebSocket server")
+        .arg(
+            Arg::new("url")
+                .default_value("ws://127.0.0.1:9000")
+                .help("ws:// or wss:// URL to connect to"),
+        )
+        .arg(
+            Arg::new("interactive")
+                .short('i')
+                .long("interactive")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("send-file")
+                .help("Send each line read from stdin"),
+        )
+        .arg(
+            Arg::new("send-file")
+                .short('f')
+                .long("send-file")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Upload a file to a chat mode server"),
+        )
+        .arg(
+            Arg::new("chunk-size")
+                .long("chunk-size")
+                .default_value("65536")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Bytes per chunk with --send-file"),
+        )
+        .arg(
+            Arg::new("count")
+                .short('n')
+                .long("count")
+                .default_value("10")
+                .value_parser(clap::value_parser!(u64))
+                .help("Messages to send; 0 sends until interrupted"),
+        )
+        .arg(
+            Arg::new("rate")
+                .short('r')
+                .long("rate")
+                .default_value("1")
+                .value_parser(rate)
+                .help("Messages per second, up to a million; 0 sends as fast as possible"),
+        )
+        .arg(
+            Arg::new("message")
+                .long("message")
+                .default_value("This is message {n}.")
+                .help("Body to send, with {n} replaced by the message number"),
+        )
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .default_value("text")
+                .value_parser(OUTPUTS)
+                .help("How received messages are printed"),
+        )
+        .arg(
+            Arg::new("ca-file")
+                .long("ca-file")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("PEM file with the CA to trust for wss:// instead of the Mozilla roots"),
+        )
+        .arg(
+            Arg::new("no-compression")
+                .long("no-compression")
+                .action(ArgAction::SetTrue)
+                .help("Don't offer message compression"),
+        )
+        .arg(
+            Arg::new("token")
+                .long("token")
+                .help("Bearer token for servers that ask for one"),
+        )
+        .get_matches();
 
-    let stream = TcpStream::connect(addr).await?;
+    let url = matches.get_one::<String>("url").unwrap();
+    let defaults = Config::default();
+    let config = Config {
+        tls: match matches.get_one::<PathBuf>("ca-file") {
+            Some(ca_file) => Some(tls::with_roots(&[ca_file])?),
+            None => None,
+        },
+        compression: defaults
+            .compression
+            .filter(|_| !matches.get_flag("no-compression")),
+        token: matches.get_one::<String>("token").cloned(),
+        ..defaults
+    };
+    let output = matches.get_one::<String>("output").unwrap().clone();
 
-    println!("Connected to {:?}", addr);
+    eprintln!("Connecting to {:?}", url);
+    if let Some(path) = matches.get_one::<PathBuf>("send-file") {
+        let chunk_size = *matches.get_one::<u64>("chunk-size").unwrap();
+        send_file(url, config, path, chunk_size, &output).await?;
+        eprintln!("Closing...");
+        return Ok(());
+    }
 
-    let (mut ws_stream, stuff) = client_async(&url, stream).await?;
+    let (mut sink, stream) = Client::with_config(url.as_str(), config).split();
+    if matches.get_flag("interactive") {
+        let reader = tokio::spawn(print_all(stream, output));
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        while let Some(line) = lines.next_line().await? {
+            sink.send(data(&line)).await?;
+        }
+        sink.close().await?;
+        reader.await?;
+    } else {
+        let reader = tokio::spawn(print_all(stream, output));
+        let count = *matches.get_one::<u64>("count").unwrap();
+        let rate = *matches.get_one::<f64>("rate").unwrap();
+        let template = matches.get_one::<String>("message").unwrap();
 
-    println!("Handshake successful. {:?},", stuff);
+        let mut ticks = (rate > 0.0).then(|| interval(Duration::from_secs_f64(1.0 / rate)));
+        if let Some(ticks) = &mut ticks {
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        }
+        for n in (0..).take_while(|&n| count == 0 || n < count) {
+            if let Some(ticks) = &mut ticks {
+                ticks.tick().await;
+            }
+            let text = template.replace("{n}", &n.to_string());
+            sink.send(data(&text)).await?;
+            eprintln!("Message sent: {:?}", text);
+        }
 
-    // write loop for audio buffer length and maybe create a struct to wrap the buffer which will contain the details for the buffer
+        // Whatever is still queued goes out before the connection closes.
+        sink.close().await?;
+        reader.await?;
+    }
 
-    for n in 0..10 {
-        let text = format!("This is message {}.", n);
-        let msg = Message::Text(text.clone());
+    eprintln!("Closing...");
+    Ok(())
+}
 
-        ws_stream.send(msg).await?;
+/// The `--rate`s allowed besides 0, which sends as fast as possible.
+const RATES: std::ops::RangeInclusive<f64> = 1e-3..=1e6;
 
-        println!("Message sent: {:?}", text);
+fn rate(s: &str) -> Result<f64, String> {
+    let rate: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if rate == 0.0 || RATES.contains(&rate) {
+        Ok(rate)
+    } else {
+        Err(format!(
+            "expected 0 or between {} and {}",
+            RATES.start(),
+            RATES.end()
+        ))
+    }
+}
+
+/// A `data` envelope with `text` as its body.
+fn data(text: &str) -> Message {
+    let body = serde_json::from_str(text).unwrap_or_else(|_| serde_json::Value::from(text));
+    Envelope::data(body).to_message()
+}
 
-        if let Some(item) = ws_stream.next().await {
-            match item {
-                Ok(msg) => {
-                    match msg {
-                        Message::Text(text) => {
-                            println!("Received text message: {}", text);
-                        },
-                        Message::Close(frame) => {
-                            println!("Received close message: {:?}", frame);
+type Sink = SplitSink<Client, Message>;
+type Stream = SplitStream<Client>;
+
+async fn print_all(mut stream: Stream, output: String) {
+    while let Some(msg) = stream.next().await {
+        print(&msg, &output);
+    }
+}
 
-                            if let Err(e) = ws_stream.close(None).await {
-                                match e {
-                                    WsError::ConnectionClosed => (),
-                                    _ => {
-                                        println!("Error while closing: {}", e);
-                                        break;
-                                    },
-                                }
-                            }
+fn print(msg: &Message, output: &str) {
+    match (output, msg) {
+        ("json", Message::Text(text)) => {
+            println!("{}", serde_json::json!({"type": "text", "data": text}));
+        }
+        ("json", Message::Binary(data)) => {
+            println!(
+                "{}",
+                serde_json::json!({"type": "binary", "data": hex::encode(data)})
+            );
+        }
+        ("raw", Message::Text(text)) => println!("{}", text),
+        ("raw", Message::Binary(data)) => {
+            let mut stdout = std::io::stdout().lock();
+            let _ = stdout.write_all(data).and_then(|_| stdout.flush());
+        }
+        (_, Message::Text(text)) => match Envelope::<serde_json::Value>::from_json(text) {
+            Ok(Envelope::Data { body, .. }) => println!("Received: {}", body),
+            Ok(Envelope::Error { code, message }) => println!("Error ({}): {}", code, message),
+            Ok(Envelope::Ack { seq }) => println!("Ack {}", seq),
+            _ => println!("Received text message: {}", text),
+        },
+        (_, msg) => println!("Received message: {:?}", msg),
+    }
+}
 
-                            // println!("Sent close message.");
+/// Upload attempts in a row that may fail to get the file announced.
+const MAX_UPLOAD_ATTEMPTS: u32 = 5;
 
-                            println!("Closing...");
-                            return Ok(());
-                        },
-                        _ => (),
-                    }
-                },
-                Err(e) => {
-                    println!("Error receiving message: \n{0:?}\n{0}", e);
+/// Upload a file with the chat server's upload protocol.
+///
+/// Each attempt has a connection of its own that is not reconnected, since
+/// chunks queued when one drops would reach the next before the file is
+/// announced again. The server keeps what it got, so the next attempt
+/// announces the file afresh and sends only the rest.
+async fn send_file(
+    url: &str,
+    config: Config,
+    path: &Path,
+    chunk_size: u64,
+    output: &str,
+) -> Result<(), AnyError> {
+    let data = tokio::fs::read(path).await?;
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or("the file needs a UTF-8 name")?;
+    let header = serde_json::json!({
+        "type": "upload",
+        "name": name,
+        "size": data.len(),
+        "sha256": hex::encode(Sha256::digest(&data)),
+        "chunk_size": chunk_size,
+    });
+
+    let mut backoff = Backoff::new(config.initial_backoff, config.max_backoff);
+    let config = Config {
+        reconnect: false,
+        ..config
+    };
+    let mut failures = 0;
+    loop {
+        let (mut sink, mut stream) = Client::with_config(url, config.clone()).split();
+        match upload(&mut sink, &mut stream, &header, &data, chunk_size, output).await? {
+            Upload::Complete => {
+                sink.close().await?;
+                while let Some(msg) = stream.next().await {
+                    print(&msg, output);
+                }
+                return Ok(());
+            }
+            Upload::Dropped { announced } => {
+                failures = if announced { 0 } else { failures + 1 };
+                if failures >= MAX_UPLOAD_ATTEMPTS {
+                    return Err(format!("gave up on {} after {} attempts", name, failures).into());
                 }
+                eprintln!("Connection lost; announcing {} again", name);
+                tokio::time::sleep(backoff.next_delay()).await;
+            }
+        }
+    }
+}
+
+/// How one upload attempt ended, short of the server turning it down.
+enum Upload {
+    Complete,
+    /// The connection went away, after the server took the announcement or
+    /// before.
+    Dropped {
+        announced: bool,
+    },
+}
+
+/// Announce the file and send the chunks the server is missing.
+async fn upload(
+    sink: &mut Sink,
+    stream: &mut Stream,
+    header: &serde_json::Value,
+    data: &[u8],
+    chunk_size: u64,
+    output: &str,
+) -> Result<Upload, AnyError> {
+    let name = header["name"].as_str().unwrap_or_default();
+    sink.send(Envelope::data(header).to_message()).await?;
+
+    let next_seq = loop {
+        let Some(msg) = stream.next().await else {
+            return Ok(Upload::Dropped { announced: false });
+        };
+        print(&msg, output);
+        let Message::Text(text) = msg else { continue };
+        match Envelope::<serde_json::Value>::from_json(&text) {
+            Ok(Envelope::Data { body, .. }) if body["type"] == "upload_ready" => {
+                break body["next_seq"].as_u64().unwrap_or(0);
+            }
+            Ok(Envelope::Error { message, .. }) => {
+                return Err(format!("upload refused: {}", message).into());
             }
-        } else {
-            break;
+            _ => continue,
         }
+    };
+    if next_seq > 0 {
+        eprintln!("Resuming {} at chunk {}", name, next_seq);
+    }
 
-        sleep(Duration::from_secs(1)).await;
+    for (seq, chunk) in data
+        .chunks(chunk_size as usize)
+        .enumerate()
+        .skip(next_seq as usize)
+    {
+        let mut frame = (seq as u64).to_be_bytes().to_vec();
+        frame.extend_from_slice(chunk);
+        sink.send(Message::Binary(frame)).await?;
     }
+    let end = serde_json::json!({"type": "upload_end"});
+    sink.send(Envelope::data(end).to_message()).await?;
+    eprintln!("Sent {} ({} bytes)", name, data.len());
 
-    ws_stream.close(None).await?;
-
-    println!("Sent close message.");
-// repeated code
-    // while let Some(item) = ws_stream.next().await {
-    //     match item {
-    //         Ok(msg) => {
-    //             match msg {
-    //                 Message::Close(frame) => {
-    //                     println!("Received close message: {:?}", frame);
-
-    //                     break;
-    //                 },
-    //                 _ => (),
-    //             }
-    //         },
-    //         Err(e) => {
-    //             println!("Error receiving message: \n{0:?}\n{0}", e);
-    //         }
-    //     }
-    // }
-
-    println!("Closing...");
-    Ok(())
-}
\ No newline at end of file
+    // The server checks the hash before it answers.
+    loop {
+        let Some(msg) = stream.next().await else {
+            return Ok(Upload::Dropped { announced: true });
+        };
+        print(&msg, output);
+        let Message::Text(text) = msg else { continue };
+        match Envelope::<serde_json::Value>::from_json(&text) {
+            Ok(Envelope::Data { body, .. }) if body["type"] == "upload_complete" => {
+                return Ok(Upload::Complete);
+            }
+            Ok(Envelope::Error { message, .. }) => {
+                return Err(format!("upload failed: {}", message).into());
+            }
+            _ => continue,
+        }
+    }
+}
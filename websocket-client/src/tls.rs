@@ -0,0 +1,55 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore};
+
+/// A TLS config that trusts only the CA certificates in the given PEM files,
+/// for servers with self-signed or private certificates.
+///
+/// Without one, `wss://` connections are checked against the Mozilla roots
+/// built into the client.
+pub fn with_roots<P: AsRef<Path>>(ca_files: &[P]) -> std::io::Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    for path in ca_files {
+        let path = path.as_ref();
+        let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))?;
+        if certs.is_empty() {
+            return Err(invalid(format!("no certificates in {}", path.display())));
+        }
+        for cert in certs {
+            roots
+                .add(&Certificate(cert))
+                .map_err(|e| invalid(format!("{}: {}", path.display(), e)))?;
+        }
+    }
+
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// The TLS config for `wss://` connections without one of their own: the
+/// Mozilla roots built into the client.
+pub fn mozilla_roots() -> Arc<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Arc::new(config)
+}
+
+fn invalid(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,449 @@
+//! FLAC frames for `i16` samples.
+//!
+//! There is no stream header: each audio frame is a single FLAC frame whose
+//! header spells out the sample rate, block size and sample size, so it can
+//! be decoded on its own. Channels are coded independently, each as a
+//! constant, verbatim or fixed-prediction subframe, whichever is smallest,
+//! with one Rice partition for the residual.
+
+use std::io::Cursor;
+
+use claxon::frame::FrameReader;
+use websocket_protocol::audio::{Format, SampleFormat};
+
+/// The most samples per channel a frame header can give.
+const MAX_BLOCK_SIZE: usize = 65_535;
+
+/// The largest Rice parameter; 15 would mark an escaped partition.
+const MAX_RICE_PARAMETER: u32 = 14;
+
+/// Fixed predictors go up to order 4.
+const MAX_ORDER: usize = 4;
+
+pub struct Encoder {
+    format: Format,
+    frame_samples: usize,
+    /// The frame number for the next frame's header.
+    frame_number: u32,
+}
+
+impl Encoder {
+    pub fn new(format: Format, frame_samples: usize) -> Result<Encoder, String> {
+        check(format)?;
+        if frame_samples == 0 || frame_samples > MAX_BLOCK_SIZE {
+            return Err(format!(
+                "FLAC frames hold 1 to {} samples per channel, not {}",
+                MAX_BLOCK_SIZE, frame_samples
+            ));
+        }
+        Ok(Encoder {
+            format,
+            frame_samples,
+            frame_number: 0,
+        })
+    }
+
+    pub fn encode(&mut self, samples: &[u8]) -> Result<Vec<u8>, String> {
+        let channels = self.format.channels as usize;
+        let block_size = samples.len() / self.format.frame_bytes();
+        if block_size == 0 || block_size > self.frame_samples {
+            return Err(format!(
+                "frames hold 1 to {} samples per channel, not {}",
+                self.frame_samples, block_size
+            ));
+        }
+        let mut planar = vec![Vec::with_capacity(block_size); channels];
+        for (n, sample) in samples.chunks_exact(2).enumerate() {
+            planar[n % channels].push(i16::from_le_bytes([sample[0], sample[1]]) as i64);
+        }
+
+        let mut out = BitWriter::default();
+        self.header(&mut out, block_size);
+        for channel in &planar {
+            subframe(&mut out, channel);
+        }
+        let mut frame = out.finish();
+        let crc = crc16(&frame);
+        frame.extend_from_slice(&crc.to_be_bytes());
+        self.frame_number = (self.frame_number + 1) & 0x7fff_ffff;
+        Ok(frame)
+    }
+
+    fn header(&self, out: &mut BitWriter, block_size: usize) {
+        // Sync code, reserved bit, fixed block size.
+        out.write(0b1111_1111_1111_1000, 16);
+        // Block size and sample rate both follow the frame number.
+        out.write(0b0111, 4);
+        let rate = self.format.sample_rate;
+        if rate <= u16::MAX as u32 {
+            out.write(0b1101, 4);
+        } else {
+            out.write(0b1110, 4);
+        }
+        out.write(self.format.channels as u64 - 1, 4);
+        // 16 bits per sample, and a reserved bit.
+        out.write(0b1000, 4);
+        utf8(out, self.frame_number);
+        out.write(block_size as u64 - 1, 16);
+        if rate <= u16::MAX as u32 {
+            out.write(rate as u64, 16);
+        } else {
+            out.write(rate as u64 / 10, 16);
+        }
+        let crc = crc8(out.bytes());
+        out.write(crc as u64, 8);
+    }
+}
+
+pub struct Decoder {
+    format: Format,
+    /// Reused from one frame to the next.
+    buffer: Vec<i32>,
+}
+
+impl Decoder {
+    pub fn new(format: Format) -> Result<Decoder, String> {
+        check(format)?;
+        Ok(Decoder {
+            format,
+            buffer: Vec::new(),
+        })
+    }
+
+    pub fn decode(&mut self, payload: &[u8]) -> Result<Vec<u8>, String> {
+        let mut reader = FrameReader::new(Cursor::new(payload));
+        let buffer = std::mem::take(&mut self.buffer);
+        let block = reader
+            .read_next_or_eof(buffer)
+            .map_err(|e| format!("bad FLAC frame: {}", e))?
+            .ok_or("empty FLAC frame")?;
+        if block.channels() != self.format.channels as u32 {
+            return Err(format!(
+                "a FLAC frame has {} channels where the stream has {}",
+                block.channels(),
+                self.format.channels
+            ));
+        }
+
+        let mut samples = Vec::with_capacity(block.len() as usize * 2);
+        for n in 0..block.duration() {
+            for ch in 0..block.channels() {
+                let sample = i16::try_from(block.sample(ch, n))
+                    .map_err(|_| "a FLAC frame has samples wider than 16 bits")?;
+                samples.extend_from_slice(&sample.to_le_bytes());
+            }
+        }
+        self.buffer = block.into_buffer();
+        Ok(samples)
+    }
+}
+
+fn check(format: Format) -> Result<(), String> {
+    if format.sample_format != SampleFormat::I16 {
+        return Err(format!(
+            "FLAC is only used for i16 samples, not {}",
+            format.sample_format
+        ));
+    }
+    if format.channels > 8 {
+        return Err(format!(
+            "FLAC takes up to 8 channels, not {}",
+            format.channels
+        ));
+    }
+    let rate = format.sample_rate;
+    if rate > u16::MAX as u32 && (!rate.is_multiple_of(10) || rate / 10 > u16::MAX as u32) {
+        return Err(format!("FLAC frames cannot give a rate of {} Hz", rate));
+    }
+    Ok(())
+}
+
+/// One channel's subframe, in whichever encoding is smallest.
+fn subframe(out: &mut BitWriter, samples: &[i64]) {
+    if samples.iter().all(|&s| s == samples[0]) {
+        // Constant.
+        out.write(0x00, 8);
+        out.write_signed(samples[0], 16);
+        return;
+    }
+
+    let verbatim = 16 * samples.len() as u64;
+    let best = (0..=MAX_ORDER.min(samples.len() - 1))
+        .map(|order| {
+            let residual = residual(samples, order);
+            let (parameter, bits) = rice_parameter(&residual);
+            // Warm-up samples, and the coding method, partition order and
+            // parameter.
+            let size = 16 * order as u64 + 10 + bits;
+            (size, order, parameter, residual)
+        })
+        .min_by_key(|&(size, ..)| size);
+
+    match best {
+        Some((size, order, parameter, residual)) if size < verbatim => {
+            // Fixed, of this order.
+            out.write(0x10 | ((order as u64) << 1), 8);
+            for &sample in &samples[..order] {
+                out.write_signed(sample, 16);
+            }
+            // Rice coding with 4-bit parameters, in one partition.
+            out.write(0b00, 2);
+            out.write(0, 4);
+            out.write(parameter as u64, 4);
+            for r in residual {
+                let folded = zigzag(r);
+                let quotient = folded >> parameter;
+                for _ in 0..quotient {
+                    out.write(0, 1);
+                }
+                out.write(1, 1);
+                out.write(folded & ((1 << parameter) - 1), parameter);
+            }
+        }
+        _ => {
+            // Verbatim.
+            out.write(0x02, 8);
+            for &sample in samples {
+                out.write_signed(sample, 16);
+            }
+        }
+    }
+}
+
+/// What the fixed predictor of `order` leaves unexplained.
+fn residual(samples: &[i64], order: usize) -> Vec<i64> {
+    let s = samples;
+    (order..s.len())
+        .map(|i| match order {
+            0 => s[i],
+            1 => s[i] - s[i - 1],
+            2 => s[i] - 2 * s[i - 1] + s[i - 2],
+            3 => s[i] - 3 * s[i - 1] + 3 * s[i - 2] - s[i - 3],
+            _ => s[i] - 4 * s[i - 1] + 6 * s[i - 2] - 4 * s[i - 3] + s[i - 4],
+        })
+        .collect()
+}
+
+/// The Rice parameter that codes `residual` in the fewest bits, and how
+/// many that is.
+fn rice_parameter(residual: &[i64]) -> (u32, u64) {
+    let folded: Vec<u64> = residual.iter().map(|&r| zigzag(r)).collect();
+    (0..=MAX_RICE_PARAMETER)
+        .map(|k| {
+            let bits = folded.iter().map(|&f| (f >> k) + 1 + k as u64).sum();
+            (k, bits)
+        })
+        .min_by_key(|&(_, bits)| bits)
+        .unwrap()
+}
+
+fn zigzag(n: i64) -> u64 {
+    ((n << 1) ^ (n >> 63)) as u64
+}
+
+/// The frame number, in FLAC's UTF-8 like variable length code.
+fn utf8(out: &mut BitWriter, n: u32) {
+    if n < 0x80 {
+        out.write(n as u64, 8);
+        return;
+    }
+    // Each continuation byte carries 6 bits; the first carries the rest.
+    let mut continuation = 1;
+    while n >= 1 << (5 * continuation + 6) {
+        continuation += 1;
+    }
+    let lead = !0u8 << (7 - continuation);
+    out.write((lead as u64) | (n as u64 >> (6 * continuation)), 8);
+    for i in (0..continuation).rev() {
+        out.write(0x80 | ((n as u64 >> (6 * i)) & 0x3f), 8);
+    }
+}
+
+/// CRC-8 with polynomial x^8 + x^2 + x + 1, over the frame header.
+fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in bytes {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// CRC-16 with polynomial x^16 + x^15 + x^2 + 1, over the whole frame.
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in bytes {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Writes bits most significant first.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// Bits not yet making up a whole byte, in the low end.
+    pending: u64,
+    pending_bits: u32,
+}
+
+impl BitWriter {
+    /// Write the low `bits` bits of `value`, at most 32 of them.
+    fn write(&mut self, value: u64, bits: u32) {
+        self.pending = (self.pending << bits) | (value & ((1 << bits) - 1));
+        self.pending_bits += bits;
+        while self.pending_bits >= 8 {
+            self.pending_bits -= 8;
+            self.bytes.push((self.pending >> self.pending_bits) as u8);
+        }
+    }
+
+    /// Write `value` as a two's complement number of `bits` bits.
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64, bits);
+    }
+
+    /// The whole bytes written so far.
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// All that was written, padded with zeros to a whole byte.
+    fn finish(mut self) -> Vec<u8> {
+        if self.pending_bits > 0 {
+            self.write(0, 8 - self.pending_bits);
+        }
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signal::{from_bytes, noise, sine, to_bytes};
+
+    fn round_trip(format: Format, frames: &[Vec<i16>]) -> usize {
+        let longest = frames.iter().map(Vec::len).max().unwrap();
+        let frame_samples = longest / format.channels as usize;
+        let mut encoder = Encoder::new(format, frame_samples).unwrap();
+        let mut decoder = Decoder::new(format).unwrap();
+        let mut encoded = 0;
+        for samples in frames {
+            let payload = encoder.encode(&to_bytes(samples)).unwrap();
+            encoded += payload.len();
+            assert_eq!(&from_bytes(&decoder.decode(&payload).unwrap()), samples);
+        }
+        encoded
+    }
+
+    #[test]
+    fn generated_signals_round_trip_bit_exact() {
+        let stereo = Format {
+            sample_format: SampleFormat::I16,
+            channels: 2,
+            sample_rate: 48_000,
+        };
+        let tone = sine(440.0, 48_000, 2, 48_000);
+        let frames: Vec<Vec<i16>> = tone.chunks(960 * 2).map(<[i16]>::to_vec).collect();
+        let encoded = round_trip(stereo, &frames);
+        // A pure tone is very predictable.
+        assert!(encoded < tone.len() * 2 / 3, "{} bytes", encoded);
+
+        let mono = Format {
+            channels: 1,
+            ..stereo
+        };
+        let hiss = noise(4800);
+        let silence = vec![0; 480];
+        let extremes = [i16::MIN, i16::MAX].repeat(240);
+        // Short frames, down to a single sample, work too.
+        let frames = [
+            hiss.clone(),
+            silence,
+            extremes,
+            vec![-7],
+            hiss[..5].to_vec(),
+        ];
+        round_trip(mono, &frames);
+    }
+
+    #[test]
+    fn frames_carry_rates_and_numbers_beyond_a_byte() {
+        let format = Format {
+            sample_format: SampleFormat::I16,
+            channels: 1,
+            sample_rate: 96_000,
+        };
+        let mut encoder = Encoder::new(format, 96).unwrap();
+        let mut decoder = Decoder::new(format).unwrap();
+        let samples = sine(1000.0, 96_000, 1, 96);
+        for frame_number in [0, 200, 70_000, 0x7fff_fffe] {
+            encoder.frame_number = frame_number;
+            let payload = encoder.encode(&to_bytes(&samples)).unwrap();
+            assert_eq!(from_bytes(&decoder.decode(&payload).unwrap()), samples);
+        }
+
+        let mut payload = encoder.encode(&to_bytes(&samples)).unwrap();
+        let last = payload.len() - 3;
+        payload[last] ^= 1;
+        assert!(decoder.decode(&payload).is_err());
+        assert!(decoder.decode(&[]).is_err());
+        assert!(encoder.encode(&to_bytes(&[0; 97])).is_err());
+    }
+
+    #[test]
+    fn frames_make_a_stream_other_decoders_read() {
+        let format = Format {
+            sample_format: SampleFormat::I16,
+            channels: 2,
+            sample_rate: 44_100,
+        };
+        let block_size = 1024;
+        let tone = sine(440.0, 44_100, 2, block_size * 3);
+        let hiss = noise(block_size * 2);
+        let extremes = [i16::MIN, i16::MAX].repeat(block_size);
+        let frames: Vec<&[i16]> = tone
+            .chunks(block_size * 2)
+            .chain([&hiss[..], &[0; 2048], &extremes[..], &hiss[..10]])
+            .collect();
+
+        // A stream is "fLaC", a STREAMINFO block, and then the frames.
+        let samples: Vec<i16> = frames.concat();
+        let mut out = BitWriter::default();
+        out.write(u64::from(u32::from_be_bytes(*b"fLaC")), 32);
+        out.write(1, 1);
+        out.write(0, 7);
+        out.write(34, 24);
+        out.write(block_size as u64, 16);
+        out.write(block_size as u64, 16);
+        out.write(0, 24);
+        out.write(0, 24);
+        out.write(u64::from(format.sample_rate), 20);
+        out.write(u64::from(format.channels) - 1, 3);
+        out.write(15, 5);
+        out.write((samples.len() / 2) as u64, 36);
+        let mut stream = out.finish();
+        stream.extend_from_slice(&[0; 16]);
+        let mut encoder = Encoder::new(format, block_size).unwrap();
+        for frame in &frames {
+            stream.extend(encoder.encode(&to_bytes(frame)).unwrap());
+        }
+
+        let mut reader = claxon::FlacReader::new(Cursor::new(stream)).unwrap();
+        let decoded: Vec<i16> = reader.samples().map(|s| s.unwrap() as i16).collect();
+        assert_eq!(decoded, samples);
+    }
+}
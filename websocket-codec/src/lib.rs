@@ -0,0 +1,240 @@
+//! Compression for the samples in `websocket_protocol::audio` frames.
+//!
+//! An [`Encoder`] turns the raw samples of one frame into the payload for
+//! its [`Codec`], and a [`Decoder`] turns payloads back into raw samples:
+//! interleaved and little-endian, in the stream's sample format.
+//!
+//! - PCM passes the samples through.
+//! - FLAC makes each frame a FLAC frame of its own, which loses nothing.
+//! - Opus makes each frame one Opus packet. A short last frame is padded
+//!   with silence to the packet's length, so it decodes a little longer.
+//!   It needs the `opus` feature, on by default.
+
+mod flac;
+#[cfg(feature = "opus")]
+mod opus;
+
+use websocket_protocol::audio::{Codec, Format};
+
+/// The codecs an encoder or decoder can be made for.
+pub const SUPPORTED: &[Codec] = &[
+    Codec::Pcm,
+    Codec::Flac,
+    #[cfg(feature = "opus")]
+    Codec::Opus,
+];
+
+/// Why there is no Opus encoder or decoder without the `opus` feature.
+#[cfg(not(feature = "opus"))]
+const NO_OPUS: &str = "Opus is not compiled in; build with the opus feature";
+
+/// Encodes one stream's frames, in order.
+pub struct Encoder {
+    inner: EncoderInner,
+}
+
+enum EncoderInner {
+    Pcm,
+    Flac(flac::Encoder),
+    #[cfg(feature = "opus")]
+    Opus(opus::Encoder),
+}
+
+impl Encoder {
+    /// An encoder for frames of `frame_samples` samples per channel of
+    /// `format`. Fails if the codec cannot take that format or length.
+    pub fn new(codec: Codec, format: Format, frame_samples: usize) -> Result<Encoder, String> {
+        let inner = match codec {
+            Codec::Pcm => EncoderInner::Pcm,
+            Codec::Flac => EncoderInner::Flac(flac::Encoder::new(format, frame_samples)?),
+            #[cfg(feature = "opus")]
+            Codec::Opus => EncoderInner::Opus(opus::Encoder::new(format, frame_samples)?),
+            #[cfg(not(feature = "opus"))]
+            Codec::Opus => return Err(NO_OPUS.to_string()),
+        };
+        Ok(Encoder { inner })
+    }
+
+    pub fn codec(&self) -> Codec {
+        match self.inner {
+            EncoderInner::Pcm => Codec::Pcm,
+            EncoderInner::Flac(_) => Codec::Flac,
+            #[cfg(feature = "opus")]
+            EncoderInner::Opus(_) => Codec::Opus,
+        }
+    }
+
+    /// The payload for one frame of raw `samples`. A frame may be shorter
+    /// than the encoder was made for, but not longer.
+    pub fn encode(&mut self, samples: &[u8]) -> Result<Vec<u8>, String> {
+        match &mut self.inner {
+            EncoderInner::Pcm => Ok(samples.to_vec()),
+            EncoderInner::Flac(encoder) => encoder.encode(samples),
+            #[cfg(feature = "opus")]
+            EncoderInner::Opus(encoder) => encoder.encode(samples),
+        }
+    }
+}
+
+/// Decodes one stream's frames. Opus carries state from one packet to the
+/// next, so they have to come in order.
+pub struct Decoder {
+    inner: DecoderInner,
+}
+
+enum DecoderInner {
+    Pcm,
+    Flac(flac::Decoder),
+    #[cfg(feature = "opus")]
+    Opus(opus::Decoder),
+}
+
+impl Decoder {
+    /// A decoder for frames of `format`. Fails if the codec cannot carry it.
+    pub fn new(codec: Codec, format: Format) -> Result<Decoder, String> {
+        let inner = match codec {
+            Codec::Pcm => DecoderInner::Pcm,
+            Codec::Flac => DecoderInner::Flac(flac::Decoder::new(format)?),
+            #[cfg(feature = "opus")]
+            Codec::Opus => DecoderInner::Opus(opus::Decoder::new(format)?),
+            #[cfg(not(feature = "opus"))]
+            Codec::Opus => return Err(NO_OPUS.to_string()),
+        };
+        Ok(Decoder { inner })
+    }
+
+    /// The raw samples in one frame's `payload`.
+    pub fn decode(&mut self, payload: &[u8]) -> Result<Vec<u8>, String> {
+        match &mut self.inner {
+            DecoderInner::Pcm => Ok(payload.to_vec()),
+            DecoderInner::Flac(decoder) => decoder.decode(payload),
+            #[cfg(feature = "opus")]
+            DecoderInner::Opus(decoder) => decoder.decode(payload),
+        }
+    }
+}
+
+/// Generated signals for the round trip tests.
+#[cfg(test)]
+mod signal {
+    use std::f64::consts::TAU;
+
+    /// `len` samples per channel of a sine at `frequency` Hz, a little
+    /// out of phase on each channel, interleaved.
+    pub fn sine(frequency: f64, rate: u32, channels: u16, len: usize) -> Vec<i16> {
+        (0..len)
+            .flat_map(|n| {
+                (0..channels).map(move |ch| {
+                    let t = n as f64 / rate as f64;
+                    let phase = TAU * frequency * t + ch as f64;
+                    (phase.sin() * 0.5 * i16::MAX as f64).round() as i16
+                })
+            })
+            .collect()
+    }
+
+    /// Full scale white noise, from a fixed seed.
+    pub fn noise(len: usize) -> Vec<i16> {
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        (0..len)
+            .map(|_| {
+                // xorshift64
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 48) as i16
+            })
+            .collect()
+    }
+
+    pub fn to_bytes(samples: &[i16]) -> Vec<u8> {
+        samples.iter().flat_map(|s| s.to_le_bytes()).collect()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Vec<i16> {
+        bytes
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use websocket_protocol::audio::SampleFormat;
+
+    #[test]
+    fn pcm_passes_samples_through() {
+        let format = Format {
+            sample_format: SampleFormat::F32,
+            channels: 3,
+            sample_rate: 44_100,
+        };
+        let samples: Vec<u8> = (0..=255).collect();
+        let mut encoder = Encoder::new(Codec::Pcm, format, 441).unwrap();
+        let mut decoder = Decoder::new(Codec::Pcm, format).unwrap();
+        let payload = encoder.encode(&samples).unwrap();
+        assert_eq!(decoder.decode(&payload).unwrap(), samples);
+    }
+
+    #[test]
+    fn codecs_refuse_formats_they_cannot_carry() {
+        let float = Format {
+            sample_format: SampleFormat::F32,
+            channels: 1,
+            sample_rate: 48_000,
+        };
+        assert!(Encoder::new(Codec::Flac, float, 960).is_err());
+        assert!(Decoder::new(Codec::Flac, float).is_err());
+
+        let cd = Format {
+            sample_format: SampleFormat::I16,
+            channels: 2,
+            sample_rate: 44_100,
+        };
+        assert!(Encoder::new(Codec::Flac, cd, 882).is_ok());
+    }
+
+    #[cfg(feature = "opus")]
+    #[test]
+    fn opus_refuses_formats_it_cannot_carry() {
+        let float = Format {
+            sample_format: SampleFormat::F32,
+            channels: 1,
+            sample_rate: 48_000,
+        };
+        assert!(Encoder::new(Codec::Opus, float, 960).is_ok());
+
+        let cd = Format {
+            sample_format: SampleFormat::I16,
+            channels: 2,
+            sample_rate: 44_100,
+        };
+        assert!(Encoder::new(Codec::Opus, cd, 882).is_err());
+        assert!(Decoder::new(Codec::Opus, cd).is_err());
+        // Opus only takes frames of 2.5 to 60 ms.
+        let wideband = Format {
+            sample_rate: 16_000,
+            ..cd
+        };
+        assert!(Encoder::new(Codec::Opus, wideband, 320).is_ok());
+        assert!(Encoder::new(Codec::Opus, wideband, 300).is_err());
+    }
+
+    #[cfg(not(feature = "opus"))]
+    #[test]
+    fn opus_can_be_left_out() {
+        let format = Format {
+            sample_format: SampleFormat::F32,
+            channels: 1,
+            sample_rate: 48_000,
+        };
+        assert!(!SUPPORTED.contains(&Codec::Opus));
+        assert_eq!(
+            Encoder::new(Codec::Opus, format, 960).err().unwrap(),
+            NO_OPUS
+        );
+        assert_eq!(Decoder::new(Codec::Opus, format).err().unwrap(), NO_OPUS);
+    }
+}
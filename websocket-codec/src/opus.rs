@@ -0,0 +1,222 @@
+//! Opus packets, at [`BITRATE`] per channel.
+
+use audiopus::coder::{Decoder as OpusDecoder, Encoder as OpusEncoder};
+use audiopus::packet::Packet;
+use audiopus::{Application, Bitrate, Channels, MutSignals, SampleRate};
+use websocket_protocol::audio::{Format, SampleFormat};
+
+/// Bits per second for each channel, enough for music to sound clean.
+pub const BITRATE: i32 = 64_000;
+
+/// The packet lengths Opus takes, in units of 2.5 ms.
+const FRAME_UNITS: [usize; 6] = [1, 2, 4, 8, 16, 24];
+
+/// Room for the largest packet, as the Opus docs recommend.
+const MAX_PACKET: usize = 4000;
+
+/// The longest a packet can play for, in milliseconds.
+const MAX_PACKET_MS: usize = 120;
+
+pub struct Encoder {
+    encoder: OpusEncoder,
+    format: Format,
+    frame_samples: usize,
+}
+
+impl Encoder {
+    pub fn new(format: Format, frame_samples: usize) -> Result<Encoder, String> {
+        let (rate, channels) = check(format)?;
+        let unit = format.sample_rate as usize / 400;
+        if !frame_samples.is_multiple_of(unit) || !FRAME_UNITS.contains(&(frame_samples / unit)) {
+            return Err(format!(
+                "Opus frames are 2.5, 5, 10, 20, 40 or 60 ms, not {} samples at {} Hz",
+                frame_samples, format.sample_rate
+            ));
+        }
+        let mut encoder = OpusEncoder::new(rate, channels, Application::Audio)
+            .map_err(|e| format!("cannot start Opus: {}", e))?;
+        encoder
+            .set_bitrate(Bitrate::BitsPerSecond(BITRATE * format.channels as i32))
+            .map_err(|e| format!("cannot set the Opus bitrate: {}", e))?;
+        Ok(Encoder {
+            encoder,
+            format,
+            frame_samples,
+        })
+    }
+
+    /// One packet. A short frame is padded with silence.
+    pub fn encode(&mut self, samples: &[u8]) -> Result<Vec<u8>, String> {
+        let len = self.frame_samples * self.format.channels as usize;
+        let mut packet = vec![0; MAX_PACKET];
+        let size = match self.format.sample_format {
+            SampleFormat::I16 => {
+                let mut input: Vec<i16> = samples
+                    .chunks_exact(2)
+                    .map(|s| i16::from_le_bytes([s[0], s[1]]))
+                    .collect();
+                pad(&mut input, len)?;
+                self.encoder.encode(&input, &mut packet)
+            }
+            SampleFormat::F32 => {
+                let mut input: Vec<f32> = samples
+                    .chunks_exact(4)
+                    .map(|s| f32::from_le_bytes(s.try_into().unwrap()))
+                    .collect();
+                pad(&mut input, len)?;
+                self.encoder.encode_float(&input, &mut packet)
+            }
+        };
+        let size = size.map_err(|e| format!("cannot encode Opus: {}", e))?;
+        packet.truncate(size);
+        Ok(packet)
+    }
+}
+
+pub struct Decoder {
+    decoder: OpusDecoder,
+    format: Format,
+}
+
+impl Decoder {
+    pub fn new(format: Format) -> Result<Decoder, String> {
+        let (rate, channels) = check(format)?;
+        let decoder =
+            OpusDecoder::new(rate, channels).map_err(|e| format!("cannot start Opus: {}", e))?;
+        Ok(Decoder { decoder, format })
+    }
+
+    pub fn decode(&mut self, payload: &[u8]) -> Result<Vec<u8>, String> {
+        let packet: Packet = payload
+            .try_into()
+            .map_err(|e| format!("bad Opus packet: {}", e))?;
+        let channels = self.format.channels as usize;
+        let len = self.format.sample_rate as usize / 1000 * MAX_PACKET_MS * channels;
+        let decoded = match self.format.sample_format {
+            SampleFormat::I16 => {
+                let mut output = vec![0i16; len];
+                let signals: MutSignals<i16> = (&mut output[..]).try_into().unwrap();
+                self.decoder
+                    .decode(Some(packet), signals, false)
+                    .map(|n| to_bytes(&output[..n * channels], i16::to_le_bytes))
+            }
+            SampleFormat::F32 => {
+                let mut output = vec![0f32; len];
+                let signals: MutSignals<f32> = (&mut output[..]).try_into().unwrap();
+                self.decoder
+                    .decode_float(Some(packet), signals, false)
+                    .map(|n| to_bytes(&output[..n * channels], f32::to_le_bytes))
+            }
+        };
+        decoded.map_err(|e| format!("bad Opus packet: {}", e))
+    }
+}
+
+fn check(format: Format) -> Result<(SampleRate, Channels), String> {
+    let rate = SampleRate::try_from(format.sample_rate as i32).map_err(|_| {
+        format!(
+            "Opus runs at 8, 12, 16, 24 or 48 kHz, not {} Hz",
+            format.sample_rate
+        )
+    })?;
+    let channels = match format.channels {
+        1 => Channels::Mono,
+        2 => Channels::Stereo,
+        n => return Err(format!("Opus takes one or two channels, not {}", n)),
+    };
+    Ok((rate, channels))
+}
+
+fn pad<T: Default + Clone>(samples: &mut Vec<T>, len: usize) -> Result<(), String> {
+    if samples.is_empty() || samples.len() > len {
+        return Err(format!(
+            "frames hold 1 to {} samples, not {}",
+            len,
+            samples.len()
+        ));
+    }
+    samples.resize(len, T::default());
+    Ok(())
+}
+
+fn to_bytes<T: Copy, const N: usize>(samples: &[T], f: fn(T) -> [u8; N]) -> Vec<u8> {
+    samples.iter().flat_map(|&s| f(s)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signal::{from_bytes, sine, to_bytes};
+
+    /// Signal to noise ratio of `decoded` against `original`, in dB, at the
+    /// lag that lines them up best, since Opus delays what it decodes.
+    fn snr(original: &[i16], decoded: &[i16], channels: usize) -> f64 {
+        (0..=480)
+            .map(|lag| {
+                let shifted = &decoded[lag * channels..];
+                let (mut signal, mut noise) = (0.0, 0.0);
+                for (&o, &d) in original.iter().zip(shifted) {
+                    signal += (o as f64).powi(2);
+                    noise += (o as f64 - d as f64).powi(2);
+                }
+                10.0 * (signal / noise).log10()
+            })
+            .fold(f64::MIN, f64::max)
+    }
+
+    #[test]
+    fn a_tone_survives_within_bounds() {
+        for channels in [1, 2] {
+            let format = Format {
+                sample_format: SampleFormat::I16,
+                channels,
+                sample_rate: 48_000,
+            };
+            let channels = channels as usize;
+            let mut encoder = Encoder::new(format, 960).unwrap();
+            let mut decoder = Decoder::new(format).unwrap();
+
+            let tone = sine(440.0, 48_000, format.channels, 48_000);
+            let mut decoded = Vec::new();
+            let mut encoded = 0;
+            for frame in tone.chunks(960 * channels) {
+                let packet = encoder.encode(&to_bytes(frame)).unwrap();
+                encoded += packet.len();
+                decoded.extend(from_bytes(&decoder.decode(&packet).unwrap()));
+            }
+            assert_eq!(decoded.len(), tone.len());
+            // A second at the bitrate, give or take the rate control.
+            let expected = BITRATE as usize / 8 * channels;
+            assert!(encoded < expected * 3 / 2, "{} bytes", encoded);
+
+            // Skip the first frame, while the encoder settles.
+            let settled = 960 * channels;
+            let snr = snr(
+                &tone[settled..tone.len() - settled],
+                &decoded[settled..],
+                channels,
+            );
+            assert!(snr > 30.0, "{} channels: SNR {:.1} dB", channels, snr);
+        }
+    }
+
+    #[test]
+    fn float_samples_and_short_last_frames() {
+        let format = Format {
+            sample_format: SampleFormat::F32,
+            channels: 1,
+            sample_rate: 16_000,
+        };
+        let mut encoder = Encoder::new(format, 320).unwrap();
+        let mut decoder = Decoder::new(format).unwrap();
+        let short: Vec<u8> = [0.25f32; 100]
+            .iter()
+            .flat_map(|s| s.to_le_bytes())
+            .collect();
+        let packet = encoder.encode(&short).unwrap();
+        // Padded out to the whole 20 ms.
+        assert_eq!(decoder.decode(&packet).unwrap().len(), 320 * 4);
+        assert!(encoder.encode(&[0; 321 * 4]).is_err());
+        assert!(decoder.decode(&[]).is_err());
+    }
+}
@@ -0,0 +1,1132 @@
+//! permessage-deflate (RFC 7692) for the websocket crates.
+//!
+//! Messages are compressed the way RFC 7692 does it: raw DEFLATE with a sync
+//! flush and the trailing `00 00 ff ff` dropped, optionally keeping the
+//! compression context from one message to the next, and the first frame of
+//! a compressed message has RSV1 set.
+//!
+//! tungstenite 0.17 fails any frame with a reserved bit set, so it cannot
+//! carry the extension itself. A [`DeflateStream`] goes between the socket
+//! and tungstenite instead: it reads what the server agreed to from the
+//! handshake response, then compresses the data frames tungstenite writes
+//! and inflates the compressed ones before tungstenite reads them. Above it
+//! messages are plain; on the wire they are what browsers and other RFC 7692
+//! peers send and expect.
+//!
+//! Compression always uses the full 32 KiB window, so an offer asking the
+//! server for a smaller one is declined.
+
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::http::header::SEC_WEBSOCKET_EXTENSIONS;
+use tokio_tungstenite::tungstenite::http::{HeaderValue, Request, Response};
+use tokio_tungstenite::tungstenite::protocol::Role;
+
+/// The extension token in `Sec-WebSocket-Extensions`.
+pub const EXTENSION: &str = "permessage-deflate";
+
+/// The largest message inflated, like tungstenite's own default limit.
+pub const MAX_MESSAGE_SIZE: usize = 64 << 20;
+
+/// What RFC 7692 has the sender strip from the end of each message.
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// The longest handshake request or response let through.
+const MAX_HEAD: usize = 64 << 10;
+
+/// How many bytes may wait for the socket before writes do too.
+const MAX_QUEUED: usize = 64 << 10;
+
+/// As many headers as tungstenite reads in a handshake.
+const MAX_HEADERS: usize = 124;
+
+const FIN: u8 = 0x80;
+const RSV1: u8 = 0x40;
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+
+/// What one side asks for. The context takeover flags are negotiated; the
+/// threshold is up to each sender.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The server starts every message with a fresh compression context.
+    pub server_no_context_takeover: bool,
+    /// The client starts every message with a fresh compression context.
+    pub client_no_context_takeover: bool,
+    /// Messages shorter than this many bytes are not compressed.
+    pub threshold: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+            threshold: 64,
+        }
+    }
+}
+
+/// The outcome of a negotiation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Params {
+    pub server_no_context_takeover: bool,
+    pub client_no_context_takeover: bool,
+}
+
+impl Params {
+    /// Parse one extension from a client's offer, or from the server's
+    /// answer when `offer` is false. `None` if it is not ours, or asks for
+    /// something we can't do.
+    fn parse(extension: &str, offer: bool) -> Option<Params> {
+        let mut parts = extension.split(';').map(str::trim);
+        if !parts.next()?.eq_ignore_ascii_case(EXTENSION) {
+            return None;
+        }
+        let mut params = Params::default();
+        let mut seen = Vec::new();
+        for param in parts {
+            let (name, value) = match param.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (param, None),
+            };
+            if seen.contains(&name) {
+                return None;
+            }
+            seen.push(name);
+            let bits = value.map(|value| value.parse().ok().filter(|bits| (8..=15).contains(bits)));
+            match (name, bits) {
+                ("server_no_context_takeover", None) => params.server_no_context_takeover = true,
+                ("client_no_context_takeover", None) => params.client_no_context_takeover = true,
+                // Any window inflates with ours, but we only deflate with the
+                // full one.
+                ("server_max_window_bits", Some(Some(bits))) if !offer || bits == 15 => {}
+                // The client could take a limit on its window; we never set one.
+                ("client_max_window_bits", None | Some(Some(_))) if offer => {}
+                _ => return None,
+            }
+        }
+        Some(params)
+    }
+
+    fn header(&self) -> String {
+        let mut header = EXTENSION.to_string();
+        if self.server_no_context_takeover {
+            header.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            header.push_str("; client_no_context_takeover");
+        }
+        header
+    }
+}
+
+impl Config {
+    fn params(&self) -> Params {
+        Params {
+            server_no_context_takeover: self.server_no_context_takeover,
+            client_no_context_takeover: self.client_no_context_takeover,
+        }
+    }
+
+    /// Offer the extension in a client's handshake request.
+    pub fn offer<T>(&self, request: &mut Request<T>) {
+        let header = HeaderValue::from_str(&self.params().header()).unwrap();
+        request
+            .headers_mut()
+            .append(SEC_WEBSOCKET_EXTENSIONS, header);
+    }
+
+    /// Accept the first offer we can take, answering in `response`. Either
+    /// side asking for no context takeover gets it.
+    pub fn accept<T, U>(&self, request: &Request<T>, response: &mut Response<U>) -> Option<Params> {
+        let headers = request.headers().get_all(SEC_WEBSOCKET_EXTENSIONS);
+        let offered = extensions(headers.iter().map(HeaderValue::as_bytes))
+            .find_map(|extension| Params::parse(extension, true))?;
+        let params = Params {
+            server_no_context_takeover: offered.server_no_context_takeover
+                || self.server_no_context_takeover,
+            client_no_context_takeover: offered.client_no_context_takeover
+                || self.client_no_context_takeover,
+        };
+        let header = HeaderValue::from_str(&params.header()).unwrap();
+        response
+            .headers_mut()
+            .append(SEC_WEBSOCKET_EXTENSIONS, header);
+        Some(params)
+    }
+
+    /// Read the server's answer to an offer. `None` means it declined.
+    pub fn accepted<T>(&self, response: &Response<T>) -> Result<Option<Params>, Error> {
+        let headers = response.headers().get_all(SEC_WEBSOCKET_EXTENSIONS);
+        answer(headers.iter().map(HeaderValue::as_bytes))
+    }
+}
+
+fn extensions<'a>(headers: impl IntoIterator<Item = &'a [u8]>) -> impl Iterator<Item = &'a str> {
+    headers
+        .into_iter()
+        .filter_map(|value| std::str::from_utf8(value).ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|extension| !extension.is_empty())
+}
+
+/// The extension a server answered with, failing on anything not offered.
+fn answer<'a>(headers: impl IntoIterator<Item = &'a [u8]>) -> Result<Option<Params>, Error> {
+    let mut accepted = None;
+    for extension in extensions(headers) {
+        match Params::parse(extension, false) {
+            Some(params) if accepted.is_none() => accepted = Some(params),
+            _ => return Err(Error::Negotiation(extension.to_string())),
+        }
+    }
+    Ok(accepted)
+}
+
+/// What went wrong between the socket and tungstenite. The stream fails
+/// with it as an `InvalidData` I/O error.
+#[derive(Debug)]
+pub enum Error {
+    /// The server answered with an extension that was not offered.
+    Negotiation(String),
+    /// Frames that break RFC 6455 or RFC 7692 in a way tungstenite can't see.
+    Frame(&'static str),
+    Inflate(flate2::DecompressError),
+    /// A frame, or an inflated message, is larger than [`MAX_MESSAGE_SIZE`].
+    TooLarge,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Negotiation(extension) => {
+                write!(f, "server accepted an unexpected extension: {}", extension)
+            }
+            Error::Frame(e) => write!(f, "invalid frame: {}", e),
+            Error::Inflate(e) => write!(f, "cannot inflate message: {}", e),
+            Error::TooLarge => write!(f, "message is too large"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// A socket speaking permessage-deflate underneath tungstenite.
+///
+/// Wrap the socket before the handshake. The handshake passes through, and
+/// if the server's response turns the extension on, frames are compressed
+/// and inflated from then on; if not, every byte passes through as is.
+pub struct DeflateStream<S> {
+    inner: S,
+    role: Role,
+    threshold: usize,
+    deflate: Negotiation,
+    /// From the peer to tungstenite.
+    incoming: Half,
+    /// From tungstenite to the peer.
+    outgoing: Half,
+    eof: bool,
+}
+
+enum Negotiation {
+    /// The server has not answered yet.
+    Pending,
+    Off,
+    On(Deflate),
+}
+
+/// One direction of a [`DeflateStream`].
+#[derive(Default)]
+struct Half {
+    /// Past the HTTP head of the handshake.
+    framing: bool,
+    /// Bytes not handled yet, such as the start of a frame.
+    input: Vec<u8>,
+    /// Bytes handled, to be passed on from `sent`.
+    output: Vec<u8>,
+    sent: usize,
+    /// The message whose frames are still coming, if it has more than one.
+    fragmented: Option<Fragmented>,
+}
+
+enum Fragmented {
+    /// Passed on frame by frame.
+    Plain,
+    /// Gathered to inflate when the last frame comes.
+    Deflated {
+        opcode: u8,
+        mask: Option<[u8; 4]>,
+        data: Vec<u8>,
+    },
+}
+
+impl<S> DeflateStream<S> {
+    /// Wrap `inner` for one end of a connection. Messages shorter than
+    /// `threshold` bytes are sent uncompressed.
+    pub fn new(inner: S, role: Role, threshold: usize) -> Self {
+        DeflateStream {
+            inner,
+            role,
+            threshold,
+            deflate: Negotiation::Pending,
+            incoming: Half::default(),
+            outgoing: Half::default(),
+            eof: false,
+        }
+    }
+
+    /// The parameters agreed on, once the handshake is done and if the
+    /// extension is on.
+    pub fn params(&self) -> Option<Params> {
+        match &self.deflate {
+            Negotiation::On(deflate) => Some(deflate.params),
+            _ => None,
+        }
+    }
+
+    /// Handle what one half can of its input.
+    fn process(&mut self, incoming: bool) -> Result<(), Error> {
+        let half = if incoming {
+            &mut self.incoming
+        } else {
+            &mut self.outgoing
+        };
+        if !half.framing {
+            let Some(end) = half.input.windows(4).position(|w| w == b"\r\n\r\n") else {
+                if half.input.len() > MAX_HEAD {
+                    return Err(Error::Frame("handshake too long"));
+                }
+                return Ok(());
+            };
+            let head: Vec<u8> = half.input.drain(..end + 4).collect();
+            // The server's response says what was agreed.
+            if incoming == (self.role == Role::Client) {
+                self.deflate = match agreed(&head)? {
+                    Some(params) => {
+                        Negotiation::On(Deflate::new(params, self.role, self.threshold))
+                    }
+                    None => Negotiation::Off,
+                };
+            }
+            half.output.extend_from_slice(&head);
+            half.framing = true;
+        }
+        match &mut self.deflate {
+            Negotiation::Pending => {}
+            Negotiation::Off => half.output.append(&mut half.input),
+            Negotiation::On(deflate) => {
+                while let Some(frame) = Frame::parse(&half.input)? {
+                    let bytes = half.input.drain(..frame.header + frame.len).collect();
+                    if incoming {
+                        half.inflate(&mut deflate.decoder, frame, bytes)?;
+                    } else {
+                        half.deflate(&mut deflate.encoder, frame, bytes);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: AsyncWrite + Unpin> DeflateStream<S> {
+    /// Write out everything queued.
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let half = &mut self.outgoing;
+        while half.sent < half.output.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &half.output[half.sent..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            half.sent += n;
+        }
+        half.output.clear();
+        half.sent = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for DeflateStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            let half = &mut this.incoming;
+            if half.sent < half.output.len() {
+                let n = buf.remaining().min(half.output.len() - half.sent);
+                buf.put_slice(&half.output[half.sent..half.sent + n]);
+                half.sent += n;
+                return Poll::Ready(Ok(()));
+            }
+            half.output.clear();
+            half.sent = 0;
+            this.process(true)?;
+            if !this.incoming.output.is_empty() {
+                continue;
+            }
+            if this.eof {
+                return Poll::Ready(Ok(()));
+            }
+            let mut chunk = [0; 8 << 10];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            this.eof = chunk.filled().is_empty();
+            this.incoming.input.extend_from_slice(chunk.filled());
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for DeflateStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.outgoing.output.len() - this.outgoing.sent >= MAX_QUEUED {
+            ready!(this.poll_send(cx))?;
+        }
+        this.outgoing.input.extend_from_slice(buf);
+        this.process(false)?;
+        // Start sending now; a flush waits for the rest.
+        if let Poll::Ready(Err(e)) = this.poll_send(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// What a server's handshake response agreed to, from its raw HTTP head.
+fn agreed(head: &[u8]) -> Result<Option<Params>, Error> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut response = httparse::Response::new(&mut headers);
+    match response.parse(head) {
+        Ok(httparse::Status::Complete(_)) if response.code == Some(101) => {}
+        // No connection, so nothing to compress; tungstenite says why.
+        _ => return Ok(None),
+    }
+    answer(
+        response
+            .headers
+            .iter()
+            .filter(|header| {
+                header
+                    .name
+                    .eq_ignore_ascii_case(SEC_WEBSOCKET_EXTENSIONS.as_str())
+            })
+            .map(|header| header.value),
+    )
+}
+
+/// The head of a frame.
+struct Frame {
+    fin: bool,
+    rsv1: bool,
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    /// The length of the head.
+    header: usize,
+    /// The length of the payload.
+    len: usize,
+}
+
+impl Frame {
+    /// Parse the frame at the start of `buf`, or `None` until all of it is
+    /// there.
+    fn parse(buf: &[u8]) -> Result<Option<Frame>, Error> {
+        let Some(&[first, second]) = buf.get(..2) else {
+            return Ok(None);
+        };
+        let (len, mut header) = match second & 0x7f {
+            126 => match buf.get(2..4) {
+                Some(len) => (u16::from_be_bytes(len.try_into().unwrap()) as u64, 4),
+                None => return Ok(None),
+            },
+            127 => match buf.get(2..10) {
+                Some(len) => (u64::from_be_bytes(len.try_into().unwrap()), 10),
+                None => return Ok(None),
+            },
+            len => (len as u64, 2),
+        };
+        if len > MAX_MESSAGE_SIZE as u64 {
+            return Err(Error::TooLarge);
+        }
+        let mask = match second & 0x80 {
+            0 => None,
+            _ => match buf.get(header..header + 4) {
+                Some(mask) => {
+                    header += 4;
+                    Some(mask.try_into().unwrap())
+                }
+                None => return Ok(None),
+            },
+        };
+        let len = len as usize;
+        if buf.len() < header + len {
+            return Ok(None);
+        }
+        Ok(Some(Frame {
+            fin: first & FIN != 0,
+            rsv1: first & RSV1 != 0,
+            opcode: first & 0x0f,
+            mask,
+            header,
+            len,
+        }))
+    }
+}
+
+/// Append a frame, masking the payload if there is a mask.
+fn put_frame(out: &mut Vec<u8>, first: u8, mask: Option<[u8; 4]>, payload: &[u8]) {
+    out.push(first);
+    let masked = if mask.is_some() { 0x80 } else { 0 };
+    match payload.len() {
+        len @ 0..=125 => out.push(masked | len as u8),
+        len @ 126..=0xffff => {
+            out.push(masked | 126);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            out.push(masked | 127);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    match mask {
+        Some(mask) => {
+            out.extend_from_slice(&mask);
+            let start = out.len();
+            out.extend_from_slice(payload);
+            apply_mask(&mut out[start..], mask);
+        }
+        None => out.extend_from_slice(payload),
+    }
+}
+
+/// Mask or unmask a payload; it is the same thing.
+fn apply_mask(data: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+impl Half {
+    /// Pass a frame on as it is, keeping track of fragmented messages.
+    fn pass(&mut self, frame: &Frame, mut bytes: Vec<u8>) {
+        match frame.opcode {
+            TEXT | BINARY if !frame.fin => self.fragmented = Some(Fragmented::Plain),
+            CONTINUATION if frame.fin => self.fragmented = None,
+            _ => {}
+        }
+        self.output.append(&mut bytes);
+    }
+
+    /// Take a frame from the peer, inflating compressed messages once their
+    /// last frame is in. Control frames and anything tungstenite will reject
+    /// pass through.
+    fn inflate(
+        &mut self,
+        decoder: &mut Decoder,
+        frame: Frame,
+        mut bytes: Vec<u8>,
+    ) -> Result<(), Error> {
+        let deflated = match (frame.opcode, &self.fragmented) {
+            (TEXT | BINARY, Some(_)) => {
+                return Err(Error::Frame("a message started before the last one ended"));
+            }
+            (TEXT | BINARY, None) => frame.rsv1,
+            (CONTINUATION, Some(Fragmented::Deflated { .. })) if frame.rsv1 => {
+                return Err(Error::Frame("RSV1 set on a continuation frame"));
+            }
+            (CONTINUATION, Some(Fragmented::Deflated { .. })) => true,
+            _ => false,
+        };
+        if !deflated {
+            self.pass(&frame, bytes);
+            return Ok(());
+        }
+
+        let mut payload = bytes.split_off(frame.header);
+        if let Some(mask) = frame.mask {
+            apply_mask(&mut payload, mask);
+        }
+        if frame.opcode != CONTINUATION {
+            self.fragmented = Some(Fragmented::Deflated {
+                opcode: frame.opcode,
+                mask: frame.mask,
+                data: Vec::new(),
+            });
+        }
+        let Some(Fragmented::Deflated { opcode, mask, data }) = &mut self.fragmented else {
+            unreachable!("started above or by an earlier frame");
+        };
+        if data.len() + payload.len() > MAX_MESSAGE_SIZE {
+            return Err(Error::TooLarge);
+        }
+        data.extend_from_slice(&payload);
+        if frame.fin {
+            // Masked as the first frame was, for tungstenite to check.
+            put_frame(
+                &mut self.output,
+                FIN | *opcode,
+                *mask,
+                &decoder.inflate(data)?,
+            );
+            self.fragmented = None;
+        }
+        Ok(())
+    }
+
+    /// Take a frame from tungstenite, compressing it if it holds a whole
+    /// message at least as long as the threshold.
+    fn deflate(&mut self, encoder: &mut Encoder, frame: Frame, mut bytes: Vec<u8>) {
+        let whole = frame.fin && !frame.rsv1 && self.fragmented.is_none();
+        if !(matches!(frame.opcode, TEXT | BINARY) && whole && frame.len >= encoder.threshold) {
+            self.pass(&frame, bytes);
+            return;
+        }
+        let mut payload = bytes.split_off(frame.header);
+        if let Some(mask) = frame.mask {
+            apply_mask(&mut payload, mask);
+        }
+        let compressed = encoder.deflate(&payload);
+        put_frame(
+            &mut self.output,
+            FIN | RSV1 | frame.opcode,
+            frame.mask,
+            &compressed,
+        );
+    }
+}
+
+/// The compression state of one end of a connection.
+struct Deflate {
+    params: Params,
+    encoder: Encoder,
+    decoder: Decoder,
+}
+
+impl Deflate {
+    fn new(params: Params, role: Role, threshold: usize) -> Self {
+        let (reset_compress, reset_decompress) = match role {
+            Role::Server => (
+                params.server_no_context_takeover,
+                params.client_no_context_takeover,
+            ),
+            Role::Client => (
+                params.client_no_context_takeover,
+                params.server_no_context_takeover,
+            ),
+        };
+        Deflate {
+            params,
+            encoder: Encoder {
+                threshold,
+                compress: Compress::new(Compression::default(), false),
+                reset: reset_compress,
+            },
+            decoder: Decoder {
+                decompress: Decompress::new(false),
+                reset: reset_decompress,
+            },
+        }
+    }
+}
+
+/// Compresses outgoing messages.
+struct Encoder {
+    threshold: usize,
+    compress: Compress,
+    /// Start each message with a fresh context.
+    reset: bool,
+}
+
+impl Encoder {
+    fn deflate(&mut self, data: &[u8]) -> Vec<u8> {
+        if self.reset {
+            self.compress.reset();
+        }
+        let mut out = Vec::with_capacity(data.len() / 2 + 16);
+        let start = self.compress.total_in();
+        loop {
+            let consumed = (self.compress.total_in() - start) as usize;
+            out.reserve(256);
+            self.compress
+                .compress_vec(&data[consumed..], &mut out, FlushCompress::Sync)
+                .expect("deflating into memory cannot fail");
+            // Room left over means the flush is complete.
+            let consumed = (self.compress.total_in() - start) as usize;
+            if consumed == data.len() && out.len() < out.capacity() {
+                break;
+            }
+        }
+        if out.ends_with(&TAIL) {
+            out.truncate(out.len() - TAIL.len());
+        }
+        out
+    }
+}
+
+/// Decompresses incoming messages.
+struct Decoder {
+    decompress: Decompress,
+    /// The peer starts each message with a fresh context, so we must too.
+    reset: bool,
+}
+
+impl Decoder {
+    fn inflate(&mut self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        if self.reset {
+            self.decompress.reset(false);
+        }
+        let mut input = Vec::with_capacity(data.len() + TAIL.len());
+        input.extend_from_slice(data);
+        input.extend_from_slice(&TAIL);
+
+        // Output never grows past one byte over the limit: `decompress_vec`
+        // only fills spare capacity, so that byte is how we see the excess.
+        let limit = MAX_MESSAGE_SIZE + 1;
+        let mut out = Vec::with_capacity(data.len().saturating_mul(4).min(limit));
+        let start = self.decompress.total_in();
+        loop {
+            let consumed = (self.decompress.total_in() - start) as usize;
+            if out.len() == out.capacity() {
+                out.reserve_exact(out.len().max(256).min(limit - out.len()));
+            }
+            let produced = out.len();
+            let status = self
+                .decompress
+                .decompress_vec(&input[consumed..], &mut out, FlushDecompress::Sync)
+                .map_err(Error::Inflate)?;
+            if out.len() > MAX_MESSAGE_SIZE {
+                return Err(Error::TooLarge);
+            }
+
+            let now_consumed = (self.decompress.total_in() - start) as usize;
+            let done = now_consumed == input.len() && out.len() < out.capacity();
+            let stuck = now_consumed == consumed && out.len() == produced;
+            if done || status == Status::StreamEnd || stuck {
+                break;
+            }
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{SinkExt, StreamExt};
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+    use tokio_tungstenite::tungstenite::Message;
+
+    fn pair(params: Params) -> (Deflate, Deflate) {
+        (
+            Deflate::new(params, Role::Client, 16),
+            Deflate::new(params, Role::Server, 16),
+        )
+    }
+
+    fn chat(n: usize) -> String {
+        format!(
+            r#"{{"type":"message","room":"lobby","from":7,"text":"message number {} from the chat"}}"#,
+            n
+        )
+    }
+
+    #[test]
+    fn round_trips_with_and_without_context_takeover() {
+        for no_takeover in [false, true] {
+            let (mut client, mut server) = pair(Params {
+                server_no_context_takeover: no_takeover,
+                client_no_context_takeover: no_takeover,
+            });
+            let mut sizes = Vec::new();
+            for n in 0..20 {
+                let msg = chat(n).into_bytes();
+                let wire = client.encoder.deflate(&msg);
+                sizes.push(wire.len());
+                assert_eq!(server.decoder.inflate(&wire).unwrap(), msg);
+
+                let wire = server.encoder.deflate(&msg);
+                assert_eq!(client.decoder.inflate(&wire).unwrap(), msg);
+            }
+            // With the context kept, repeats of earlier messages shrink.
+            assert_eq!(sizes[5] < sizes[0], !no_takeover);
+        }
+    }
+
+    #[test]
+    fn inflating_stops_at_the_limit() {
+        let (mut client, mut server) = pair(Params::default());
+        let fits = client.encoder.deflate(&vec![0; MAX_MESSAGE_SIZE]);
+        assert_eq!(
+            server.decoder.inflate(&fits).unwrap().len(),
+            MAX_MESSAGE_SIZE
+        );
+
+        let (mut client, mut server) = pair(Params::default());
+        let over = client.encoder.deflate(&vec![0; MAX_MESSAGE_SIZE + 1]);
+        assert!(matches!(
+            server.decoder.inflate(&over),
+            Err(Error::TooLarge)
+        ));
+    }
+
+    fn offer(extensions: &[&'static str]) -> Request<()> {
+        let mut request = "ws://localhost/".into_client_request().unwrap();
+        for &extension in extensions {
+            request.headers_mut().append(
+                SEC_WEBSOCKET_EXTENSIONS,
+                HeaderValue::from_static(extension),
+            );
+        }
+        request
+    }
+
+    fn answered(extension: &'static str) -> Response<()> {
+        let mut response = Response::new(());
+        response.headers_mut().append(
+            SEC_WEBSOCKET_EXTENSIONS,
+            HeaderValue::from_static(extension),
+        );
+        response
+    }
+
+    #[test]
+    fn negotiation() {
+        let config = Config {
+            server_no_context_takeover: true,
+            ..Config::default()
+        };
+        // What browsers offer, after an offer we can't take.
+        let mut request = offer(&[
+            "permessage-deflate; server_max_window_bits=10",
+            "permessage-deflate; client_max_window_bits",
+        ]);
+        Config {
+            client_no_context_takeover: true,
+            ..Config::default()
+        }
+        .offer(&mut request);
+
+        let mut response = Response::new(());
+        assert_eq!(
+            config.accept(&request, &mut response),
+            Some(Params {
+                server_no_context_takeover: true,
+                client_no_context_takeover: false,
+            })
+        );
+        assert_eq!(
+            response.headers()[SEC_WEBSOCKET_EXTENSIONS],
+            "permessage-deflate; server_no_context_takeover"
+        );
+        assert_eq!(
+            config.accepted(&response).unwrap(),
+            Some(Params {
+                server_no_context_takeover: true,
+                client_no_context_takeover: false,
+            })
+        );
+
+        // Not offered, or only in ways we can't take, so not accepted.
+        let mut response = Response::new(());
+        assert!(config.accept(&offer(&[]), &mut response).is_none());
+        let request = offer(&[
+            "permessage-deflate; server_max_window_bits=9",
+            "permessage-deflate; client_no_context_takeover; client_no_context_takeover",
+            "permessage-deflate; mystery",
+            "x-webkit-deflate-frame",
+        ]);
+        assert!(config.accept(&request, &mut response).is_none());
+        assert!(response.headers().is_empty());
+
+        assert_eq!(config.accepted(&Response::new(())).unwrap(), None);
+        let smaller = answered("permessage-deflate; server_max_window_bits=12");
+        assert_eq!(config.accepted(&smaller).unwrap(), Some(Params::default()));
+        // We never offer a limit on our own window, nor other extensions.
+        for extension in ["permessage-deflate; client_max_window_bits=12", "x-mystery"] {
+            assert!(matches!(
+                config.accepted(&answered(extension)),
+                Err(Error::Negotiation(_))
+            ));
+        }
+    }
+
+    /// Copies the bytes written through it.
+    struct Tap {
+        inner: DuplexStream,
+        written: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl AsyncRead for Tap {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for Tap {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+            if let Poll::Ready(Ok(n)) = poll {
+                self.written.lock().unwrap().extend_from_slice(&buf[..n]);
+            }
+            poll
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    /// The first byte and payload length of each frame in `wire`, after the
+    /// handshake.
+    fn frames(wire: &[u8]) -> Vec<(u8, usize)> {
+        let start = wire.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let mut wire = &wire[start..];
+        let mut frames = Vec::new();
+        while let Some(frame) = Frame::parse(wire).unwrap() {
+            frames.push((wire[0], frame.len));
+            wire = &wire[frame.header + frame.len..];
+        }
+        assert!(wire.is_empty());
+        frames
+    }
+
+    /// Send `messages` from a client to a server over an in-memory socket,
+    /// both ends wrapped in a `DeflateStream`. Returns the frames the client
+    /// wrote.
+    async fn loopback(config: Option<Config>, messages: Vec<Message>) -> Vec<(u8, usize)> {
+        let (client_io, server_io) = tokio::io::duplex(1 << 20);
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let client_io = Tap {
+            inner: client_io,
+            written: written.clone(),
+        };
+
+        let server_config = config.clone();
+        let server = tokio::spawn(async move {
+            let server_io = DeflateStream::new(server_io, Role::Server, 16);
+            // The error type is tungstenite's.
+            #[allow(clippy::result_large_err)]
+            let callback = |request: &_, mut response| {
+                if let Some(config) = &server_config {
+                    config.accept(request, &mut response);
+                }
+                Ok(response)
+            };
+            let mut ws = tokio_tungstenite::accept_hdr_async(server_io, callback)
+                .await
+                .unwrap();
+            let mut received = Vec::new();
+            while let Some(Ok(msg)) = ws.next().await {
+                if !msg.is_close() {
+                    received.push(msg);
+                }
+            }
+            received
+        });
+
+        let mut request = "ws://localhost/".into_client_request().unwrap();
+        if let Some(config) = &config {
+            config.offer(&mut request);
+        }
+        let client_io = DeflateStream::new(client_io, Role::Client, 16);
+        let (mut ws, response) = tokio_tungstenite::client_async(request, client_io)
+            .await
+            .unwrap();
+        let accepted = match &config {
+            Some(config) => config.accepted(&response).unwrap(),
+            None => None,
+        };
+        assert_eq!(accepted.is_some(), config.is_some());
+        assert_eq!(ws.get_ref().params(), accepted);
+
+        for msg in &messages {
+            ws.send(msg.clone()).await.unwrap();
+        }
+        ws.close(None).await.unwrap();
+
+        assert_eq!(server.await.unwrap(), messages);
+        let written = written.lock().unwrap();
+        frames(&written)
+    }
+
+    #[tokio::test]
+    async fn only_long_messages_are_compressed() {
+        let messages = vec![
+            Message::Text(chat(1)),
+            Message::Text("short".into()),
+            Message::Binary(vec![7; 1000]),
+            Message::Binary(vec![9, 9]),
+            Message::Ping(vec![1; 100]),
+        ];
+        let frames = loopback(Some(Config::default()), messages).await;
+        let rsv1: Vec<bool> = frames.iter().map(|&(first, _)| first & RSV1 != 0).collect();
+        // The close frame too.
+        assert_eq!(rsv1, [true, false, true, false, false, false]);
+        assert!(frames[2].1 < 100);
+
+        let frames = loopback(None, vec![Message::Binary(vec![7; 1000])]).await;
+        assert!(frames.iter().all(|&(first, _)| first & RSV1 == 0));
+        assert_eq!(frames[0].1, 1000);
+    }
+
+    #[tokio::test]
+    async fn compression_saves_bytes_on_the_wire() {
+        let messages: Vec<_> = (0..200).map(|n| Message::Text(chat(n))).collect();
+        let sent = |frames: Vec<(u8, usize)>| frames.iter().map(|&(_, len)| len).sum::<usize>();
+        let plain = sent(loopback(None, messages.clone()).await);
+        let kept = sent(loopback(Some(Config::default()), messages.clone()).await);
+        let fresh = Config {
+            client_no_context_takeover: true,
+            ..Config::default()
+        };
+        let fresh = sent(loopback(Some(fresh), messages).await);
+        println!(
+            "200 chat messages: {} payload bytes plain, {} with context takeover ({:.0}% saved), {} without ({:.0}% saved)",
+            plain,
+            kept,
+            100.0 * (1.0 - kept as f64 / plain as f64),
+            fresh,
+            100.0 * (1.0 - fresh as f64 / plain as f64),
+        );
+        assert!(kept * 3 < plain);
+        assert!(fresh < plain);
+        assert!(kept < fresh);
+    }
+
+    /// Read an HTTP head off `io`.
+    async fn read_head(io: &mut DuplexStream) -> String {
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(io.read_u8().await.unwrap());
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    /// A server written from RFC 7692 rather than with this crate.
+    #[tokio::test]
+    async fn speaks_rfc_7692() {
+        let (client_io, mut server) = tokio::io::duplex(1 << 16);
+        let client = tokio::spawn(async move {
+            let mut request = "ws://localhost/".into_client_request().unwrap();
+            Config::default().offer(&mut request);
+            let client_io = DeflateStream::new(client_io, Role::Client, 16);
+            let (mut ws, _) = tokio_tungstenite::client_async(request, client_io)
+                .await
+                .unwrap();
+            let mut received = Vec::new();
+            for _ in 0..3 {
+                received.push(ws.next().await.unwrap().unwrap());
+            }
+            ws.send(Message::Text(chat(1))).await.unwrap();
+            received
+        });
+
+        let request = read_head(&mut server).await;
+        let header = |name: &str| {
+            request.lines().find_map(|line| {
+                let (key, value) = line.split_once(": ")?;
+                key.eq_ignore_ascii_case(name).then_some(value)
+            })
+        };
+        assert_eq!(header("Sec-WebSocket-Extensions"), Some(EXTENSION));
+        let key = header("Sec-WebSocket-Key").unwrap();
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\nSec-WebSocket-Extensions: permessage-deflate\r\n\r\n",
+            derive_accept_key(key.as_bytes())
+        );
+        server.write_all(response.as_bytes()).await.unwrap();
+        // "Hello" twice with the context kept (RFC 7692, 7.2.3.2), then
+        // fragmented (7.2.3.3) with a ping between the fragments.
+        server
+            .write_all(&[0xc1, 0x07, 0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00])
+            .await
+            .unwrap();
+        server
+            .write_all(&[0xc1, 0x05, 0xf2, 0x00, 0x11, 0x00, 0x00])
+            .await
+            .unwrap();
+        server
+            .write_all(&[
+                0x41, 0x03, 0xf2, 0x48, 0xcd, 0x89, 0x00, 0x80, 0x04, 0xc9, 0xc9, 0x07, 0x00,
+            ])
+            .await
+            .unwrap();
+
+        // The pong comes first, as it is, then our message compressed.
+        let mut pong = [0; 6];
+        server.read_exact(&mut pong).await.unwrap();
+        assert_eq!(pong[..2], [0x8a, 0x80]);
+        let mut first = [0; 2];
+        server.read_exact(&mut first).await.unwrap();
+        assert_eq!(first[0], FIN | RSV1 | TEXT);
+        assert_eq!(first[1] & 0x80, 0x80);
+        let mut mask = [0; 4];
+        server.read_exact(&mut mask).await.unwrap();
+        let mut payload = vec![0; (first[1] & 0x7f) as usize];
+        server.read_exact(&mut payload).await.unwrap();
+        apply_mask(&mut payload, mask);
+        payload.extend_from_slice(&TAIL);
+        let mut inflated = Vec::with_capacity(1000);
+        Decompress::new(false)
+            .decompress_vec(&payload, &mut inflated, FlushDecompress::Sync)
+            .unwrap();
+        assert_eq!(inflated, chat(1).as_bytes());
+
+        let received = client.await.unwrap();
+        assert_eq!(
+            received,
+            [
+                Message::Text("Hello".into()),
+                Message::Text("Hello".into()),
+                Message::Ping(Vec::new()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,344 @@
+//! Binary frames carrying live audio.
+//!
+//! Each frame is a fixed [`HEADER_LEN`] byte header followed by the samples,
+//! encoded with the frame's [`Codec`]. Uncompressed, they are interleaved
+//! and little-endian. Header fields are big-endian:
+//!
+//! | bytes  | field                                          |
+//! |--------|------------------------------------------------|
+//! | 0..8   | sequence number, from 0                        |
+//! | 8..16  | microseconds of audio sent before this frame   |
+//! | 16     | sample format: 1 for `i16`, 2 for `f32`        |
+//! | 17     | codec: 0 for PCM, 1 for FLAC, 2 for Opus       |
+//! | 18..20 | channels                                       |
+//! | 20..24 | sample rate in Hz                              |
+//!
+//! The codec is agreed on in the `hello`s: the client lists the ones it can
+//! send and the server answers with the one it [chose](Codec::choose).
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+pub const HEADER_LEN: usize = 24;
+
+/// The highest sample rate a frame may have, in Hz.
+pub const MAX_SAMPLE_RATE: u32 = 384_000;
+
+/// The most channels a frame may have.
+pub const MAX_CHANNELS: u16 = 32;
+
+/// How each sample is encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SampleFormat {
+    /// Signed 16-bit integers.
+    #[default]
+    I16,
+    /// 32-bit floats between -1 and 1.
+    F32,
+}
+
+impl SampleFormat {
+    pub const NAMES: [&'static str; 2] = ["i16", "f32"];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SampleFormat::I16 => "i16",
+            SampleFormat::F32 => "f32",
+        }
+    }
+
+    pub fn bytes(self) -> usize {
+        match self {
+            SampleFormat::I16 => 2,
+            SampleFormat::F32 => 4,
+        }
+    }
+
+    fn code(self) -> u8 {
+        match self {
+            SampleFormat::I16 => 1,
+            SampleFormat::F32 => 2,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<SampleFormat> {
+        match code {
+            1 => Some(SampleFormat::I16),
+            2 => Some(SampleFormat::F32),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for SampleFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SampleFormat, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "i16" => Ok(SampleFormat::I16),
+            "f32" => Ok(SampleFormat::F32),
+            _ => Err(format!("unknown sample format '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for SampleFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.name().fmt(f)
+    }
+}
+
+/// How the samples in a frame are compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Codec {
+    /// As they are.
+    #[default]
+    Pcm,
+    /// One FLAC frame. Lossless, but only for `i16` samples.
+    Flac,
+    /// One Opus packet. Lossy, and only at 8, 12, 16, 24 or 48 kHz with one
+    /// or two channels.
+    Opus,
+}
+
+impl Codec {
+    pub const NAMES: [&'static str; 3] = ["pcm", "flac", "opus"];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Pcm => "pcm",
+            Codec::Flac => "flac",
+            Codec::Opus => "opus",
+        }
+    }
+
+    /// The first of the `offered` codecs that is `supported`, or PCM, which
+    /// every peer can take.
+    pub fn choose(offered: &[Codec], supported: &[Codec]) -> Codec {
+        offered
+            .iter()
+            .copied()
+            .find(|codec| supported.contains(codec))
+            .unwrap_or_default()
+    }
+
+    fn code(self) -> u8 {
+        match self {
+            Codec::Pcm => 0,
+            Codec::Flac => 1,
+            Codec::Opus => 2,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Codec> {
+        match code {
+            0 => Some(Codec::Pcm),
+            1 => Some(Codec::Flac),
+            2 => Some(Codec::Opus),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Codec, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "pcm" => Ok(Codec::Pcm),
+            "flac" => Ok(Codec::Flac),
+            "opus" => Ok(Codec::Opus),
+            _ => Err(format!("unknown codec '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.name().fmt(f)
+    }
+}
+
+/// The shape of a stream's samples.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Format {
+    pub sample_format: SampleFormat,
+    pub channels: u16,
+    pub sample_rate: u32,
+}
+
+impl Format {
+    /// Bytes in one sample for every channel.
+    pub fn frame_bytes(&self) -> usize {
+        self.sample_format.bytes() * self.channels as usize
+    }
+
+    /// How long `bytes` of samples play for.
+    pub fn micros(&self, bytes: usize) -> u64 {
+        let frames = (bytes / self.frame_bytes()) as u64;
+        frames * 1_000_000 / self.sample_rate as u64
+    }
+}
+
+/// What comes before the samples in each frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub seq: u64,
+    /// Where the frame starts in the stream, in microseconds.
+    pub timestamp: u64,
+    pub format: Format,
+    pub codec: Codec,
+}
+
+impl Header {
+    /// A frame with this header and `samples`, already encoded with the
+    /// header's codec.
+    pub fn encode(&self, samples: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(HEADER_LEN + samples.len());
+        frame.extend_from_slice(&self.seq.to_be_bytes());
+        frame.extend_from_slice(&self.timestamp.to_be_bytes());
+        frame.push(self.format.sample_format.code());
+        frame.push(self.codec.code());
+        frame.extend_from_slice(&self.format.channels.to_be_bytes());
+        frame.extend_from_slice(&self.format.sample_rate.to_be_bytes());
+        frame.extend_from_slice(samples);
+        frame
+    }
+
+    /// Split a frame into its header and samples, still encoded.
+    pub fn decode(frame: &[u8]) -> Result<(Header, &[u8]), String> {
+        if frame.len() < HEADER_LEN {
+            return Err(format!(
+                "audio frames start with a {} byte header, got {} bytes",
+                HEADER_LEN,
+                frame.len()
+            ));
+        }
+        let (header, samples) = frame.split_at(HEADER_LEN);
+        let sample_format = SampleFormat::from_code(header[16])
+            .ok_or_else(|| format!("unknown sample format {}", header[16]))?;
+        let codec =
+            Codec::from_code(header[17]).ok_or_else(|| format!("unknown codec {}", header[17]))?;
+        let format = Format {
+            sample_format,
+            channels: u16::from_be_bytes(header[18..20].try_into().unwrap()),
+            sample_rate: u32::from_be_bytes(header[20..24].try_into().unwrap()),
+        };
+        if !(1..=MAX_CHANNELS).contains(&format.channels) {
+            return Err(format!(
+                "audio frames have 1 to {} channels, not {}",
+                MAX_CHANNELS, format.channels
+            ));
+        }
+        if !(1..=MAX_SAMPLE_RATE).contains(&format.sample_rate) {
+            return Err(format!(
+                "audio frames have a sample rate of 1 to {} Hz, not {}",
+                MAX_SAMPLE_RATE, format.sample_rate
+            ));
+        }
+        // Only raw samples can be checked without decoding them.
+        if codec == Codec::Pcm && samples.len() % format.frame_bytes() != 0 {
+            return Err(format!(
+                "{} bytes of samples do not split into {} channels of {}",
+                samples.len(),
+                format.channels,
+                sample_format
+            ));
+        }
+        let header = Header {
+            seq: u64::from_be_bytes(header[0..8].try_into().unwrap()),
+            timestamp: u64::from_be_bytes(header[8..16].try_into().unwrap()),
+            format,
+            codec,
+        };
+        Ok((header, samples))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STEREO: Format = Format {
+        sample_format: SampleFormat::I16,
+        channels: 2,
+        sample_rate: 48_000,
+    };
+
+    #[test]
+    fn frames_round_trip() {
+        let header = Header {
+            seq: 7,
+            timestamp: 140_000,
+            format: STEREO,
+            codec: Codec::Pcm,
+        };
+        let frame = header.encode(&[1, 2, 3, 4]);
+        assert_eq!(frame.len(), HEADER_LEN + 4);
+        assert_eq!(Header::decode(&frame), Ok((header, &[1, 2, 3, 4][..])));
+        assert_eq!(STEREO.micros(48_000 * 4), 1_000_000);
+
+        // Encoded samples need not line up with the channels.
+        let flac = Header {
+            codec: Codec::Flac,
+            ..header
+        };
+        assert_eq!(Header::decode(&flac.encode(&[1])), Ok((flac, &[1][..])));
+    }
+
+    #[test]
+    fn bad_frames_are_rejected() {
+        let header = Header {
+            seq: 0,
+            timestamp: 0,
+            format: STEREO,
+            codec: Codec::Pcm,
+        };
+        assert!(Header::decode(&[0; 10]).is_err());
+        // Half a sample for the second channel.
+        assert!(Header::decode(&header.encode(&[0; 3])).is_err());
+        let mut frame = header.encode(&[]);
+        frame[16] = 9;
+        assert!(Header::decode(&frame).is_err());
+        let mut frame = header.encode(&[]);
+        frame[17] = 9;
+        assert!(Header::decode(&frame).is_err());
+
+        for format in [
+            Format {
+                channels: 0,
+                ..STEREO
+            },
+            Format {
+                channels: MAX_CHANNELS + 1,
+                ..STEREO
+            },
+            Format {
+                sample_rate: 0,
+                ..STEREO
+            },
+            Format {
+                sample_rate: MAX_SAMPLE_RATE + 1,
+                ..STEREO
+            },
+        ] {
+            let frame = Header { format, ..header }.encode(&[]);
+            assert!(Header::decode(&frame).is_err(), "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn the_first_offered_codec_that_is_supported_is_chosen() {
+        let supported = [Codec::Pcm, Codec::Flac];
+        assert_eq!(
+            Codec::choose(&[Codec::Opus, Codec::Flac], &supported),
+            Codec::Flac
+        );
+        assert_eq!(Codec::choose(&[Codec::Opus], &supported), Codec::Pcm);
+        assert_eq!(Codec::choose(&[], &supported), Codec::Pcm);
+        assert_eq!("FLAC".parse(), Ok(Codec::Flac));
+    }
+}
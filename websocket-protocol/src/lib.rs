@@ -0,0 +1,208 @@
+//! The JSON messages websocket-server and websocket-client exchange.
+//!
+//! Every text frame holds one [`Envelope`], tagged by its `type` field.
+//! What an application sends rides in `data` envelopes; the other kinds
+//! belong to the protocol itself:
+//!
+//! ```json
+//! {"type": "hello", "version": 1}
+//! {"type": "hello", "version": 1, "codecs": ["opus", "flac"]}
+//! {"type": "data", "seq": 7, "body": {"type": "join", "room": "lobby"}}
+//! {"type": "ack", "seq": 7}
+//! {"type": "ping", "seq": 8}
+//! {"type": "error", "code": "malformed", "message": "expected value at line 1 column 1"}
+//! ```
+//!
+//! Each side opens with a `hello` naming the version it speaks. A peer
+//! whose version is not [compatible](is_compatible) gets an
+//! `incompatible_version` error and is closed with [`INCOMPATIBLE_VERSION`].
+//! Clients can also ask for the protocol by name during the WebSocket
+//! handshake, as the [`SUBPROTOCOL`]. Binary frames are not enveloped; what
+//! they mean is up to the application. Live audio uses the [`audio`] frames.
+
+pub mod audio;
+
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::Message;
+
+/// The protocol version this crate speaks.
+pub const VERSION: u32 = 1;
+
+/// The `Sec-WebSocket-Protocol` name for this version of the protocol.
+pub const SUBPROTOCOL: &str = "envelope.v1";
+
+/// The close code for a peer that speaks an incompatible version, from the
+/// range RFC 6455 leaves to applications.
+pub const INCOMPATIBLE_VERSION: CloseCode = CloseCode::Library(4000);
+
+/// Whether a peer saying hello with `version` can be talked to.
+///
+/// There is only one version so far, so it has to match.
+pub fn is_compatible(version: u32) -> bool {
+    version == VERSION
+}
+
+/// One protocol message, carrying an application message of type `T` when
+/// it is `Data`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Envelope<T> {
+    /// The first message each side sends. A client that streams audio lists
+    /// the [`audio::Codec`]s it can send, best first, and the server answers
+    /// with the one it chose.
+    Hello {
+        version: u32,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        codecs: Vec<audio::Codec>,
+    },
+    /// Answers a `ping`, or a `data` message that carried a `seq`.
+    Ack { seq: u64 },
+    /// An application message. Given a `seq`, the receiver acks it once it
+    /// has been handled.
+    Data {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+        body: T,
+    },
+    /// Something the peer sent could not be handled.
+    Error { code: ErrorCode, message: String },
+    /// Asks for an `ack` with the same `seq`, to measure round trips.
+    Ping { seq: u64 },
+}
+
+/// What kind of problem an `error` envelope reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The frame was not JSON, or not an envelope with a body of the
+    /// expected shape.
+    Malformed,
+    /// A `hello` was missing, or came twice.
+    Handshake,
+    /// The peer's `hello` named a version this side does not speak.
+    IncompatibleVersion,
+    /// The message was understood but could not be carried out.
+    InvalidRequest,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ErrorCode::Malformed => "malformed",
+            ErrorCode::Handshake => "handshake",
+            ErrorCode::IncompatibleVersion => "incompatible_version",
+            ErrorCode::InvalidRequest => "invalid_request",
+        };
+        name.fmt(f)
+    }
+}
+
+impl<T> Envelope<T> {
+    /// The `hello` for this crate's [`VERSION`].
+    pub fn hello() -> Self {
+        Envelope::Hello {
+            version: VERSION,
+            codecs: Vec::new(),
+        }
+    }
+
+    /// A `data` message that wants no ack.
+    pub fn data(body: T) -> Self {
+        Envelope::Data { seq: None, body }
+    }
+
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        Envelope::Error {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl<T: Serialize> Envelope<T> {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("envelopes always serialize")
+    }
+
+    /// The text frame carrying this envelope.
+    pub fn to_message(&self) -> Message {
+        Message::Text(self.to_json())
+    }
+}
+
+impl<T: DeserializeOwned> Envelope<T> {
+    /// Decode a text frame. On failure, the error says what was wrong with
+    /// it, ready to go back to the peer as a `malformed` error.
+    pub fn from_json(text: &str) -> Result<Self, Envelope<T>> {
+        serde_json::from_str(text).map_err(|e| Envelope::error(ErrorCode::Malformed, e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn envelopes_are_tagged_by_type() {
+        assert_eq!(
+            Envelope::<Value>::hello().to_json(),
+            r#"{"type":"hello","version":1}"#
+        );
+        let hello = r#"{"type":"hello","version":1,"codecs":["flac"]}"#;
+        assert_eq!(
+            Envelope::<Value>::from_json(hello).unwrap(),
+            Envelope::Hello {
+                version: 1,
+                codecs: vec![audio::Codec::Flac]
+            }
+        );
+        assert_eq!(
+            Envelope::data(json!({"type": "join"})).to_json(),
+            r#"{"type":"data","body":{"type":"join"}}"#
+        );
+        assert_eq!(
+            Envelope::<Value>::error(ErrorCode::IncompatibleVersion, "v2").to_json(),
+            r#"{"type":"error","code":"incompatible_version","message":"v2"}"#
+        );
+
+        let text = r#"{"type":"data","seq":3,"body":[1,2]}"#;
+        let decoded = Envelope::<Vec<u8>>::from_json(text).unwrap();
+        assert_eq!(
+            decoded,
+            Envelope::Data {
+                seq: Some(3),
+                body: vec![1, 2]
+            }
+        );
+        assert_eq!(decoded.to_json(), text);
+    }
+
+    #[test]
+    fn bad_frames_decode_to_malformed_errors() {
+        for text in [
+            "not json",
+            r#"{"type":"shout"}"#,
+            r#"{"type":"data","body":"not a number"}"#,
+        ] {
+            match Envelope::<u64>::from_json(text) {
+                Err(Envelope::Error {
+                    code: ErrorCode::Malformed,
+                    ..
+                }) => {}
+                other => panic!("{} decoded to {:?}", text, other),
+            }
+        }
+    }
+
+    #[test]
+    fn only_the_same_version_is_compatible() {
+        assert!(is_compatible(VERSION));
+        assert!(!is_compatible(VERSION + 1));
+        assert!(!is_compatible(0));
+    }
+}
@@ -0,0 +1,198 @@
+use std::borrow::Cow;
+
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::header::{
+    AUTHORIZATION, ORIGIN, SEC_WEBSOCKET_PROTOCOL, WWW_AUTHENTICATE,
+};
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+
+use crate::Config;
+
+/// Check an upgrade request before it is accepted: the origin it comes
+/// from, the bearer token it carries and the subprotocols it asks for.
+///
+/// A request that fails gets a plain HTTP error instead of the upgrade: 403
+/// for a foreign origin, 401 for a missing or wrong token and 400 when none
+/// of the subprotocols it offers is spoken here.
+// The error type is tungstenite's.
+#[allow(clippy::result_large_err)]
+pub fn check(
+    request: &Request,
+    mut response: Response,
+    config: &Config,
+) -> Result<Response, ErrorResponse> {
+    // Only browsers send an origin. Anything else is let through to the
+    // token check.
+    if let Some(origin) = request.headers().get(ORIGIN) {
+        let allowed = config.allowed_origins.is_empty()
+            || config
+                .allowed_origins
+                .iter()
+                .any(|allowed| origin.as_bytes() == allowed.as_bytes());
+        if !allowed {
+            return Err(reject(StatusCode::FORBIDDEN, "origin not allowed"));
+        }
+    }
+
+    if let Some(expected) = &config.token {
+        match token(request) {
+            Some(token) if same(token.as_bytes(), expected.as_bytes()) => {}
+            Some(_) => {
+                let challenge = r#"Bearer error="invalid_token""#;
+                return Err(unauthorized("invalid token", challenge));
+            }
+            None => return Err(unauthorized("a bearer token is required", "Bearer")),
+        }
+    }
+
+    let offered: Vec<&str> = request
+        .headers()
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .collect();
+    if !offered.is_empty() {
+        // The client lists what it wants most first.
+        let chosen = offered
+            .iter()
+            .find(|name| config.subprotocols.iter().any(|ours| ours == *name));
+        let Some(chosen) = chosen else {
+            let supported = config.subprotocols.join(", ");
+            let message = format!(
+                "no supported subprotocol; this server speaks: {}",
+                supported
+            );
+            return Err(reject(StatusCode::BAD_REQUEST, &message));
+        };
+        let value = HeaderValue::from_str(chosen).expect("it came from a header");
+        response.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, value);
+    }
+
+    Ok(response)
+}
+
+/// The bearer token in the `Authorization` header, or else the `token`
+/// query parameter, percent-decoded as browsers encode it.
+fn token(request: &Request) -> Option<Cow<'_, str>> {
+    let header = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+        .map(|(_, token)| Cow::Borrowed(token.trim()));
+    header.or_else(|| {
+        form_urlencoded::parse(request.uri().query()?.as_bytes())
+            .find_map(|(name, value)| (name == "token").then_some(value))
+    })
+}
+
+/// Compare without stopping at the first difference, so the time taken
+/// does not give away how much of a guess was right.
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn reject(status: StatusCode, message: &str) -> ErrorResponse {
+    let mut response = ErrorResponse::new(Some(message.to_string()));
+    *response.status_mut() = status;
+    response
+}
+
+fn unauthorized(message: &str, challenge: &'static str) -> ErrorResponse {
+    let mut response = reject(StatusCode::UNAUTHORIZED, message);
+    let challenge = HeaderValue::from_static(challenge);
+    response.headers_mut().insert(WWW_AUTHENTICATE, challenge);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config {
+            allowed_origins: vec!["https://chat.example".to_string()],
+            token: Some("s3cret".to_string()),
+            ..Config::default()
+        }
+    }
+
+    fn request(uri: &str, headers: &[(&str, &str)]) -> Request {
+        let mut builder = Request::builder().uri(uri);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap()
+    }
+
+    fn status(request: &Request, config: &Config) -> StatusCode {
+        match check(request, Response::default(), config) {
+            Ok(response) => response.status(),
+            Err(response) => response.status(),
+        }
+    }
+
+    #[test]
+    fn origins_outside_the_allowlist_are_forbidden() {
+        let config = config();
+        let auth = ("Authorization", "Bearer s3cret");
+        let foreign = request("/", &[("Origin", "https://evil.example"), auth]);
+        assert_eq!(status(&foreign, &config), StatusCode::FORBIDDEN);
+        let listed = request("/", &[("Origin", "https://chat.example"), auth]);
+        assert_eq!(status(&listed, &config), StatusCode::OK);
+        // Not a browser.
+        assert_eq!(status(&request("/", &[auth]), &config), StatusCode::OK);
+    }
+
+    #[test]
+    fn tokens_come_from_the_header_or_the_query() {
+        let config = config();
+        let header = request("/", &[("Authorization", "bearer s3cret")]);
+        assert_eq!(status(&header, &config), StatusCode::OK);
+        let query = request("/chat?room=a&token=s3cret", &[]);
+        assert_eq!(status(&query, &config), StatusCode::OK);
+        // Query tokens are compared once decoded.
+        let awkward = Config {
+            token: Some("a+b/c=d".to_string()),
+            ..config.clone()
+        };
+        let encoded = request("/?token=a%2Bb%2Fc%3Dd", &[]);
+        assert_eq!(status(&encoded, &awkward), StatusCode::OK);
+        // Headers are not encoded, so they are not decoded either.
+        let raw = request("/", &[("Authorization", "Bearer a%2Bb%2Fc%3Dd")]);
+        assert_eq!(status(&raw, &awkward), StatusCode::UNAUTHORIZED);
+
+        let missing = check(&request("/", &[]), Response::default(), &config).unwrap_err();
+        assert_eq!(missing.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(missing.headers()[WWW_AUTHENTICATE], "Bearer");
+        let wrong = request("/?token=guess", &[]);
+        assert_eq!(status(&wrong, &config), StatusCode::UNAUTHORIZED);
+
+        let open = Config::default();
+        assert_eq!(status(&request("/", &[]), &open), StatusCode::OK);
+    }
+
+    #[test]
+    fn the_first_supported_subprotocol_is_chosen() {
+        let config = Config {
+            subprotocols: vec!["envelope.v1".to_string(), "envelope.v2".to_string()],
+            ..Config::default()
+        };
+        let offer = request(
+            "/",
+            &[("Sec-WebSocket-Protocol", "mqtt, envelope.v2, envelope.v1")],
+        );
+        let response = check(&offer, Response::default(), &config).unwrap();
+        assert_eq!(response.headers()[SEC_WEBSOCKET_PROTOCOL], "envelope.v2");
+
+        let unknown = request("/", &[("Sec-WebSocket-Protocol", "mqtt")]);
+        assert_eq!(status(&unknown, &config), StatusCode::BAD_REQUEST);
+
+        let none = check(&request("/", &[]), Response::default(), &config).unwrap();
+        assert!(none.headers().get(SEC_WEBSOCKET_PROTOCOL).is_none());
+    }
+}
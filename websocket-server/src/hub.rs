@@ -0,0 +1,357 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use tokio_tungstenite::tungstenite::Message;
+use websocket_protocol::{Envelope, ErrorCode};
+
+use crate::outbox::{self, Counters, Overflow, QueueStats};
+use crate::protocol::{ClientMessage, PresenceEvent, ServerMessage};
+
+pub type ClientId = u64;
+
+/// The clients connected to the server and the rooms they are in.
+///
+/// Each client is reached through the sending half of its own bounded
+/// queue. A writer task per connection drains the queue into the socket, so
+/// fanning a message out never waits on a slow client; a client that falls
+/// too far behind is dealt with by the queue's overflow policy.
+#[derive(Clone, Default)]
+pub struct Hub {
+    inner: Arc<Mutex<Inner>>,
+    counters: Arc<Counters>,
+}
+
+#[derive(Default)]
+struct Inner {
+    next_id: ClientId,
+    clients: HashMap<ClientId, Client>,
+    rooms: HashMap<String, BTreeSet<ClientId>>,
+}
+
+struct Client {
+    tx: outbox::Sender,
+    rooms: HashSet<String>,
+}
+
+impl Hub {
+    /// A queue for a new client's messages, counted in the hub's stats.
+    pub fn outbox(&self, capacity: usize, policy: Overflow) -> (outbox::Sender, outbox::Receiver) {
+        outbox::channel(capacity, policy, self.counters.clone())
+    }
+
+    /// Register a client whose messages go to `tx`.
+    pub fn connect(&self, tx: outbox::Sender) -> ClientId {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        let id = inner.next_id;
+        inner.clients.insert(
+            id,
+            Client {
+                tx,
+                rooms: HashSet::new(),
+            },
+        );
+        id
+    }
+
+    /// Tell a chat client its id.
+    pub fn welcome(&self, id: ClientId) {
+        let inner = self.inner.lock().unwrap();
+        inner.send(id, &ServerMessage::Welcome { id });
+    }
+
+    /// Send `msg` as is to every client, the sender included.
+    pub fn broadcast_all(&self, msg: Message) {
+        let inner = self.inner.lock().unwrap();
+        for client in inner.clients.values() {
+            let _ = client.tx.send(msg.clone());
+        }
+    }
+
+    /// How deep the clients' queues are.
+    pub fn queue_stats(&self) -> QueueStats {
+        let inner = self.inner.lock().unwrap();
+        let depths = inner.clients.values().map(|client| client.tx.len());
+        QueueStats::new(depths, &self.counters)
+    }
+
+    /// Take a client out of every room it is in and forget it.
+    pub fn disconnect(&self, id: ClientId) {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(client) = inner.clients.remove(&id) else {
+            return;
+        };
+        for room in client.rooms {
+            inner.remove_member(&room, id);
+        }
+    }
+
+    pub fn handle(&self, id: ClientId, msg: ClientMessage) {
+        let mut inner = self.inner.lock().unwrap();
+        let result = match msg {
+            ClientMessage::Join { room } => inner.join(id, room),
+            ClientMessage::Leave { room } => inner.leave(id, room),
+            ClientMessage::Message { room, text } => inner.message(id, room, text),
+            ClientMessage::Upload { .. } | ClientMessage::UploadEnd => {
+                unreachable!("uploads are handled by the connection")
+            }
+        };
+        if let Err(message) = result {
+            inner.send_error(id, ErrorCode::InvalidRequest, message);
+        }
+    }
+
+    /// Tell a client its message could not be handled.
+    pub fn error(&self, id: ClientId, code: ErrorCode, message: String) {
+        let inner = self.inner.lock().unwrap();
+        inner.send_error(id, code, message);
+    }
+}
+
+impl Inner {
+    fn join(&mut self, id: ClientId, room: String) -> Result<(), String> {
+        if room.is_empty() {
+            return Err("room name must not be empty".to_string());
+        }
+        let client = self.clients.get_mut(&id).expect("unknown client");
+        if !client.rooms.insert(room.clone()) {
+            return Err(format!("already in room '{}'", room));
+        }
+
+        let members = self.rooms.entry(room.clone()).or_default();
+        members.insert(id);
+        let members: Vec<ClientId> = members.iter().copied().collect();
+
+        self.broadcast(
+            &room,
+            Some(id),
+            &ServerMessage::Presence {
+                room: room.clone(),
+                client: id,
+                event: PresenceEvent::Join,
+            },
+        );
+        self.send(id, &ServerMessage::Joined { room, members });
+        Ok(())
+    }
+
+    fn leave(&mut self, id: ClientId, room: String) -> Result<(), String> {
+        let client = self.clients.get_mut(&id).expect("unknown client");
+        if !client.rooms.remove(&room) {
+            return Err(format!("not in room '{}'", room));
+        }
+        self.remove_member(&room, id);
+        self.send(id, &ServerMessage::Left { room });
+        Ok(())
+    }
+
+    fn message(&mut self, id: ClientId, room: String, text: String) -> Result<(), String> {
+        if !self.clients[&id].rooms.contains(&room) {
+            return Err(format!("not in room '{}'", room));
+        }
+        // The sender gets its own message too, so every member sees the
+        // room in the same order.
+        let msg = ServerMessage::Message {
+            room: room.clone(),
+            from: id,
+            text,
+        };
+        self.broadcast(&room, None, &msg);
+        Ok(())
+    }
+
+    /// Drop `id` from the members of `room`, telling the others it left.
+    fn remove_member(&mut self, room: &str, id: ClientId) {
+        let Some(members) = self.rooms.get_mut(room) else {
+            return;
+        };
+        members.remove(&id);
+        if members.is_empty() {
+            self.rooms.remove(room);
+            return;
+        }
+        self.broadcast(
+            room,
+            Some(id),
+            &ServerMessage::Presence {
+                room: room.to_string(),
+                client: id,
+                event: PresenceEvent::Leave,
+            },
+        );
+    }
+
+    /// Send `msg` to the members of `room`, skipping `except`.
+    fn broadcast(&self, room: &str, except: Option<ClientId>, msg: &ServerMessage) {
+        let Some(members) = self.rooms.get(room) else {
+            return;
+        };
+        // Serialized once, each member gets a copy of the same frame.
+        let msg = text(msg);
+        for member in members.iter().filter(|&&member| Some(member) != except) {
+            if let Some(client) = self.clients.get(member) {
+                // A closed queue means the client is disconnecting and is
+                // about to be removed.
+                let _ = client.tx.send(msg.clone());
+            }
+        }
+    }
+
+    fn send(&self, id: ClientId, msg: &ServerMessage) {
+        if let Some(client) = self.clients.get(&id) {
+            let _ = client.tx.send(text(msg));
+        }
+    }
+
+    fn send_error(&self, id: ClientId, code: ErrorCode, message: String) {
+        if let Some(client) = self.clients.get(&id) {
+            let error = Envelope::<ServerMessage>::error(code, message);
+            let _ = client.tx.send(error.to_message());
+        }
+    }
+}
+
+fn text(msg: &ServerMessage) -> Message {
+    Envelope::data(msg).to_message()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(hub: &Hub) -> (ClientId, outbox::Receiver) {
+        let (tx, mut rx) = hub.outbox(16, Overflow::Disconnect);
+        let id = hub.connect(tx);
+        hub.welcome(id);
+        assert_eq!(
+            next(&mut rx),
+            format!(r#"{{"type":"welcome","id":{}}}"#, id)
+        );
+        (id, rx)
+    }
+
+    /// The next message's text, unwrapped from its envelope if it is data.
+    fn next(rx: &mut outbox::Receiver) -> String {
+        match rx.try_recv() {
+            Some(Message::Text(text)) => match text.strip_prefix(r#"{"type":"data","body":"#) {
+                Some(body) => body.strip_suffix('}').unwrap().to_string(),
+                None => text,
+            },
+            other => panic!("expected a text message, got {:?}", other),
+        }
+    }
+
+    fn join(room: &str) -> ClientMessage {
+        ClientMessage::Join {
+            room: room.to_string(),
+        }
+    }
+
+    #[test]
+    fn messages_reach_every_member() {
+        let hub = Hub::default();
+        let (a, mut a_rx) = client(&hub);
+        let (b, mut b_rx) = client(&hub);
+        let (_, mut c_rx) = client(&hub);
+
+        hub.handle(a, join("lobby"));
+        assert_eq!(
+            next(&mut a_rx),
+            format!(r#"{{"type":"joined","room":"lobby","members":[{}]}}"#, a)
+        );
+        hub.handle(b, join("lobby"));
+        assert_eq!(
+            next(&mut a_rx),
+            format!(
+                r#"{{"type":"presence","room":"lobby","client":{},"event":"join"}}"#,
+                b
+            )
+        );
+        assert_eq!(
+            next(&mut b_rx),
+            format!(
+                r#"{{"type":"joined","room":"lobby","members":[{},{}]}}"#,
+                a, b
+            )
+        );
+
+        hub.handle(
+            a,
+            ClientMessage::Message {
+                room: "lobby".to_string(),
+                text: "hi".to_string(),
+            },
+        );
+        let expected = format!(
+            r#"{{"type":"message","room":"lobby","from":{},"text":"hi"}}"#,
+            a
+        );
+        assert_eq!(next(&mut a_rx), expected);
+        assert_eq!(next(&mut b_rx), expected);
+        // Not in the room, so nothing arrives.
+        assert!(c_rx.try_recv().is_none());
+    }
+
+    #[test]
+    fn leaving_and_disconnecting_announce_presence() {
+        let hub = Hub::default();
+        let (a, mut a_rx) = client(&hub);
+        let (b, mut b_rx) = client(&hub);
+        hub.handle(a, join("lobby"));
+        hub.handle(a, join("games"));
+        hub.handle(b, join("lobby"));
+        hub.handle(b, join("games"));
+        while a_rx.try_recv().is_some() {}
+        while b_rx.try_recv().is_some() {}
+
+        hub.handle(
+            b,
+            ClientMessage::Leave {
+                room: "games".to_string(),
+            },
+        );
+        assert_eq!(next(&mut b_rx), r#"{"type":"left","room":"games"}"#);
+        assert_eq!(
+            next(&mut a_rx),
+            format!(
+                r#"{{"type":"presence","room":"games","client":{},"event":"leave"}}"#,
+                b
+            )
+        );
+
+        hub.disconnect(b);
+        assert_eq!(
+            next(&mut a_rx),
+            format!(
+                r#"{{"type":"presence","room":"lobby","client":{},"event":"leave"}}"#,
+                b
+            )
+        );
+        assert!(a_rx.try_recv().is_none());
+    }
+
+    #[test]
+    fn invalid_requests_get_errors() {
+        let hub = Hub::default();
+        let (a, mut a_rx) = client(&hub);
+        hub.handle(
+            a,
+            ClientMessage::Message {
+                room: "lobby".to_string(),
+                text: "hi".to_string(),
+            },
+        );
+        assert_eq!(
+            next(&mut a_rx),
+            r#"{"type":"error","code":"invalid_request","message":"not in room 'lobby'"}"#
+        );
+
+        hub.handle(a, join("lobby"));
+        next(&mut a_rx);
+        hub.handle(a, join("lobby"));
+        assert_eq!(
+            next(&mut a_rx),
+            r#"{"type":"error","code":"invalid_request","message":"already in room 'lobby'"}"#
+        );
+    }
+}
@@ -1,65 +1,1146 @@
+mod handshake;
+mod hub;
+mod outbox;
+mod protocol;
+mod recording;
+mod tls;
+mod upload;
+
+use std::borrow::Cow;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::{Arg, ArgAction, Command};
 use futures::sink::SinkExt;
-use futures::stream::StreamExt;
+use futures::stream::{Stream, StreamExt};
 
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+use tokio::time::{interval, interval_at, sleep, timeout, Instant, MissedTickBehavior};
 
-use tokio_tungstenite::accept_async;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::accept_hdr_async;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Role};
 use tokio_tungstenite::tungstenite::{Error as WsError, Message};
 
+use hub::Hub;
+use outbox::Overflow;
+use protocol::{ClientMessage, ServerMessage};
+use recording::Recording;
+use upload::Uploads;
+use websocket_deflate::DeflateStream;
+use websocket_protocol::audio::Codec;
+use websocket_protocol::{Envelope, ErrorCode, INCOMPATIBLE_VERSION};
+
 type AnyError = Box<dyn std::error::Error + Send + Sync>;
 
+/// What the server does with the messages it receives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    /// The JSON rooms protocol, with file uploads.
+    #[default]
+    Chat,
+    /// Send every message back to its sender.
+    Echo,
+    /// Send every message to every connected client.
+    Broadcast,
+    /// Read and drop every message.
+    Sink,
+    /// Write the audio frames each client streams to a WAV file.
+    Record,
+}
+
+impl Mode {
+    pub const NAMES: [&'static str; 5] = ["chat", "echo", "broadcast", "sink", "record"];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Chat => "chat",
+            Mode::Echo => "echo",
+            Mode::Broadcast => "broadcast",
+            Mode::Sink => "sink",
+            Mode::Record => "record",
+        }
+    }
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Mode, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "chat" => Ok(Mode::Chat),
+            "echo" => Ok(Mode::Echo),
+            "broadcast" => Ok(Mode::Broadcast),
+            "sink" => Ok(Mode::Sink),
+            "record" => Ok(Mode::Record),
+            _ => Err(format!("unknown mode '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.name().fmt(f)
+    }
+}
+
+/// Settings shared by every connection.
+#[derive(Clone)]
+pub struct Config {
+    pub mode: Mode,
+    /// Connections served at once. Further clients wait to be accepted.
+    pub max_connections: usize,
+    /// Messages waiting to be written to one client before `overflow`
+    /// kicks in.
+    pub max_queued: usize,
+    pub overflow: Overflow,
+    /// How often to print queue stats, if at all.
+    pub stats_interval: Option<Duration>,
+    /// How often a ping is sent to each client.
+    pub ping_interval: Duration,
+    /// Pings that can go unanswered before the peer is taken for dead.
+    pub max_missed_pongs: u32,
+    /// How long a client may go without sending a message. Pongs keep the
+    /// connection alive but do not count as activity.
+    pub idle_timeout: Duration,
+    /// Where uploaded files are written.
+    pub upload_dir: PathBuf,
+    /// The largest file a client may upload, in bytes.
+    pub max_upload_size: u64,
+    /// Bytes that uploads not yet finished may take up in all.
+    pub max_unfinished_uploads: u64,
+    /// Where record mode writes its WAV files.
+    pub record_dir: PathBuf,
+    /// Message compression for clients that ask for it, or `None` to turn
+    /// every client down.
+    pub compression: Option<websocket_deflate::Config>,
+    /// Serve `wss://` with this certificate instead of plain `ws://`.
+    pub tls: Option<Arc<ServerConfig>>,
+    /// Origins browsers may connect from, such as `https://example.com`.
+    /// Empty allows any.
+    pub allowed_origins: Vec<String>,
+    /// Subprotocols spoken here. A client asking for others is refused.
+    pub subprotocols: Vec<String>,
+    /// The bearer token clients have to present, or `None` to let anyone
+    /// in.
+    pub token: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            mode: Mode::default(),
+            max_connections: 1024,
+            max_queued: 1024,
+            overflow: Overflow::default(),
+            stats_interval: None,
+            ping_interval: Duration::from_secs(15),
+            max_missed_pongs: 2,
+            idle_timeout: Duration::from_secs(5 * 60),
+            upload_dir: PathBuf::from("uploads"),
+            max_upload_size: 1024 * 1024 * 1024,
+            max_unfinished_uploads: 4 * 1024 * 1024 * 1024,
+            record_dir: PathBuf::from("recordings"),
+            compression: Some(websocket_deflate::Config::default()),
+            tls: None,
+            allowed_origins: Vec::new(),
+            subprotocols: vec![websocket_protocol::SUBPROTOCOL.to_string()],
+            token: None,
+        }
+    }
+}
+
+/// How long a closing connection gets to flush its queue before the socket
+/// is dropped. A half-open peer would otherwise hold the writer forever.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[tokio::main]
 async fn main() -> Result<(), AnyError> {
-    let addr = "127.0.0.1:9000";
+    let matches = Command::new("websocket-server")
+        .about("A WebSocket chat, echo, broadcast, sink or audio recording server")
+        .arg(
+            Arg::new("bind")
+                .short('b')
+                .long("bind")
+                .default_value("127.0.0.1:9000")
+                .help("Address to listen on"),
+        )
+        .arg(
+            Arg::new("mode")
+                .short('m')
+                .long("mode")
+                .default_value("chat")
+                .value_parser(Mode::NAMES)
+                .help("What to do with received messages"),
+        )
+        .arg(
+            Arg::new("max-connections")
+                .short('c')
+                .long("max-connections")
+                .default_value("1024")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Connections served at once; more wait to be accepted"),
+        )
+        .arg(
+            Arg::new("max-queued")
+                .long("max-queued")
+                .default_value("1024")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Messages queued for one slow client before --overflow applies"),
+        )
+        .arg(
+            Arg::new("overflow")
+                .long("overflow")
+                .default_value("disconnect")
+                .value_parser(Overflow::NAMES)
+                .help("What to do when a client's queue is full"),
+        )
+        .arg(
+            Arg::new("stats-interval")
+                .long("stats-interval")
+                .default_value("0")
+                .value_parser(clap::value_parser!(u64))
+                .help("Seconds between queue depth reports; 0 for none"),
+        )
+        .arg(
+            Arg::new("idle-timeout")
+                .long("idle-timeout")
+                .default_value("300")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Seconds a client may stay silent before it is closed"),
+        )
+        .arg(
+            Arg::new("upload-dir")
+                .long("upload-dir")
+                .default_value("uploads")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Where files uploaded in chat mode are written"),
+        )
+        .arg(
+            Arg::new("max-upload-size")
+                .long("max-upload-size")
+                .default_value("1073741824")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Largest file a client may upload, in bytes"),
+        )
+        .arg(
+            Arg::new("max-unfinished-uploads")
+                .long("max-unfinished-uploads")
+                .default_value("4294967296")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Bytes that unfinished uploads may take up in all"),
+        )
+        .arg(
+            Arg::new("record-dir")
+                .long("record-dir")
+                .default_value("recordings")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Where record mode writes a WAV file for each client"),
+        )
+        .arg(
+            Arg::new("no-compression")
+                .long("no-compression")
+                .action(ArgAction::SetTrue)
+                .help("Turn down clients asking for message compression"),
+        )
+        .arg(
+            Arg::new("cert")
+                .long("cert")
+                .requires("key")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("PEM certificate chain; serves wss:// with --key"),
+        )
+        .arg(
+            Arg::new("key")
+                .long("key")
+                .requires("cert")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("PEM private key for --cert"),
+        )
+        .arg(
+            Arg::new("allow-origin")
+                .long("allow-origin")
+                .action(ArgAction::Append)
+                .help("Origin browsers may connect from; repeat for more, omit to allow any"),
+        )
+        .arg(
+            Arg::new("token")
+                .long("token")
+                .help("Bearer token clients must send in Authorization or ?token="),
+        )
+        .get_matches();
+
+    let tls = match (
+        matches.get_one::<PathBuf>("cert"),
+        matches.get_one::<PathBuf>("key"),
+    ) {
+        (Some(cert), Some(key)) => Some(tls::load_config(cert, key)?),
+        _ => None,
+    };
+    let defaults = Config::default();
+    let config = Config {
+        mode: matches.get_one::<String>("mode").unwrap().parse()?,
+        max_connections: *matches.get_one::<u64>("max-connections").unwrap() as usize,
+        max_queued: *matches.get_one::<u64>("max-queued").unwrap() as usize,
+        overflow: matches.get_one::<String>("overflow").unwrap().parse()?,
+        stats_interval: match *matches.get_one::<u64>("stats-interval").unwrap() {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        },
+        idle_timeout: Duration::from_secs(*matches.get_one::<u64>("idle-timeout").unwrap()),
+        upload_dir: matches.get_one::<PathBuf>("upload-dir").unwrap().clone(),
+        max_upload_size: *matches.get_one::<u64>("max-upload-size").unwrap(),
+        max_unfinished_uploads: *matches.get_one::<u64>("max-unfinished-uploads").unwrap(),
+        record_dir: matches.get_one::<PathBuf>("record-dir").unwrap().clone(),
+        compression: defaults
+            .compression
+            .filter(|_| !matches.get_flag("no-compression")),
+        tls,
+        allowed_origins: matches
+            .get_many::<String>("allow-origin")
+            .map(|origins| origins.cloned().collect())
+            .unwrap_or_default(),
+        token: matches.get_one::<String>("token").cloned(),
+        ..defaults
+    };
+
+    let addr = matches.get_one::<String>("bind").unwrap();
     let listener = TcpListener::bind(addr).await?;
+    let scheme = if config.tls.is_some() { "wss" } else { "ws" };
+    println!(
+        "Listening on {}://{} in {} mode",
+        scheme,
+        listener.local_addr()?,
+        config.mode
+    );
 
-    println!("Listening on {:?}", addr);
+    serve(listener, Hub::default(), config).await
+}
 
+async fn serve(listener: TcpListener, hub: Hub, config: Config) -> Result<(), AnyError> {
+    if let Some(period) = config.stats_interval {
+        tokio::spawn(report_stats(hub.clone(), period));
+    }
+    let limit_connections = Arc::new(Semaphore::new(config.max_connections));
+    let config = Arc::new(config);
     loop {
+        // Wait for a free slot before accepting, so clients over the limit
+        // queue in the listen backlog.
+        let permit = limit_connections.clone().acquire_owned().await.unwrap();
         let (stream, addr) = listener.accept().await?;
         println!("{:?} connected.", addr);
 
+        let hub = hub.clone();
+        let config = config.clone();
         tokio::spawn(async move {
-            let mut ws_stream = accept_async(stream).await?;
-            println!("Handshake successful.");
+            let result = match &config.tls {
+                Some(tls) => match TlsAcceptor::from(tls.clone()).accept(stream).await {
+                    Ok(stream) => handle_connection(stream, hub, &config).await,
+                    Err(e) => Err(e.into()),
+                },
+                None => handle_connection(stream, hub, &config).await,
+            };
+            match result {
+                Ok(close) => println!("{:?} disconnected: {:?}", addr, close),
+                Err(e) => println!("Error on connection {:?}: {}", addr, e),
+            }
+            drop(permit);
+        });
+    }
+}
+
+/// Print the queue stats every `period`, as JSON.
+async fn report_stats(hub: Hub, period: Duration) {
+    let mut ticks = interval(period);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        let stats = serde_json::to_string(&hub.queue_stats()).unwrap();
+        println!("Queue stats: {}", stats);
+    }
+}
+
+/// Run one client: a writer task sends whatever lands in the client's queue,
+/// while this task reads control messages and hands them to the hub.
+///
+/// Returns the close frame the server sent, if it was the one to close.
+async fn handle_connection<S>(
+    stream: S,
+    hub: Hub,
+    config: &Config,
+) -> Result<Option<CloseFrame<'static>>, AnyError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // The error type is tungstenite's.
+    #[allow(clippy::result_large_err)]
+    let negotiate = |request: &_, response| {
+        let mut response = handshake::check(request, response, config)?;
+        if let Some(compression) = &config.compression {
+            compression.accept(request, &mut response);
+        }
+        Ok(response)
+    };
+    let threshold = config.compression.as_ref().map_or(0, |c| c.threshold);
+    let stream = DeflateStream::new(stream, Role::Server, threshold);
+    let ws_stream = accept_hdr_async(stream, negotiate).await?;
+    println!(
+        "Handshake successful. Compression: {:?}",
+        ws_stream.get_ref().params()
+    );
+
+    let (mut sink, mut stream) = ws_stream.split();
+    let (tx, mut rx) = hub.outbox(config.max_queued, config.overflow);
+    let id = hub.connect(tx.clone());
+
+    let mut writer = tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            let closing = msg.is_close();
+            if sink.send(msg).await.is_err() || closing {
+                break;
+            }
+        }
+        // Also answers a close frame from the client.
+        let _ = sink.close().await;
+    });
 
-            while let Some(item) = ws_stream.next().await {
-                match item {
-                    Ok(msg) => match msg {
-                        Message::Text(text) => {
-                            println!("Received text message: {}", text);
+    // A name taken by an earlier run gets a number added, so a restart
+    // never overwrites a recording.
+    let mut recording = (config.mode == Mode::Record).then(|| {
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let name = format!("{}-{}.wav", started.as_secs(), id);
+        Recording::new(config.record_dir.join(name))
+    });
+    let result = read_messages(&mut stream, &mut recording, &hub, id, &tx, config).await;
+    // Finished when dropped, writing what was held back and the header.
+    if let Some(recording) = recording {
+        let _ = tokio::task::spawn_blocking(move || drop(recording)).await;
+    }
+    if let Ok(Some(frame)) = &result {
+        let _ = tx.send(Message::Close(Some(frame.clone())));
+    }
+
+    // Once the hub forgets the client and our own sender is gone, the writer
+    // sends what is left and stops.
+    hub.disconnect(id);
+    drop(tx);
+    let closing = async {
+        // Wait for the client to answer our close frame. Dropping the socket
+        // with its last messages unread would reset the connection, and the
+        // client could lose the close frame.
+        if matches!(result, Ok(Some(_))) {
+            while let Some(Ok(_)) = stream.next().await {}
+        }
+        let _ = (&mut writer).await;
+    };
+    if timeout(CLOSE_TIMEOUT, closing).await.is_err() {
+        writer.abort();
+    }
+    result
+}
 
-                            ws_stream.send(Message::Text(text)).await?;
+/// Read from the client until it closes the connection, or until it has to
+/// be closed. In the second case the close frame to send is returned.
+///
+/// The client has to say hello before anything else, with a version the
+/// server speaks.
+async fn read_messages<S>(
+    stream: &mut S,
+    recording: &mut Option<Recording>,
+    hub: &Hub,
+    id: hub::ClientId,
+    tx: &outbox::Sender,
+    config: &Config,
+) -> Result<Option<CloseFrame<'static>>, AnyError>
+where
+    S: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    let mut pings = interval_at(Instant::now() + config.ping_interval, config.ping_interval);
+    pings.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let idle = sleep(config.idle_timeout);
+    tokio::pin!(idle);
+    let mut missed_pongs = 0;
+    let mut greeted = false;
+    let mut uploads = Uploads::new(
+        &config.upload_dir,
+        config.max_upload_size,
+        config.max_unfinished_uploads,
+    );
 
-                            println!("Message sent back.");
+    loop {
+        let item = tokio::select! {
+            item = stream.next() => item,
+            _ = pings.tick() => {
+                if missed_pongs >= config.max_missed_pongs {
+                    return Ok(Some(close(CloseCode::Away, "ping timeout")));
+                }
+                missed_pongs += 1;
+                let _ = tx.send(Message::Ping(Vec::new()));
+                continue;
+            }
+            _ = &mut idle => return Ok(Some(close(CloseCode::Policy, "idle timeout"))),
+            _ = tx.overflowed() => {
+                return Ok(Some(close(CloseCode::Policy, "too slow to keep up")));
+            }
+        };
+        let msg = match item {
+            Some(Ok(msg)) => msg,
+            Some(Err(WsError::Protocol(e))) => {
+                return Ok(Some(close(CloseCode::Protocol, e.to_string())));
+            }
+            Some(Err(WsError::Capacity(e))) => {
+                return Ok(Some(close(CloseCode::Size, e.to_string())));
+            }
+            // Frames the compression layer could not take.
+            Some(Err(WsError::Io(e))) if e.kind() == io::ErrorKind::InvalidData => {
+                return Ok(Some(close(CloseCode::Invalid, e.to_string())));
+            }
+            Some(Err(e)) => return Err(e.into()),
+            None => return Ok(None),
+        };
+
+        // Anything from the peer shows it is still there.
+        missed_pongs = 0;
+        let text = match msg {
+            Message::Text(text) => text,
+            Message::Binary(_) if !greeted => {
+                return Ok(Some(close(CloseCode::Protocol, "expected a hello")));
+            }
+            Message::Binary(data) => {
+                match config.mode {
+                    Mode::Chat if uploads.in_progress() => {
+                        if let Err(e) = uploads.chunk(&data).await {
+                            hub.error(id, ErrorCode::InvalidRequest, e);
                         }
-                        Message::Close(frame) => {
-                            println!("Received close message: {:?}", frame);
-
-                            if let Err(e) = ws_stream.close(None).await {
-                                match e {
-                                    WsError::ConnectionClosed => (),
-                                    _ => {
-                                        println!("Error while closing: {}", e);
-                                        break;
-                                    }
-                                }
-                            }
-
-                            println!("Sent close message.");
-
-                            println!("Closing...");
-                            break;
+                    }
+                    Mode::Chat => {
+                        return Ok(Some(close(
+                            CloseCode::Unsupported,
+                            "binary messages are only accepted during an upload",
+                        )));
+                    }
+                    Mode::Echo => {
+                        let _ = tx.send(Message::Binary(data));
+                    }
+                    Mode::Broadcast => hub.broadcast_all(Message::Binary(data)),
+                    Mode::Sink => {}
+                    Mode::Record => {
+                        // A frame can come with a gap's worth of silence to
+                        // write, so it goes to a blocking thread.
+                        let mut taken = recording.take().expect("made in record mode");
+                        let (taken, written) = tokio::task::spawn_blocking(move || {
+                            let written = taken.frame(&data);
+                            (taken, written)
+                        })
+                        .await?;
+                        *recording = Some(taken);
+                        if let Err(e) = written {
+                            hub.error(id, ErrorCode::InvalidRequest, e);
                         }
-                        _ => (),
-                    },
-                    Err(e) => {
-                        println!("Error receiving message: \n{0:?}\n{0}", e);
                     }
                 }
+                idle.as_mut().reset(Instant::now() + config.idle_timeout);
+                continue;
+            }
+            Message::Close(frame) => {
+                println!("Received close message: {:?}", frame);
+                return Ok(None);
+            }
+            // Pings are answered by tungstenite itself.
+            Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => continue,
+        };
+        idle.as_mut().reset(Instant::now() + config.idle_timeout);
+
+        // Bodies stay as they are until the mode decides what they are.
+        let envelope = match Envelope::<serde_json::Value>::from_json(&text) {
+            Ok(envelope) => envelope,
+            Err(_) if !greeted => {
+                return Ok(Some(close(CloseCode::Protocol, "expected a hello")));
+            }
+            Err(error) => {
+                let _ = tx.send(error.to_message());
+                continue;
+            }
+        };
+        match envelope {
+            Envelope::Hello { .. } if greeted => {
+                hub.error(id, ErrorCode::Handshake, "already said hello".to_string());
+            }
+            Envelope::Hello { version, .. } if !websocket_protocol::is_compatible(version) => {
+                let message = format!(
+                    "version {} is not supported; this server speaks {}",
+                    version,
+                    websocket_protocol::VERSION
+                );
+                hub.error(id, ErrorCode::IncompatibleVersion, message);
+                return Ok(Some(close(
+                    INCOMPATIBLE_VERSION,
+                    "incompatible protocol version",
+                )));
+            }
+            Envelope::Hello { codecs, .. } => {
+                greeted = true;
+                // Only recordings decode audio; other modes pass frames on
+                // as they are, so PCM is all they can promise.
+                let supported: &[Codec] = match config.mode {
+                    Mode::Record => websocket_codec::SUPPORTED,
+                    _ => &[Codec::Pcm],
+                };
+                let chosen = match codecs.is_empty() {
+                    true => Vec::new(),
+                    false => vec![Codec::choose(&codecs, supported)],
+                };
+                let hello = Envelope::<()>::Hello {
+                    version: websocket_protocol::VERSION,
+                    codecs: chosen,
+                };
+                let _ = tx.send(hello.to_message());
+                if config.mode == Mode::Chat {
+                    hub.welcome(id);
+                }
+            }
+            _ if !greeted => return Ok(Some(close(CloseCode::Protocol, "expected a hello"))),
+            Envelope::Ping { seq } => {
+                let _ = tx.send(Envelope::<()>::Ack { seq }.to_message());
+            }
+            Envelope::Ack { .. } => {}
+            Envelope::Error { code, message } => {
+                println!("Client {} reported an error ({}): {}", id, code, message);
+            }
+            Envelope::Data { seq, body } => {
+                match config.mode {
+                    Mode::Chat => chat(body, hub, id, tx, &mut uploads).await,
+                    Mode::Echo => {
+                        let _ = tx.send(Message::Text(text));
+                    }
+                    Mode::Broadcast => hub.broadcast_all(Message::Text(text)),
+                    Mode::Sink | Mode::Record => {}
+                }
+                if let Some(seq) = seq {
+                    let _ = tx.send(Envelope::<()>::Ack { seq }.to_message());
+                }
+            }
+        }
+    }
+}
+
+/// Handle the body of one `data` message in chat mode.
+async fn chat(
+    body: serde_json::Value,
+    hub: &Hub,
+    id: hub::ClientId,
+    tx: &outbox::Sender,
+    uploads: &mut Uploads,
+) {
+    match serde_json::from_value::<ClientMessage>(body) {
+        Ok(ClientMessage::Upload {
+            name,
+            size,
+            sha256,
+            chunk_size,
+        }) => {
+            let result = uploads.start(name, size, sha256, chunk_size).await;
+            reply(hub, id, tx, result);
+        }
+        Ok(ClientMessage::UploadEnd) => reply(hub, id, tx, uploads.finish().await),
+        Ok(msg) => hub.handle(id, msg),
+        Err(e) => hub.error(id, ErrorCode::Malformed, format!("invalid body: {}", e)),
+    }
+}
+
+fn reply(hub: &Hub, id: hub::ClientId, tx: &outbox::Sender, result: Result<ServerMessage, String>) {
+    match result {
+        Ok(msg) => {
+            let _ = tx.send(Envelope::data(msg).to_message());
+        }
+        Err(e) => hub.error(id, ErrorCode::InvalidRequest, e),
+    }
+}
+
+fn close(code: CloseCode, reason: impl Into<Cow<'static, str>>) -> CloseFrame<'static> {
+    CloseFrame {
+        code,
+        reason: reason.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+    /// The next message, unwrapped from its envelope if it is data.
+    async fn recv<S>(ws: &mut WebSocketStream<S>) -> Value
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        let msg = timeout(Duration::from_secs(5), ws.next()).await;
+        let text = msg.unwrap().unwrap().unwrap().into_text().unwrap();
+        let mut envelope: Value = serde_json::from_str(&text).unwrap();
+        match envelope["type"] == "data" {
+            true => envelope["body"].take(),
+            false => envelope,
+        }
+    }
+
+    fn data(body: Value) -> Message {
+        Envelope::data(body).to_message()
+    }
+
+    async fn hello<S>(ws: &mut WebSocketStream<S>)
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        ws.send(Envelope::<()>::hello().to_message()).await.unwrap();
+        assert_eq!(
+            recv(ws).await,
+            json!({"type": "hello", "version": websocket_protocol::VERSION})
+        );
+    }
+
+    /// Connect and get through the handshake.
+    async fn connect(url: &str) -> WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>> {
+        let (mut ws, _) = connect_async(url).await.unwrap();
+        hello(&mut ws).await;
+        ws
+    }
+
+    async fn start(config: Config) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(serve(listener, Hub::default(), config));
+        url
+    }
+
+    /// Read until the server's close frame, answering pings on the way.
+    async fn close_frame<S>(ws: &mut tokio_tungstenite::WebSocketStream<S>) -> CloseFrame<'static>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        loop {
+            let msg = timeout(Duration::from_secs(5), ws.next()).await;
+            if let Message::Close(frame) = msg.unwrap().unwrap().unwrap() {
+                return frame.expect("close frame without a code");
             }
+        }
+    }
+
+    #[tokio::test]
+    async fn clients_chat_in_a_room() {
+        let url = start(Config::default()).await;
+
+        let mut a = connect(&url).await;
+        let mut b = connect(&url).await;
+
+        assert_eq!(recv(&mut a).await["type"], "welcome");
+        assert_eq!(recv(&mut b).await["type"], "welcome");
+
+        let join = json!({"type": "join", "room": "lobby"});
+        a.send(data(join.clone())).await.unwrap();
+        assert_eq!(recv(&mut a).await["type"], "joined");
+        b.send(data(join)).await.unwrap();
+        assert_eq!(recv(&mut b).await["type"], "joined");
+        assert_eq!(recv(&mut a).await["event"], "join");
+
+        let hello = json!({"type": "message", "room": "lobby", "text": "hello"});
+        b.send(data(hello)).await.unwrap();
+        assert_eq!(recv(&mut a).await["text"], "hello");
+        assert_eq!(recv(&mut b).await["text"], "hello");
+
+        b.close(None).await.unwrap();
+        let presence = recv(&mut a).await;
+        assert_eq!(presence["type"], "presence");
+        assert_eq!(presence["event"], "leave");
+
+        a.send(Message::Text("not json".into())).await.unwrap();
+        assert_eq!(recv(&mut a).await["code"], "malformed");
+        a.send(data(json!({"type": "dance"}))).await.unwrap();
+        assert_eq!(recv(&mut a).await["code"], "malformed");
+        a.send(data(json!({"type": "leave", "room": "lobby"})))
+            .await
+            .unwrap();
+        a.send(data(json!({"type": "leave", "room": "lobby"})))
+            .await
+            .unwrap();
+        assert_eq!(recv(&mut a).await["type"], "left");
+        let error = recv(&mut a).await;
+        assert_eq!(error["type"], "error");
+        assert_eq!(error["code"], "invalid_request");
+    }
+
+    #[tokio::test]
+    async fn clients_must_say_hello_with_a_compatible_version() {
+        let url = start(Config::default()).await;
+
+        let (mut ws, _) = connect_async(&url).await.unwrap();
+        ws.send(data(json!({"type": "join", "room": "lobby"})))
+            .await
+            .unwrap();
+        let frame = close_frame(&mut ws).await;
+        assert_eq!(frame.code, CloseCode::Protocol);
+        assert_eq!(frame.reason, "expected a hello");
+
+        let (mut ws, _) = connect_async(&url).await.unwrap();
+        let future = Envelope::<()>::Hello {
+            version: websocket_protocol::VERSION + 1,
+            codecs: Vec::new(),
+        };
+        ws.send(future.to_message()).await.unwrap();
+        let error = recv(&mut ws).await;
+        assert_eq!(error["code"], "incompatible_version");
+        assert_eq!(close_frame(&mut ws).await.code, INCOMPATIBLE_VERSION);
+
+        let mut ws = connect(&url).await;
+        assert_eq!(recv(&mut ws).await["type"], "welcome");
+        ws.send(Envelope::<()>::hello().to_message()).await.unwrap();
+        assert_eq!(recv(&mut ws).await["code"], "handshake");
+    }
+
+    #[tokio::test]
+    async fn pings_and_numbered_data_are_acked() {
+        let url = start(Config {
+            mode: Mode::Sink,
+            ..Config::default()
+        })
+        .await;
+        let mut ws = connect(&url).await;
+
+        ws.send(Envelope::<()>::Ping { seq: 5 }.to_message())
+            .await
+            .unwrap();
+        assert_eq!(recv(&mut ws).await, json!({"type": "ack", "seq": 5}));
+
+        let numbered = Envelope::Data {
+            seq: Some(6),
+            body: "dropped",
+        };
+        ws.send(numbered.to_message()).await.unwrap();
+        assert_eq!(recv(&mut ws).await, json!({"type": "ack", "seq": 6}));
+    }
+
+    #[tokio::test]
+    async fn idle_clients_are_closed() {
+        let url = start(Config {
+            ping_interval: Duration::from_millis(20),
+            max_missed_pongs: 2,
+            idle_timeout: Duration::from_millis(300),
+            ..Config::default()
+        })
+        .await;
+        let mut ws = connect(&url).await;
+        recv(&mut ws).await;
+
+        // Answering pings is not enough to count as active.
+        let started = Instant::now();
+        let frame = close_frame(&mut ws).await;
+        assert!(started.elapsed() >= Duration::from_millis(250));
+        assert_eq!(frame.code, CloseCode::Policy);
+        assert_eq!(frame.reason, "idle timeout");
+    }
 
-            Ok::<(), AnyError>(())
+    #[tokio::test]
+    async fn silent_peers_are_dropped() {
+        let config = Config {
+            ping_interval: Duration::from_millis(20),
+            max_missed_pongs: 2,
+            idle_timeout: Duration::from_secs(60),
+            ..Config::default()
+        };
+        let hub = Hub::default();
+        let (tx, mut rx) = hub.outbox(config.max_queued, config.overflow);
+        let id = hub.connect(tx.clone());
+
+        // A half-open connection: nothing ever arrives.
+        let mut peer = futures::stream::pending();
+        let started = Instant::now();
+        let frame = read_messages(&mut peer, &mut None, &hub, id, &tx, &config)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(frame.code, CloseCode::Away);
+        assert_eq!(frame.reason, "ping timeout");
+        // Two unanswered pings, then the third tick gives up.
+        assert!(started.elapsed() >= Duration::from_millis(60));
+        assert!(matches!(rx.try_recv(), Some(Message::Ping(_))));
+        assert!(matches!(rx.try_recv(), Some(Message::Ping(_))));
+        assert!(rx.try_recv().is_none());
+    }
+
+    #[tokio::test]
+    async fn binary_messages_are_refused() {
+        let url = start(Config::default()).await;
+        let mut ws = connect(&url).await;
+        ws.send(Message::Binary(vec![1, 2, 3])).await.unwrap();
+        assert_eq!(close_frame(&mut ws).await.code, CloseCode::Unsupported);
+    }
+
+    #[tokio::test]
+    async fn files_are_uploaded_in_chunks() {
+        use sha2::{Digest, Sha256};
+
+        let dir = tempfile::tempdir().unwrap();
+        let url = start(Config {
+            upload_dir: dir.path().to_owned(),
+            ..Config::default()
+        })
+        .await;
+        let mut ws = connect(&url).await;
+        recv(&mut ws).await;
+
+        let contents: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let header = json!({
+            "type": "upload",
+            "name": "data.bin",
+            "size": contents.len(),
+            "sha256": hex::encode(Sha256::digest(&contents)),
+            "chunk_size": 300,
         });
+        ws.send(data(header)).await.unwrap();
+        assert_eq!(recv(&mut ws).await["next_seq"], 0);
+
+        for (seq, chunk) in contents.chunks(300).enumerate() {
+            let mut frame = (seq as u64).to_be_bytes().to_vec();
+            frame.extend_from_slice(chunk);
+            ws.send(Message::Binary(frame)).await.unwrap();
+        }
+        let end = json!({"type": "upload_end"});
+        ws.send(data(end)).await.unwrap();
+        let done = recv(&mut ws).await;
+        assert_eq!(done["type"], "upload_complete");
+        assert_eq!(done["size"], 1000);
+        assert_eq!(
+            std::fs::read(dir.path().join("data.bin")).unwrap(),
+            contents
+        );
+    }
+
+    #[tokio::test]
+    async fn streamed_audio_is_recorded() {
+        use websocket_protocol::audio::{Format, Header, SampleFormat};
+
+        let dir = tempfile::tempdir().unwrap();
+        let url = start(Config {
+            mode: Mode::Record,
+            record_dir: dir.path().to_owned(),
+            ..Config::default()
+        })
+        .await;
+        let mut ws = connect(&url).await;
+
+        let format = Format {
+            sample_format: SampleFormat::I16,
+            channels: 1,
+            sample_rate: 8000,
+        };
+        for seq in [1, 0, 2] {
+            let header = Header {
+                seq,
+                timestamp: seq * 1000,
+                format,
+                codec: Codec::Pcm,
+            };
+            let frame = header.encode(&[seq as u8, 0].repeat(8));
+            ws.send(Message::Binary(frame)).await.unwrap();
+        }
+        ws.send(Message::Binary(vec![1, 2, 3])).await.unwrap();
+        assert_eq!(recv(&mut ws).await["code"], "invalid_request");
+        // Gone without a close frame.
+        drop(ws);
+
+        let expected: Vec<i16> = (0..3).flat_map(|seq| [seq; 8]).collect();
+        let recorded = async {
+            loop {
+                sleep(Duration::from_millis(10)).await;
+                let Some(Ok(entry)) = std::fs::read_dir(dir.path()).unwrap().next() else {
+                    continue;
+                };
+                // Samples only count once the header has been finalized.
+                let Ok(mut reader) = hound::WavReader::open(entry.path()) else {
+                    continue;
+                };
+                let samples: Vec<i16> = reader.samples().map(Result::unwrap).collect();
+                if samples.len() == expected.len() {
+                    return samples;
+                }
+            }
+        };
+        let samples = timeout(Duration::from_secs(5), recorded).await.unwrap();
+        assert_eq!(samples, expected);
+    }
+
+    #[tokio::test]
+    async fn the_hello_answers_with_a_codec_the_mode_can_take() {
+        let offer = Envelope::<()>::Hello {
+            version: websocket_protocol::VERSION,
+            codecs: vec![Codec::Opus, Codec::Flac],
+        };
+        let recorded = if cfg!(feature = "opus") {
+            "opus"
+        } else {
+            "flac"
+        };
+        for (mode, chosen) in [(Mode::Record, recorded), (Mode::Echo, "pcm")] {
+            let url = start(Config {
+                mode,
+                ..Config::default()
+            })
+            .await;
+            let (mut ws, _) = connect_async(&url).await.unwrap();
+            ws.send(offer.to_message()).await.unwrap();
+            assert_eq!(recv(&mut ws).await["codecs"], json!([chosen]));
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_wss_with_a_configured_certificate() {
+        use tokio_rustls::rustls::{self, Certificate, RootCertStore};
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let (cert_path, key_path) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+        std::fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Config {
+            tls: Some(tls::load_config(&cert_path, &key_path).unwrap()),
+            ..Config::default()
+        };
+        tokio::spawn(serve(listener, Hub::default(), config));
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(&Certificate(cert.serialize_der().unwrap()))
+            .unwrap();
+        let client_config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(client_config));
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let stream = connector
+            .connect("localhost".try_into().unwrap(), stream)
+            .await
+            .unwrap();
+        let url = format!("wss://localhost:{}", addr.port());
+        let (mut ws, _) = tokio_tungstenite::client_async(url, stream).await.unwrap();
+        hello(&mut ws).await;
+        assert_eq!(recv(&mut ws).await["type"], "welcome");
+    }
+
+    #[tokio::test]
+    async fn echo_broadcast_and_sink_modes() {
+        let url = start(Config {
+            mode: Mode::Echo,
+            ..Config::default()
+        })
+        .await;
+        let mut ws = connect(&url).await;
+        ws.send(Message::Binary(vec![1, 2])).await.unwrap();
+        let msg = timeout(Duration::from_secs(5), ws.next()).await.unwrap();
+        assert_eq!(msg.unwrap().unwrap(), Message::Binary(vec![1, 2]));
+
+        let url = start(Config {
+            mode: Mode::Broadcast,
+            ..Config::default()
+        })
+        .await;
+        let mut a = connect(&url).await;
+        let mut b = connect(&url).await;
+        a.send(data(json!("to everyone"))).await.unwrap();
+        for ws in [&mut a, &mut b] {
+            assert_eq!(recv(ws).await, "to everyone");
+        }
+
+        let url = start(Config {
+            mode: Mode::Sink,
+            ..Config::default()
+        })
+        .await;
+        let mut ws = connect(&url).await;
+        ws.send(data(json!("dropped"))).await.unwrap();
+        assert!(timeout(Duration::from_millis(100), ws.next())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn clients_over_the_limit_wait() {
+        let url = start(Config {
+            mode: Mode::Echo,
+            max_connections: 1,
+            ..Config::default()
+        })
+        .await;
+        let (mut first, _) = connect_async(&url).await.unwrap();
+
+        let second = tokio::spawn(async move { connect_async(&url).await.unwrap() });
+        sleep(Duration::from_millis(100)).await;
+        assert!(!second.is_finished());
+
+        first.close(None).await.unwrap();
+        while first.next().await.is_some() {}
+        timeout(Duration::from_secs(5), second)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn upgrades_are_checked_before_they_complete() {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let url = start(Config {
+            token: Some("s3cret".to_string()),
+            ..Config::default()
+        })
+        .await;
+
+        match connect_async(&url).await {
+            Err(WsError::Http(response)) => assert_eq!(response.status(), 401),
+            other => panic!("expected a 401, got {:?}", other.map(|(_, r)| r)),
+        }
+
+        let mut request = format!("{}/?token=s3cret", url)
+            .into_client_request()
+            .unwrap();
+        let headers = request.headers_mut();
+        headers.insert("Sec-WebSocket-Protocol", "envelope.v1".parse().unwrap());
+        let (mut ws, response) = connect_async(request).await.unwrap();
+        assert_eq!(response.headers()["Sec-WebSocket-Protocol"], "envelope.v1");
+        hello(&mut ws).await;
+    }
+
+    #[tokio::test]
+    async fn clients_that_fall_behind_are_disconnected() {
+        let config = Config {
+            max_queued: 2,
+            ..Config::default()
+        };
+        let hub = Hub::default();
+        let (tx, mut rx) = hub.outbox(config.max_queued, config.overflow);
+        let id = hub.connect(tx.clone());
+        // Nothing is written to the socket, so the third message overflows.
+        for n in 0..3 {
+            hub.broadcast_all(Message::Text(n.to_string()));
+        }
+
+        let mut peer = futures::stream::pending();
+        let mut recording = None;
+        let frame = read_messages(&mut peer, &mut recording, &hub, id, &tx, &config);
+        let frame = timeout(Duration::from_secs(5), frame).await.unwrap();
+        assert_eq!(frame.unwrap().unwrap().reason, "too slow to keep up");
+        assert!(rx.try_recv().is_none());
+        assert_eq!(hub.queue_stats().disconnected, 1);
     }
 }
@@ -0,0 +1,321 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use tokio::sync::Notify;
+use tokio_tungstenite::tungstenite::Message;
+
+/// What happens to a message for a client whose queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overflow {
+    /// Make room by dropping the oldest queued message.
+    DropOldest,
+    /// Drop the new message.
+    DropNewest,
+    /// Give up on the client and close the connection.
+    #[default]
+    Disconnect,
+}
+
+impl Overflow {
+    pub const NAMES: [&'static str; 3] = ["drop-oldest", "drop-newest", "disconnect"];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Overflow::DropOldest => "drop-oldest",
+            Overflow::DropNewest => "drop-newest",
+            Overflow::Disconnect => "disconnect",
+        }
+    }
+}
+
+impl FromStr for Overflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Overflow, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "drop-oldest" => Ok(Overflow::DropOldest),
+            "drop-newest" => Ok(Overflow::DropNewest),
+            "disconnect" => Ok(Overflow::Disconnect),
+            _ => Err(format!("unknown overflow policy '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Overflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.name().fmt(f)
+    }
+}
+
+/// Running totals across every queue on the server.
+#[derive(Debug, Default)]
+pub struct Counters {
+    dropped: AtomicU64,
+    disconnected: AtomicU64,
+}
+
+/// A snapshot of the outbound queues, as reported by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct QueueStats {
+    pub clients: usize,
+    /// Messages waiting across every queue.
+    pub queued: usize,
+    /// The longest single queue.
+    pub deepest: usize,
+    /// Messages dropped to overflow since the server started.
+    pub dropped: u64,
+    /// Clients closed for falling behind since the server started.
+    pub disconnected: u64,
+}
+
+impl QueueStats {
+    pub fn new(depths: impl Iterator<Item = usize>, counters: &Counters) -> Self {
+        let mut stats = QueueStats {
+            dropped: counters.dropped.load(Ordering::Relaxed),
+            disconnected: counters.disconnected.load(Ordering::Relaxed),
+            ..QueueStats::default()
+        };
+        for depth in depths {
+            stats.clients += 1;
+            stats.queued += depth;
+            stats.deepest = stats.deepest.max(depth);
+        }
+        stats
+    }
+}
+
+/// Why a message was not queued.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    /// The connection is gone.
+    Closed,
+    /// The queue was full and the message, or the client, was dropped.
+    Overflow,
+}
+
+/// One client's outbound queue: many senders, and the connection's writer
+/// task at the other end.
+///
+/// Close frames always get in, even past the capacity, so a connection can
+/// be closed however far behind it is.
+pub fn channel(capacity: usize, policy: Overflow, counters: Arc<Counters>) -> (Sender, Receiver) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            queue: VecDeque::new(),
+            senders: 1,
+            receiver_gone: false,
+            overflowed: false,
+        }),
+        capacity,
+        policy,
+        counters,
+        message: Notify::new(),
+        overflow: Notify::new(),
+    });
+    let receiver = Receiver {
+        shared: shared.clone(),
+    };
+    (Sender { shared }, receiver)
+}
+
+struct Shared {
+    state: Mutex<State>,
+    capacity: usize,
+    policy: Overflow,
+    counters: Arc<Counters>,
+    /// Wakes the receiver.
+    message: Notify,
+    /// Wakes whoever waits in `Sender::overflowed`.
+    overflow: Notify,
+}
+
+struct State {
+    queue: VecDeque<Message>,
+    senders: usize,
+    receiver_gone: bool,
+    /// Set once under `Overflow::Disconnect`; from then on only close
+    /// frames are taken.
+    overflowed: bool,
+}
+
+pub struct Sender {
+    shared: Arc<Shared>,
+}
+
+impl Sender {
+    pub fn send(&self, msg: Message) -> Result<(), SendError> {
+        let shared = &self.shared;
+        let mut state = shared.state.lock().unwrap();
+        if state.receiver_gone {
+            return Err(SendError::Closed);
+        }
+        if !msg.is_close() {
+            if state.overflowed {
+                return Err(SendError::Overflow);
+            }
+            if state.queue.len() >= shared.capacity {
+                match shared.policy {
+                    Overflow::DropOldest => {
+                        state.queue.pop_front();
+                        shared.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Overflow::DropNewest => {
+                        shared.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                        return Err(SendError::Overflow);
+                    }
+                    Overflow::Disconnect => {
+                        // What is queued would only delay the close frame.
+                        state.queue.clear();
+                        state.overflowed = true;
+                        shared.counters.disconnected.fetch_add(1, Ordering::Relaxed);
+                        shared.overflow.notify_one();
+                        return Err(SendError::Overflow);
+                    }
+                }
+            }
+        }
+        state.queue.push_back(msg);
+        shared.message.notify_one();
+        Ok(())
+    }
+
+    /// Messages waiting to be written.
+    pub fn len(&self) -> usize {
+        self.shared.state.lock().unwrap().queue.len()
+    }
+
+    /// Wait until the queue has overflowed under `Overflow::Disconnect`.
+    /// Never returns under the other policies.
+    pub async fn overflowed(&self) {
+        loop {
+            if self.shared.state.lock().unwrap().overflowed {
+                return;
+            }
+            self.shared.overflow.notified().await;
+        }
+    }
+}
+
+impl Clone for Sender {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().senders -= 1;
+        self.shared.message.notify_one();
+    }
+}
+
+pub struct Receiver {
+    shared: Arc<Shared>,
+}
+
+impl Receiver {
+    /// The next message, or `None` once the queue is empty and every sender
+    /// is gone.
+    pub async fn recv(&mut self) -> Option<Message> {
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if let Some(msg) = state.queue.pop_front() {
+                    return Some(msg);
+                }
+                if state.senders == 0 {
+                    return None;
+                }
+            }
+            // A notification sent before this point is kept as a permit.
+            self.shared.message.notified().await;
+        }
+    }
+
+    #[cfg(test)]
+    pub fn try_recv(&mut self) -> Option<Message> {
+        self.shared.state.lock().unwrap().queue.pop_front()
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.receiver_gone = true;
+        state.queue.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(n: usize) -> Message {
+        Message::Text(n.to_string())
+    }
+
+    fn fill(policy: Overflow) -> (Sender, Receiver, Arc<Counters>) {
+        let counters = Arc::new(Counters::default());
+        let (tx, rx) = channel(3, policy, counters.clone());
+        for n in 0..5 {
+            let _ = tx.send(text(n));
+        }
+        (tx, rx, counters)
+    }
+
+    fn drain(rx: &mut Receiver) -> Vec<Message> {
+        std::iter::from_fn(|| rx.try_recv()).collect()
+    }
+
+    #[test]
+    fn full_queues_drop_by_policy() {
+        let (_tx, mut rx, counters) = fill(Overflow::DropOldest);
+        assert_eq!(drain(&mut rx), [text(2), text(3), text(4)]);
+        assert_eq!(counters.dropped.load(Ordering::Relaxed), 2);
+
+        let (tx, mut rx, counters) = fill(Overflow::DropNewest);
+        assert_eq!(tx.send(text(5)), Err(SendError::Overflow));
+        assert_eq!(drain(&mut rx), [text(0), text(1), text(2)]);
+        assert_eq!(counters.dropped.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn slow_consumers_are_disconnected() {
+        let (tx, mut rx, counters) = fill(Overflow::Disconnect);
+        tokio::time::timeout(std::time::Duration::from_secs(1), tx.overflowed())
+            .await
+            .unwrap();
+        assert_eq!(counters.disconnected.load(Ordering::Relaxed), 1);
+        assert_eq!(tx.send(text(5)), Err(SendError::Overflow));
+
+        // Only the close frame goes out.
+        tx.send(Message::Close(None)).unwrap();
+        drop(tx);
+        assert_eq!(rx.recv().await, Some(Message::Close(None)));
+        assert_eq!(rx.recv().await, None);
+    }
+
+    #[test]
+    fn stats_sum_the_queues() {
+        let counters = Counters::default();
+        counters.dropped.store(4, Ordering::Relaxed);
+        let stats = QueueStats::new([2, 0, 7].into_iter(), &counters);
+        assert_eq!(
+            stats,
+            QueueStats {
+                clients: 3,
+                queued: 9,
+                deepest: 7,
+                dropped: 4,
+                disconnected: 0,
+            }
+        );
+    }
+}
@@ -0,0 +1,80 @@
+use serde::{Deserialize, Serialize};
+
+use crate::hub::ClientId;
+
+/// A chat request, sent by a client as the body of a `data` envelope and
+/// tagged by its own `type` field:
+///
+/// ```json
+/// {"type": "data", "body": {"type": "join", "room": "lobby"}}
+/// {"type": "data", "body": {"type": "message", "room": "lobby", "text": "hi all"}}
+/// {"type": "data", "body": {"type": "leave", "room": "lobby"}}
+/// ```
+///
+/// Files are uploaded with an `upload` request, then binary chunks, each
+/// prefixed by its big-endian `u64` sequence number, then `upload_end`:
+///
+/// ```json
+/// {"type": "data", "body": {"type": "upload", "name": "a.wav", "size": 1048576, "sha256": "9f86…", "chunk_size": 65536}}
+/// {"type": "data", "body": {"type": "upload_end"}}
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Join {
+        room: String,
+    },
+    Leave {
+        room: String,
+    },
+    Message {
+        room: String,
+        text: String,
+    },
+    Upload {
+        name: String,
+        size: u64,
+        sha256: String,
+        chunk_size: u64,
+    },
+    UploadEnd,
+}
+
+/// A chat message sent by the server in a `data` envelope, tagged the same
+/// way. Requests that fail are answered with an `error` envelope instead.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// The first message on a connection, telling the client its id.
+    Welcome { id: ClientId },
+    /// The client is now in `room`, along with `members`, itself included.
+    Joined {
+        room: String,
+        members: Vec<ClientId>,
+    },
+    /// The client is no longer in `room`.
+    Left { room: String },
+    /// Another client joined or left a room this client is in.
+    Presence {
+        room: String,
+        client: ClientId,
+        event: PresenceEvent,
+    },
+    Message {
+        room: String,
+        from: ClientId,
+        text: String,
+    },
+    /// Send the chunks of `name` from `next_seq` on. Not zero when an
+    /// earlier upload of the same file is being resumed.
+    UploadReady { name: String, next_seq: u64 },
+    /// The file arrived whole and matched its hash.
+    UploadComplete { name: String, size: u64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceEvent {
+    Join,
+    Leave,
+}
@@ -0,0 +1,484 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Serialize;
+use websocket_codec::Decoder;
+use websocket_protocol::audio::{Codec, Format, Header, SampleFormat};
+
+/// Frames held back waiting for one that is missing. Past this the missing
+/// frame is given up on.
+pub const REORDER_WINDOW: usize = 50;
+
+/// The longest gap filled with silence. A timestamp further ahead than this
+/// is taken to be wrong rather than a real gap.
+const MAX_SILENCE: Duration = Duration::from_secs(10);
+
+/// The most bytes of silence one gap gets, whatever the format: ten seconds
+/// of 384 kHz audio in 32 channels would be half a gigabyte.
+const MAX_SILENCE_BYTES: u64 = 16 << 20;
+
+/// The most silence one recording gets in all, in time and in bytes. Past
+/// this gaps are closed up, so a client sending wild timestamps cannot fill
+/// the disk with nothing.
+const MAX_TOTAL_SILENCE: Duration = Duration::from_secs(60);
+const MAX_TOTAL_SILENCE_BYTES: u64 = 64 << 20;
+
+/// Names tried after the one asked for, before a recording gives up.
+const MAX_RENAMES: u32 = 1000;
+
+/// One connection's stream of audio frames, written to a WAV file.
+///
+/// Frames are put back in sequence order before they are written, and
+/// decoded to PCM on the way. Missing frames become silence, so the file
+/// keeps the stream's timing. The file is
+/// only created once the first frame arrives, and is finalized when the
+/// recording is finished or dropped, however the connection ended. It never
+/// replaces an existing file: if the name is taken, a number is added to it.
+pub struct Recording {
+    path: PathBuf,
+    writer: Option<hound::WavWriter<BufWriter<File>>>,
+    format: Option<(Format, Codec)>,
+    decoder: Option<Decoder>,
+    next_seq: u64,
+    /// Sample frames written, silence included.
+    position: u64,
+    /// Sample frames of silence written.
+    silence: u64,
+    pending: BTreeMap<u64, (Header, Vec<u8>)>,
+    stats: Stats,
+}
+
+/// What a recording took in, as reported when it ends.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Stats {
+    pub path: PathBuf,
+    /// Frames written to the file.
+    pub frames: u64,
+    /// Frames that came after their place had been written, or twice.
+    pub late: u64,
+    /// Runs of frames that never came.
+    pub gaps: u64,
+    /// Frames that never came.
+    pub missing: u64,
+    /// Seconds of audio in the file, silence included.
+    pub duration_secs: f64,
+}
+
+impl Recording {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        Recording {
+            stats: Stats {
+                path: path.clone(),
+                ..Stats::default()
+            },
+            path,
+            writer: None,
+            format: None,
+            decoder: None,
+            next_seq: 0,
+            position: 0,
+            silence: 0,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Take in one binary frame. Every frame has to have the format and
+    /// codec of the first.
+    ///
+    /// This writes to the file, up to [`MAX_SILENCE_BYTES`] of silence as
+    /// well as the frame, so async callers run it on a blocking thread.
+    pub fn frame(&mut self, frame: &[u8]) -> Result<(), String> {
+        let (header, samples) = Header::decode(frame)?;
+        // The frame after it could not be numbered.
+        if header.seq == u64::MAX {
+            return Err("frame sequence numbers have run out".to_string());
+        }
+        match self.format {
+            Some((format, codec)) if (format, codec) != (header.format, header.codec) => {
+                return Err(format!(
+                    "the stream started as {:?} in {} and cannot change to {:?} in {}",
+                    format, codec, header.format, header.codec
+                ));
+            }
+            Some(_) => {}
+            None => self.open(header.format, header.codec)?,
+        }
+
+        if header.seq < self.next_seq || self.pending.contains_key(&header.seq) {
+            self.stats.late += 1;
+            return Ok(());
+        }
+        self.pending.insert(header.seq, (header, samples.to_vec()));
+        self.write_pending()?;
+        if self.pending.len() > REORDER_WINDOW {
+            self.skip_gap();
+            self.write_pending()?;
+        }
+        Ok(())
+    }
+
+    /// Write what is still held back and finalize the file. Returns `None`
+    /// if no audio ever came.
+    pub fn finish(&mut self) -> Result<Option<Stats>, String> {
+        while !self.pending.is_empty() {
+            self.skip_gap();
+            self.write_pending()?;
+        }
+        let Some(writer) = self.writer.take() else {
+            return Ok(None);
+        };
+        writer
+            .finalize()
+            .map_err(|e| format!("cannot finish recording: {}", e))?;
+        Ok(Some(self.stats.clone()))
+    }
+
+    fn open(&mut self, format: Format, codec: Codec) -> Result<(), String> {
+        let decoder = Decoder::new(codec, format)?;
+        let spec = hound::WavSpec {
+            channels: format.channels,
+            sample_rate: format.sample_rate,
+            bits_per_sample: (format.sample_format.bytes() * 8) as u16,
+            sample_format: match format.sample_format {
+                SampleFormat::I16 => hound::SampleFormat::Int,
+                SampleFormat::F32 => hound::SampleFormat::Float,
+            },
+        };
+        let created = self
+            .path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| create_new(&self.path))
+            .map_err(hound::Error::IoError)
+            .and_then(|(path, file)| {
+                let writer = hound::WavWriter::new(BufWriter::new(file), spec)?;
+                Ok((path, writer))
+            });
+        let (path, writer) = created.map_err(|e| format!("cannot start recording: {}", e))?;
+        self.path = path.clone();
+        self.stats.path = path;
+        self.writer = Some(writer);
+        self.format = Some((format, codec));
+        self.decoder = Some(decoder);
+        Ok(())
+    }
+
+    /// Decode and write held back frames for as long as they follow on.
+    /// Frames are decoded in order, as Opus needs them to be.
+    fn write_pending(&mut self) -> Result<(), String> {
+        while let Some((header, payload)) = self.pending.remove(&self.next_seq) {
+            self.next_seq += 1;
+            let decoder = self.decoder.as_mut().expect("opened on the first frame");
+            let samples = decoder
+                .decode(&payload)
+                .map_err(|e| format!("cannot decode frame {}: {}", header.seq, e))?;
+            self.write(header, &samples)
+                .map_err(|e| format!("cannot write recording: {}", e))?;
+        }
+        Ok(())
+    }
+
+    /// Give up on the frames before the first one held back.
+    fn skip_gap(&mut self) {
+        if let Some(&seq) = self.pending.keys().next() {
+            self.stats.gaps += 1;
+            self.stats.missing += seq - self.next_seq;
+            self.next_seq = seq;
+        }
+    }
+
+    fn write(&mut self, header: Header, samples: &[u8]) -> hound::Result<()> {
+        let format = header.format;
+        let writer = self.writer.as_mut().expect("opened on the first frame");
+
+        // Where the frame belongs, rounded to the nearest sample, since the
+        // timestamp was rounded down to a microsecond. Any timestamp times
+        // any rate fits in a `u128`.
+        let rate = format.sample_rate as u64;
+        let at = (header.timestamp as u128 * rate as u128 + 500_000) / 1_000_000;
+        let frame_bytes = format.frame_bytes() as u64;
+        let most = (MAX_SILENCE.as_secs() * rate).min(MAX_SILENCE_BYTES / frame_bytes);
+        let total = (MAX_TOTAL_SILENCE.as_secs() * rate).min(MAX_TOTAL_SILENCE_BYTES / frame_bytes);
+        let left = total.saturating_sub(self.silence);
+        let silence = at
+            .saturating_sub(self.position as u128)
+            .min(most.min(left) as u128) as u64;
+        for _ in 0..silence * format.channels as u64 {
+            match format.sample_format {
+                SampleFormat::I16 => writer.write_sample(0i16)?,
+                SampleFormat::F32 => writer.write_sample(0f32)?,
+            }
+        }
+
+        match format.sample_format {
+            SampleFormat::I16 => {
+                for sample in samples.chunks_exact(2) {
+                    writer.write_sample(i16::from_le_bytes([sample[0], sample[1]]))?;
+                }
+            }
+            SampleFormat::F32 => {
+                for sample in samples.chunks_exact(4) {
+                    writer.write_sample(f32::from_le_bytes(sample.try_into().unwrap()))?;
+                }
+            }
+        }
+
+        self.silence += silence;
+        self.position += silence + (samples.len() / format.frame_bytes()) as u64;
+        self.stats.frames += 1;
+        self.stats.duration_secs = self.position as f64 / rate as f64;
+        Ok(())
+    }
+}
+
+/// Create a new file at `path`, or if there is one, at `path` with `-1`,
+/// `-2` and so on added to its name.
+fn create_new(path: &Path) -> io::Result<(PathBuf, File)> {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().unwrap_or_default().to_string_lossy();
+    for n in 0..=MAX_RENAMES {
+        let candidate = match n {
+            0 => path.to_path_buf(),
+            n => path.with_file_name(format!("{}-{}.{}", stem, n, extension)),
+        };
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&candidate)
+        {
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            opened => return opened.map(|file| (candidate, file)),
+        }
+    }
+    Err(io::Error::new(
+        ErrorKind::AlreadyExists,
+        format!("{} and {} more like it exist", path.display(), MAX_RENAMES),
+    ))
+}
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        match self.finish() {
+            Ok(Some(stats)) => {
+                let stats = serde_json::to_string(&stats).unwrap();
+                println!("Recording stats: {}", stats);
+            }
+            Ok(None) => {}
+            Err(e) => println!("Error finishing {:?}: {}", self.path, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MONO: Format = Format {
+        sample_format: SampleFormat::I16,
+        channels: 1,
+        sample_rate: 1000,
+    };
+
+    /// Frame `seq` of a stream of 10 ms frames, its samples all `seq`.
+    fn frame(seq: u64) -> Vec<u8> {
+        let header = Header {
+            seq,
+            timestamp: seq * 10_000,
+            format: MONO,
+            codec: Codec::Pcm,
+        };
+        header.encode(&[seq as u8, 0].repeat(10))
+    }
+
+    fn samples(path: &std::path::Path) -> Vec<i16> {
+        let mut reader = hound::WavReader::open(path).unwrap();
+        reader.samples().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn frames_are_written_in_sequence() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.wav");
+        let mut recording = Recording::new(&path);
+        for seq in [0, 2, 1, 1, 3] {
+            recording.frame(&frame(seq)).unwrap();
+        }
+        let stats = recording.finish().unwrap().unwrap();
+        assert_eq!((stats.frames, stats.late, stats.gaps), (4, 1, 0));
+        assert_eq!(stats.duration_secs, 0.04);
+
+        let expected: Vec<i16> = (0..4).flat_map(|seq| [seq; 10]).collect();
+        assert_eq!(samples(&path), expected);
+    }
+
+    #[test]
+    fn missing_frames_become_silence() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.wav");
+        let mut recording = Recording::new(&path);
+        recording.frame(&frame(0)).unwrap();
+        // Frame 1 never comes; the rest wait for it until they're finished.
+        recording.frame(&frame(3)).unwrap();
+        recording.frame(&frame(2)).unwrap();
+        let stats = recording.finish().unwrap().unwrap();
+        assert_eq!((stats.frames, stats.gaps, stats.missing), (3, 1, 1));
+
+        let mut expected = vec![0; 20];
+        expected.extend([2; 10]);
+        expected.extend([3; 10]);
+        assert_eq!(samples(&path), expected);
+    }
+
+    #[test]
+    fn silence_is_bounded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.wav");
+        let mut recording = Recording::new(&path);
+        let at = |seq, timestamp| {
+            let header = Header {
+                seq,
+                timestamp,
+                format: MONO,
+                codec: Codec::Pcm,
+            };
+            header.encode(&[1, 0].repeat(10))
+        };
+        recording.frame(&at(0, 0)).unwrap();
+        // Each gap is cut to ten seconds, until a minute has been spent.
+        for seq in 1..=8 {
+            recording.frame(&at(seq, seq * 20_000_000)).unwrap();
+        }
+        recording.frame(&at(9, u64::MAX)).unwrap();
+        assert!(recording.frame(&at(u64::MAX, u64::MAX)).is_err());
+        let stats = recording.finish().unwrap().unwrap();
+        assert_eq!(stats.frames, 10);
+        assert_eq!(samples(&path).len(), 60 * 1000 + 10 * 10);
+    }
+
+    #[test]
+    fn silence_is_bounded_in_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.wav");
+        let mut recording = Recording::new(&path);
+        let format = Format {
+            sample_format: SampleFormat::F32,
+            channels: 32,
+            sample_rate: 384_000,
+        };
+        let at = |seq, timestamp| {
+            let header = Header {
+                seq,
+                timestamp,
+                format,
+                codec: Codec::Pcm,
+            };
+            header.encode(&[0; 128])
+        };
+        recording.frame(&at(0, 0)).unwrap();
+        // Five seconds would be 245 MB; the gap gets 16 MiB.
+        recording.frame(&at(1, 5_000_000)).unwrap();
+        recording.finish().unwrap().unwrap();
+        let reader = hound::WavReader::open(&path).unwrap();
+        assert_eq!(reader.duration() as u64, MAX_SILENCE_BYTES / 128 + 2);
+    }
+
+    #[test]
+    fn recordings_never_overwrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.wav");
+        fs::write(&path, b"keep").unwrap();
+        fs::write(dir.path().join("session-1.wav"), b"keep").unwrap();
+
+        let mut recording = Recording::new(&path);
+        recording.frame(&frame(0)).unwrap();
+        let stats = recording.finish().unwrap().unwrap();
+        assert_eq!(stats.path, dir.path().join("session-2.wav"));
+        assert_eq!(samples(&stats.path), [0; 10]);
+        assert_eq!(fs::read(&path).unwrap(), b"keep");
+    }
+
+    #[test]
+    fn the_format_cannot_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut recording = Recording::new(dir.path().join("session.wav"));
+        recording.frame(&frame(0)).unwrap();
+        let stereo = Format {
+            channels: 2,
+            ..MONO
+        };
+        let header = Header {
+            seq: 1,
+            timestamp: 10_000,
+            format: stereo,
+            codec: Codec::Pcm,
+        };
+        assert!(recording.frame(&header.encode(&[0; 4])).is_err());
+        let flac = Header {
+            format: MONO,
+            codec: Codec::Flac,
+            ..header
+        };
+        assert!(recording.frame(&flac.encode(&[0; 4])).is_err());
+        assert!(recording.frame(&[1, 2, 3]).is_err());
+
+        let mut empty = Recording::new(dir.path().join("nothing.wav"));
+        assert_eq!(empty.finish(), Ok(None));
+        assert!(!dir.path().join("nothing.wav").exists());
+    }
+
+    #[test]
+    fn compressed_frames_are_decoded_before_writing() {
+        use websocket_codec::Encoder;
+
+        let dir = tempfile::tempdir().unwrap();
+        let format = Format {
+            sample_rate: 8000,
+            ..MONO
+        };
+        // A rising ramp, in 20 ms frames.
+        let ramp: Vec<i16> = (0..800).map(|n| n * 40 - 16_000).collect();
+        let frames = |codec| {
+            let mut encoder = Encoder::new(codec, format, 160).unwrap();
+            let frames: Vec<Vec<u8>> = ramp
+                .chunks(160)
+                .enumerate()
+                .map(|(seq, samples)| {
+                    let bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+                    let header = Header {
+                        seq: seq as u64,
+                        timestamp: seq as u64 * 20_000,
+                        format,
+                        codec,
+                    };
+                    header.encode(&encoder.encode(&bytes).unwrap())
+                })
+                .collect();
+            frames
+        };
+
+        let path = dir.path().join("flac.wav");
+        let mut recording = Recording::new(&path);
+        let flac = frames(Codec::Flac);
+        for seq in [0, 2, 1, 3, 4] {
+            recording.frame(&flac[seq]).unwrap();
+        }
+        recording.finish().unwrap();
+        assert_eq!(samples(&path), ramp);
+
+        // Opus keeps the length, if not the samples.
+        if cfg!(feature = "opus") {
+            let path = dir.path().join("opus.wav");
+            let mut recording = Recording::new(&path);
+            for frame in frames(Codec::Opus) {
+                recording.frame(&frame).unwrap();
+            }
+            let stats = recording.finish().unwrap().unwrap();
+            assert_eq!((stats.frames, stats.duration_secs), (5, 0.1));
+            assert_eq!(samples(&path).len(), ramp.len());
+        }
+    }
+}
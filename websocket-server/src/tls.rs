@@ -0,0 +1,35 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+
+use crate::AnyError;
+
+/// Build the TLS config for `wss://` from a PEM certificate chain and a PEM
+/// private key, in PKCS#8, RSA or SEC1 form.
+pub fn load_config(cert: &Path, key: &Path) -> Result<Arc<ServerConfig>, AnyError> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))?;
+    if certs.is_empty() {
+        return Err(format!("no certificates in {}", cert.display()).into());
+    }
+    let certs = certs.into_iter().map(Certificate).collect();
+
+    let mut reader = BufReader::new(File::open(key)?);
+    let key = loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => break PrivateKey(key),
+            Some(_) => continue,
+            None => return Err(format!("no private key in {}", key.display()).into()),
+        }
+    };
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(Arc::new(config))
+}
@@ -0,0 +1,472 @@
+use std::collections::BTreeSet;
+use std::io::{ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::protocol::ServerMessage;
+
+/// The largest chunk a client may ask for.
+pub const MAX_CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Bytes before the data in a binary chunk: its big-endian sequence number.
+pub const CHUNK_HEADER_LEN: usize = 8;
+
+/// How long a part file nobody writes to is kept for its upload to be
+/// resumed.
+const PART_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Part files some connection is writing to, across the whole server.
+static WRITING: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// The file uploads of one connection, written into `dir`.
+///
+/// An upload starts with an `upload` message naming the file, its size and
+/// its SHA-256, followed by binary chunks and an `upload_end` message. Data
+/// goes to a part file named after the hash, so a client that reconnects and
+/// announces the same file again is told which chunk to continue from. The
+/// file is moved to its name only once the hash matches, and never over a
+/// file already there.
+///
+/// A part file has one writer at a time. Those left behind count against
+/// `max_unfinished` until they are resumed, or removed after a day.
+pub struct Uploads {
+    dir: PathBuf,
+    max_size: u64,
+    max_unfinished: u64,
+    current: Option<Upload>,
+}
+
+struct Upload {
+    name: String,
+    size: u64,
+    sha256: String,
+    chunk_size: u64,
+    next_seq: u64,
+    written: u64,
+    part: PathBuf,
+    file: File,
+    _writing: Writing,
+}
+
+impl Uploads {
+    /// Uploads into `dir` of files up to `max_size` bytes, with at most
+    /// `max_unfinished` bytes in part files there.
+    pub fn new(dir: impl Into<PathBuf>, max_size: u64, max_unfinished: u64) -> Self {
+        Uploads {
+            dir: dir.into(),
+            max_size,
+            max_unfinished,
+            current: None,
+        }
+    }
+
+    pub fn in_progress(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// Start, or pick up again, the upload of a file. Replaces any upload
+    /// already in progress; its part file is kept to be resumed later.
+    pub async fn start(
+        &mut self,
+        name: String,
+        size: u64,
+        sha256: String,
+        chunk_size: u64,
+    ) -> Result<ServerMessage, String> {
+        self.current = None;
+        if !valid_name(&name) {
+            return Err(format!("invalid file name '{}'", name));
+        }
+        let sha256 = sha256.to_ascii_lowercase();
+        if sha256.len() != 64 || !sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err("sha256 must be 64 hex digits".to_string());
+        }
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+            return Err(format!(
+                "chunk size must be between 1 and {}",
+                MAX_CHUNK_SIZE
+            ));
+        }
+        if size > self.max_size {
+            return Err(format!(
+                "file is {} bytes, more than the {} allowed",
+                size, self.max_size
+            ));
+        }
+        if fs::try_exists(self.dir.join(&name)).await.unwrap_or(false) {
+            return Err(format!("'{}' already exists", name));
+        }
+
+        let part = self.dir.join(format!(".{}.part", sha256));
+        let writing =
+            Writing::claim(&part).ok_or_else(|| format!("'{}' is already being uploaded", name))?;
+        let unfinished = sweep_parts(&self.dir, &part)
+            .await
+            .map_err(|e| format!("cannot open upload: {}", e))?;
+        if unfinished + size > self.max_unfinished {
+            return Err(format!(
+                "unfinished uploads take up {} of the {} bytes allowed",
+                unfinished, self.max_unfinished
+            ));
+        }
+        let (file, next_seq) = open_part(&self.dir, &part, size, chunk_size)
+            .await
+            .map_err(|e| format!("cannot open upload: {}", e))?;
+        self.current = Some(Upload {
+            name: name.clone(),
+            size,
+            sha256,
+            chunk_size,
+            next_seq,
+            written: next_seq * chunk_size,
+            part,
+            file,
+            _writing: writing,
+        });
+        Ok(ServerMessage::UploadReady { name, next_seq })
+    }
+
+    /// Write one binary chunk. A chunk out of sequence ends the upload; the
+    /// client has to announce the file again to learn where to resume.
+    pub async fn chunk(&mut self, frame: &[u8]) -> Result<(), String> {
+        let result = self.write_chunk(frame).await;
+        if result.is_err() {
+            self.current = None;
+        }
+        result
+    }
+
+    async fn write_chunk(&mut self, frame: &[u8]) -> Result<(), String> {
+        let upload = self.current.as_mut().ok_or("no upload in progress")?;
+        if frame.len() < CHUNK_HEADER_LEN {
+            return Err("chunk is missing its sequence number".to_string());
+        }
+        let (seq, data) = frame.split_at(CHUNK_HEADER_LEN);
+        let seq = u64::from_be_bytes(seq.try_into().unwrap());
+        if seq != upload.next_seq {
+            return Err(format!("expected chunk {}, got {}", upload.next_seq, seq));
+        }
+
+        let len = data.len() as u64;
+        let end = upload.written + len;
+        if end > upload.size {
+            return Err(format!("chunk {} goes past the end of the file", seq));
+        }
+        // Only the last chunk may be short, so every chunk starts at
+        // `seq * chunk_size` and resuming can count whole chunks.
+        if len != upload.chunk_size && end != upload.size {
+            return Err(format!("chunk {} is short", seq));
+        }
+
+        // Flushing waits for tokio's background write, so a failure shows up
+        // here rather than on a later chunk.
+        let written = async {
+            upload.file.write_all(data).await?;
+            upload.file.flush().await
+        };
+        written
+            .await
+            .map_err(|e| format!("cannot write upload: {}", e))?;
+        upload.next_seq += 1;
+        upload.written = end;
+        Ok(())
+    }
+
+    /// Check the finished file against its hash and move it into place.
+    pub async fn finish(&mut self) -> Result<ServerMessage, String> {
+        let upload = self.current.take().ok_or("no upload in progress")?;
+        if upload.written != upload.size {
+            let message = format!(
+                "upload incomplete: {} of {} bytes",
+                upload.written, upload.size
+            );
+            // More chunks may still follow.
+            self.current = Some(upload);
+            return Err(message);
+        }
+
+        let hash = sha256_file(&upload.part)
+            .await
+            .map_err(|e| format!("cannot read upload: {}", e))?;
+        if hash != upload.sha256 {
+            let _ = fs::remove_file(&upload.part).await;
+            return Err(format!(
+                "sha256 mismatch: expected {}, got {}",
+                upload.sha256, hash
+            ));
+        }
+
+        // Linking fails if the name was taken since the upload started,
+        // where renaming would replace that file.
+        let stored = fs::hard_link(&upload.part, self.dir.join(&upload.name)).await;
+        let _ = fs::remove_file(&upload.part).await;
+        stored.map_err(|e| match e.kind() {
+            ErrorKind::AlreadyExists => format!("'{}' already exists", upload.name),
+            _ => format!("cannot store upload: {}", e),
+        })?;
+        Ok(ServerMessage::UploadComplete {
+            name: upload.name,
+            size: upload.size,
+        })
+    }
+}
+
+/// A part file's claim on [`WRITING`], given up when dropped.
+struct Writing(PathBuf);
+
+impl Writing {
+    /// `None` if another upload is writing to `part`.
+    fn claim(part: &Path) -> Option<Writing> {
+        let mut writing = WRITING.lock().unwrap();
+        writing
+            .insert(part.to_owned())
+            .then(|| Writing(part.to_owned()))
+    }
+}
+
+impl Drop for Writing {
+    fn drop(&mut self) {
+        WRITING.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Remove part files in `dir` left alone for longer than [`PART_TTL`], and
+/// return the bytes the others, save `own`, take up.
+async fn sweep_parts(dir: &Path, own: &Path) -> std::io::Result<u64> {
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let mut total = 0;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if path == own || !name.starts_with('.') || !name.ends_with(".part") {
+            continue;
+        }
+        let metadata = entry.metadata().await?;
+        let stale = metadata
+            .modified()?
+            .elapsed()
+            .is_ok_and(|age| age > PART_TTL);
+        if stale && !WRITING.lock().unwrap().contains(&path) {
+            let _ = fs::remove_file(&path).await;
+        } else {
+            total += metadata.len();
+        }
+    }
+    Ok(total)
+}
+
+/// A plain file name: no directories, and not hidden like the part files.
+fn valid_name(name: &str) -> bool {
+    !name.starts_with('.') && Path::new(name).file_name() == Some(name.as_ref())
+}
+
+/// Open the part file for appending, dropping any partial chunk at its end.
+/// Returns the file and the sequence number of the next chunk.
+async fn open_part(
+    dir: &Path,
+    part: &Path,
+    size: u64,
+    chunk_size: u64,
+) -> std::io::Result<(File, u64)> {
+    fs::create_dir_all(dir).await?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(part)
+        .await?;
+
+    let len = file.metadata().await?.len();
+    // More data than the file should have means it belongs to something else.
+    let next_seq = if len > size { 0 } else { len / chunk_size };
+    let written = next_seq * chunk_size;
+    file.set_len(written).await?;
+    file.seek(SeekFrom::Start(written)).await?;
+    Ok((file, next_seq))
+}
+
+pub async fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(seq: u64, data: &[u8]) -> Vec<u8> {
+        let mut frame = seq.to_be_bytes().to_vec();
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        hex::encode(Sha256::digest(data))
+    }
+
+    #[tokio::test]
+    async fn upload_is_resumed_and_verified() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = b"hello, resumable world";
+        let hash = sha256(data);
+
+        let mut uploads = Uploads::new(dir.path(), 1024, 1024);
+        let ready = uploads.start("a.txt".into(), 22, hash.clone(), 8).await;
+        assert_eq!(
+            ready,
+            Ok(ServerMessage::UploadReady {
+                name: "a.txt".into(),
+                next_seq: 0
+            })
+        );
+        uploads.chunk(&chunk(0, &data[..8])).await.unwrap();
+        // The connection drops halfway through the second chunk's write.
+        uploads.chunk(&chunk(1, &data[8..16])).await.unwrap();
+        drop(uploads);
+        let part = dir.path().join(format!(".{}.part", hash));
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&part)
+            .unwrap()
+            .set_len(12)
+            .unwrap();
+
+        let mut uploads = Uploads::new(dir.path(), 1024, 1024);
+        let ready = uploads.start("a.txt".into(), 22, hash.clone(), 8).await;
+        assert_eq!(
+            ready,
+            Ok(ServerMessage::UploadReady {
+                name: "a.txt".into(),
+                next_seq: 1
+            })
+        );
+        uploads.chunk(&chunk(1, &data[8..16])).await.unwrap();
+        uploads.chunk(&chunk(2, &data[16..])).await.unwrap();
+        assert_eq!(
+            uploads.finish().await,
+            Ok(ServerMessage::UploadComplete {
+                name: "a.txt".into(),
+                size: 22
+            })
+        );
+        assert_eq!(std::fs::read(dir.path().join("a.txt")).unwrap(), data);
+        assert!(!part.exists());
+    }
+
+    #[tokio::test]
+    async fn bad_uploads_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut uploads = Uploads::new(dir.path(), 1024, 1024);
+        let hash = sha256(b"abcd");
+
+        for name in ["../escape", "a/b", ".hidden", ""] {
+            let result = uploads.start(name.into(), 4, hash.clone(), 2).await;
+            assert!(result.is_err(), "accepted {:?}", name);
+        }
+
+        uploads.start("x".into(), 4, hash.clone(), 2).await.unwrap();
+        assert_eq!(
+            uploads.chunk(&chunk(1, b"cd")).await,
+            Err("expected chunk 0, got 1".to_string())
+        );
+        assert!(!uploads.in_progress());
+
+        uploads.start("x".into(), 4, hash.clone(), 2).await.unwrap();
+        uploads.chunk(&chunk(0, b"ab")).await.unwrap();
+        assert_eq!(
+            uploads.finish().await,
+            Err("upload incomplete: 2 of 4 bytes".to_string())
+        );
+        uploads.chunk(&chunk(1, b"xx")).await.unwrap();
+        assert!(uploads
+            .finish()
+            .await
+            .unwrap_err()
+            .starts_with("sha256 mismatch"));
+        assert!(!dir.path().join("x").exists());
+    }
+
+    #[tokio::test]
+    async fn uploads_are_bounded_and_never_overwrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut uploads = Uploads::new(dir.path(), 4, 1024);
+        let hash = sha256(b"abcd");
+        assert_eq!(
+            uploads.start("big".into(), 5, sha256(b"abcde"), 5).await,
+            Err("file is 5 bytes, more than the 4 allowed".to_string())
+        );
+
+        std::fs::write(dir.path().join("taken"), b"keep").unwrap();
+        assert_eq!(
+            uploads.start("taken".into(), 4, hash.clone(), 4).await,
+            Err("'taken' already exists".to_string())
+        );
+
+        // The name is taken while the upload is under way.
+        uploads
+            .start("late".into(), 4, hash.clone(), 4)
+            .await
+            .unwrap();
+        uploads.chunk(&chunk(0, b"abcd")).await.unwrap();
+        std::fs::write(dir.path().join("late"), b"keep").unwrap();
+        assert_eq!(
+            uploads.finish().await,
+            Err("'late' already exists".to_string())
+        );
+        assert_eq!(std::fs::read(dir.path().join("late")).unwrap(), b"keep");
+        assert_eq!(std::fs::read(dir.path().join("taken")).unwrap(), b"keep");
+    }
+
+    #[tokio::test]
+    async fn parts_have_one_writer_and_a_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let hash = sha256(b"abcd");
+        let mut first = Uploads::new(dir.path(), 4, 5);
+        let mut second = Uploads::new(dir.path(), 4, 5);
+        first.start("a".into(), 4, hash.clone(), 2).await.unwrap();
+        assert_eq!(
+            second.start("b".into(), 4, hash.clone(), 2).await,
+            Err("'b' is already being uploaded".to_string())
+        );
+
+        // Abandoned halfway, the part still counts.
+        first.chunk(&chunk(0, b"ab")).await.unwrap();
+        drop(first);
+        let other = sha256(b"wxyz");
+        assert_eq!(
+            second.start("c".into(), 4, other.clone(), 2).await,
+            Err("unfinished uploads take up 2 of the 5 bytes allowed".to_string())
+        );
+
+        // Until nobody has touched it for a day.
+        let part = dir.path().join(format!(".{}.part", hash));
+        let day_ago = std::time::SystemTime::now() - PART_TTL - Duration::from_secs(60);
+        std::fs::File::options()
+            .write(true)
+            .open(&part)
+            .unwrap()
+            .set_modified(day_ago)
+            .unwrap();
+        second.start("c".into(), 4, other, 2).await.unwrap();
+        assert!(!part.exists());
+    }
+}
@@ -0,0 +1,14 @@
+use websocket_client::audio;
+
+/// Captures audio from an input device and streams it to a WebSocket server
+/// while it records, in `websocket_protocol::audio` frames.
+///
+/// This is websocket-client's `stream-audio` under another name: the command
+/// line and the sending both come from [`websocket_client::audio`]. On
+/// machines without audio hardware, `--file` or `--tone` stream a WAV file
+/// or a test tone instead.
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    let matches = audio::command("streamaudio-client").get_matches();
+    audio::stream(&matches).await.map_err(anyhow::Error::msg)
+}